@@ -0,0 +1,22 @@
+use std::{env, path::Path};
+
+/// Set to regenerate the committed `include/file_encryptor.h`, which `tests/header.rs` compares
+/// with the header of the build.
+const UPDATE_HEADER: &str = "FILE_ENCRYPTOR_UPDATE_HEADER";
+
+fn main() {
+    let dir = env::var("CARGO_MANIFEST_DIR").expect("set by cargo");
+    let dir = Path::new(&dir);
+    let out_dir = env::var("OUT_DIR").expect("set by cargo");
+
+    let config = cbindgen::Config::from_file(dir.join("cbindgen.toml")).expect("cbindgen.toml");
+    let bindings = cbindgen::generate_with_config(dir, config).expect("generating the C header");
+    bindings.write_to_file(Path::new(&out_dir).join("file_encryptor.h"));
+    if env::var_os(UPDATE_HEADER).is_some() {
+        bindings.write_to_file(dir.join("include/file_encryptor.h"));
+    }
+
+    println!("cargo:rerun-if-changed=src/lib.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-env-changed={UPDATE_HEADER}");
+}
@@ -0,0 +1,136 @@
+#ifndef FILE_ENCRYPTOR_H
+#define FILE_ENCRYPTOR_H
+
+/* Generated by cbindgen from ffi/src/lib.rs, do not edit. */
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define FE_KEY_SIZE 32
+
+#define FE_OK 0
+
+// anything else: invalid arguments, a failed callback that reported no I/O error, a panic
+#define FE_OTHER 1
+
+// reading or writing failed, the callbacks included
+#define FE_IO 2
+
+// the key does not match the key check of the sealed data, nothing was decrypted
+#define FE_WRONG_KEY 3
+
+// the sealed data is corrupted
+#define FE_TAG_MISMATCH 4
+
+// the sealed data ends before its tag
+#define FE_TRUNCATED 5
+
+// the header is malformed
+#define FE_BAD_HEADER 6
+
+// the sealed data is of a format version this library cannot open
+#define FE_UNSUPPORTED_VERSION 7
+
+// not used by this API, key files are read by the caller
+#define FE_KEY_FILE_INVALID 8
+
+// the sealed data is headerless (format version 0), its tag does not authenticate it, this
+// library refuses it
+#define FE_UNAUTHENTICATED 9
+
+// compression byte of the header, `--compress` of `file-encryptor seal`
+#define FE_COMPRESSION_NONE 0
+
+#define FE_COMPRESSION_ZSTD 1
+
+#define FE_COMPRESSION_GZIP 2
+
+// Bytes allocated by this library, released with `fe_buffer_free`.
+typedef struct FeBuffer {
+  uint8_t *data;
+  size_t len;
+} FeBuffer;
+
+// Reads up to `len` bytes into `buf`, returns how many were read, 0 at the end of the input, or
+// a negative value on failure.
+typedef ptrdiff_t (*FeReadFn)(void *ctx, uint8_t *buf, size_t len);
+
+// Writes up to `len` bytes of `buf`, returns how many were written (at least one), or a negative
+// value on failure.
+typedef ptrdiff_t (*FeWriteFn)(void *ctx, const uint8_t *buf, size_t len);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// The message of the last error on the calling thread, or null if the last call succeeded. It
+// stays valid until the next call on the thread.
+const char *fe_last_error(void);
+
+// Fills `key` with `FE_KEY_SIZE` random bytes from the operating system.
+//
+// # Safety
+// `key` points to `FE_KEY_SIZE` writable bytes.
+int fe_keygen_random(uint8_t *key);
+
+// Derives `key` from a password, the same key as `file-encryptor keygen --password` with the
+// default scrypt parameters: a `kdf` set in its config file derives another one.
+//
+// # Safety
+// `password` points to `len` readable bytes, `key` to `FE_KEY_SIZE` writable bytes.
+int fe_keygen_password(const uint8_t *password, size_t len, uint8_t *key);
+
+// Seals `len` bytes of `plaintext` into `out`, which is released with `fe_buffer_free`.
+// `compression` is one of the `FE_COMPRESSION_*` values.
+//
+// # Safety
+// `key` points to `FE_KEY_SIZE` bytes, `plaintext` to `len` readable bytes, `out` to a
+// writable `FeBuffer`.
+int fe_seal(const uint8_t *key,
+            uint8_t compression,
+            const uint8_t *plaintext,
+            size_t len,
+            struct FeBuffer *out);
+
+// Opens `len` bytes of sealed data into `out`, which is released with `fe_buffer_free`. Nothing
+// is written to `out` unless the whole input opens.
+//
+// # Safety
+// `key` points to `FE_KEY_SIZE` bytes, `sealed` to `len` readable bytes, `out` to a writable
+// `FeBuffer`.
+int fe_open(const uint8_t *key, const uint8_t *sealed, size_t len, struct FeBuffer *out);
+
+// Releases a buffer filled by this library and resets it, null and empty buffers are left alone.
+//
+// # Safety
+// `buf` is null, or points to a buffer filled by `fe_seal` or `fe_open` and not released yet.
+void fe_buffer_free(struct FeBuffer *buf);
+
+// Seals everything `read` gives until it returns 0, and hands the sealed bytes to `write`. The
+// callbacks are called on the calling thread, with their own context.
+//
+// # Safety
+// The callbacks are safe to call with their contexts, and never write past `len` bytes.
+int fe_seal_stream(const uint8_t *key,
+                   uint8_t compression,
+                   FeReadFn read,
+                   void *read_ctx,
+                   FeWriteFn write,
+                   void *write_ctx);
+
+// Opens the sealed bytes `read` gives, and hands the plaintext to `write`. The tag is only
+// checked at the end of the input: what was written before a failure can't be trusted.
+//
+// # Safety
+// See `fe_seal_stream`.
+int fe_open_stream(const uint8_t *key,
+                   FeReadFn read,
+                   void *read_ctx,
+                   FeWriteFn write,
+                   void *write_ctx);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* FILE_ENCRYPTOR_H */
@@ -0,0 +1,370 @@
+//! A C API over `file_encryptor`, declared in `include/file_encryptor.h` (generated from this file
+//! by cbindgen when the crate builds).
+//!
+//! Every function returns `FE_OK`, or the exit code `file-encryptor` gives the same error (see
+//! `error::Error::status_code`), and keeps its message for `fe_last_error`. Panics are caught and
+//! reported as `FE_OTHER`, nothing unwinds into the caller. Keys are `FE_KEY_SIZE` bytes, files
+//! are sealed in the format `file-encryptor open` reads.
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_int, c_void, CString},
+    io::{self, Read, Write},
+    panic, ptr, slice,
+};
+
+use file_encryptor::{
+    crypto::{Key, KEY_SIZE},
+    error,
+    format::{Compression, Header},
+    kdf, OpenReader, SealWriter,
+};
+use rand::{rngs::OsRng, RngCore};
+
+pub const FE_KEY_SIZE: usize = 32;
+const _: () = assert!(FE_KEY_SIZE == KEY_SIZE);
+
+pub const FE_OK: c_int = 0;
+/// anything else: invalid arguments, a failed callback that reported no I/O error, a panic
+pub const FE_OTHER: c_int = 1;
+/// reading or writing failed, the callbacks included
+pub const FE_IO: c_int = 2;
+/// the key does not match the key check of the sealed data, nothing was decrypted
+pub const FE_WRONG_KEY: c_int = 3;
+/// the sealed data is corrupted
+pub const FE_TAG_MISMATCH: c_int = 4;
+/// the sealed data ends before its tag
+pub const FE_TRUNCATED: c_int = 5;
+/// the header is malformed
+pub const FE_BAD_HEADER: c_int = 6;
+/// the sealed data is of a format version this library cannot open
+pub const FE_UNSUPPORTED_VERSION: c_int = 7;
+/// not used by this API, key files are read by the caller
+pub const FE_KEY_FILE_INVALID: c_int = 8;
+/// the sealed data is headerless (format version 0), its tag does not authenticate it, this
+/// library refuses it
+pub const FE_UNAUTHENTICATED: c_int = 9;
+
+/// compression byte of the header, `--compress` of `file-encryptor seal`
+pub const FE_COMPRESSION_NONE: u8 = 0;
+pub const FE_COMPRESSION_ZSTD: u8 = 1;
+pub const FE_COMPRESSION_GZIP: u8 = 2;
+
+/// Bytes allocated by this library, released with `fe_buffer_free`.
+#[repr(C)]
+pub struct FeBuffer {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+/// Reads up to `len` bytes into `buf`, returns how many were read, 0 at the end of the input, or
+/// a negative value on failure.
+pub type FeReadFn =
+    Option<unsafe extern "C" fn(ctx: *mut c_void, buf: *mut u8, len: usize) -> isize>;
+
+/// Writes up to `len` bytes of `buf`, returns how many were written (at least one), or a negative
+/// value on failure.
+pub type FeWriteFn =
+    Option<unsafe extern "C" fn(ctx: *mut c_void, buf: *const u8, len: usize) -> isize>;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// The message of the last error on the calling thread, or null if the last call succeeded. It
+/// stays valid until the next call on the thread.
+#[no_mangle]
+pub extern "C" fn fe_last_error() -> *const c_char {
+    LAST_ERROR.with_borrow(|message| message.as_ref().map_or(ptr::null(), |m| m.as_ptr()))
+}
+
+/// Fills `key` with `FE_KEY_SIZE` random bytes from the operating system.
+///
+/// # Safety
+/// `key` points to `FE_KEY_SIZE` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn fe_keygen_random(key: *mut u8) -> c_int {
+    run(|| {
+        let mut random = Key::default();
+        OsRng
+            .try_fill_bytes(&mut random)
+            .map_err(|err| error::Error::Other(err.to_string()))?;
+        write_key(key, random)
+    })
+}
+
+/// Derives `key` from a password, the same key as `file-encryptor keygen --password` with the
+/// default scrypt parameters: a `kdf` set in its config file derives another one.
+///
+/// # Safety
+/// `password` points to `len` readable bytes, `key` to `FE_KEY_SIZE` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn fe_keygen_password(
+    password: *const u8,
+    len: usize,
+    key: *mut u8,
+) -> c_int {
+    run(|| write_key(key, kdf::password_key(bytes_arg(password, len)?)))
+}
+
+/// Seals `len` bytes of `plaintext` into `out`, which is released with `fe_buffer_free`.
+/// `compression` is one of the `FE_COMPRESSION_*` values.
+///
+/// # Safety
+/// `key` points to `FE_KEY_SIZE` bytes, `plaintext` to `len` readable bytes, `out` to a
+/// writable `FeBuffer`.
+#[no_mangle]
+pub unsafe extern "C" fn fe_seal(
+    key: *const u8,
+    compression: u8,
+    plaintext: *const u8,
+    len: usize,
+    out: *mut FeBuffer,
+) -> c_int {
+    run(|| {
+        let (key, header) = (key_arg(key)?, header_arg(compression)?);
+        let plaintext = bytes_arg(plaintext, len)?;
+
+        let mut writer = SealWriter::new(Vec::new(), key, header)?;
+        writer.write_all(plaintext)?;
+        write_buffer(out, writer.finish()?)
+    })
+}
+
+/// Opens `len` bytes of sealed data into `out`, which is released with `fe_buffer_free`. Nothing
+/// is written to `out` unless the whole input opens.
+///
+/// # Safety
+/// `key` points to `FE_KEY_SIZE` bytes, `sealed` to `len` readable bytes, `out` to a writable
+/// `FeBuffer`.
+#[no_mangle]
+pub unsafe extern "C" fn fe_open(
+    key: *const u8,
+    sealed: *const u8,
+    len: usize,
+    out: *mut FeBuffer,
+) -> c_int {
+    run(|| {
+        let key = key_arg(key)?;
+        let sealed = bytes_arg(sealed, len)?;
+
+        let mut opened = Vec::new();
+        OpenReader::new(sealed, key)?.read_to_end(&mut opened)?;
+        write_buffer(out, opened)
+    })
+}
+
+/// Releases a buffer filled by this library and resets it, null and empty buffers are left alone.
+///
+/// # Safety
+/// `buf` is null, or points to a buffer filled by `fe_seal` or `fe_open` and not released yet.
+#[no_mangle]
+pub unsafe extern "C" fn fe_buffer_free(buf: *mut FeBuffer) {
+    let Some(buf) = buf.as_mut() else {
+        return;
+    };
+    if !buf.data.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+            buf.data, buf.len,
+        )));
+    }
+    buf.data = ptr::null_mut();
+    buf.len = 0;
+}
+
+/// Seals everything `read` gives until it returns 0, and hands the sealed bytes to `write`. The
+/// callbacks are called on the calling thread, with their own context.
+///
+/// # Safety
+/// The callbacks are safe to call with their contexts, and never write past `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn fe_seal_stream(
+    key: *const u8,
+    compression: u8,
+    read: FeReadFn,
+    read_ctx: *mut c_void,
+    write: FeWriteFn,
+    write_ctx: *mut c_void,
+) -> c_int {
+    run(|| {
+        let (key, header) = (key_arg(key)?, header_arg(compression)?);
+        let mut reader = CallbackReader::new(read, read_ctx)?;
+        let writer = CallbackWriter::new(write, write_ctx)?;
+
+        let mut writer = SealWriter::new(writer, key, header)?;
+        io::copy(&mut reader, &mut writer)?;
+        writer.finish()?.flush()?;
+        Ok(())
+    })
+}
+
+/// Opens the sealed bytes `read` gives, and hands the plaintext to `write`. The tag is only
+/// checked at the end of the input: what was written before a failure can't be trusted.
+///
+/// # Safety
+/// See `fe_seal_stream`.
+#[no_mangle]
+pub unsafe extern "C" fn fe_open_stream(
+    key: *const u8,
+    read: FeReadFn,
+    read_ctx: *mut c_void,
+    write: FeWriteFn,
+    write_ctx: *mut c_void,
+) -> c_int {
+    run(|| {
+        let key = key_arg(key)?;
+        let reader = CallbackReader::new(read, read_ctx)?;
+        let mut writer = CallbackWriter::new(write, write_ctx)?;
+
+        io::copy(&mut OpenReader::new(reader, key)?, &mut writer)?;
+        writer.flush()?;
+        Ok(())
+    })
+}
+
+/// Runs `f` as the body of an exported function: its error, or panic, becomes a status code.
+fn run(f: impl FnOnce() -> error::Result<()>) -> c_int {
+    let err = match panic::catch_unwind(panic::AssertUnwindSafe(f)) {
+        Ok(Ok(())) => {
+            LAST_ERROR.set(None);
+            return FE_OK;
+        }
+        Ok(Err(err)) => err,
+        Err(_) => error::Error::Other("file-encryptor panicked".to_string()),
+    };
+
+    LAST_ERROR.set(CString::new(err.to_string()).ok());
+    err.status_code().into()
+}
+
+fn invalid_argument(message: &str) -> error::Error {
+    error::Error::Other(format!("invalid argument: {}", message))
+}
+
+unsafe fn key_arg(key: *const u8) -> error::Result<Key> {
+    if key.is_null() {
+        return Err(invalid_argument("null key"));
+    }
+    Ok(ptr::read(key.cast::<Key>()))
+}
+
+unsafe fn write_key(out: *mut u8, key: Key) -> error::Result<()> {
+    if out.is_null() {
+        return Err(invalid_argument("null key"));
+    }
+    ptr::write(out.cast::<Key>(), key);
+    Ok(())
+}
+
+unsafe fn bytes_arg<'a>(data: *const u8, len: usize) -> error::Result<&'a [u8]> {
+    match (data.is_null(), len) {
+        (_, 0) => Ok(&[]),
+        (true, _) => Err(invalid_argument("null buffer")),
+        (false, _) => Ok(slice::from_raw_parts(data, len)),
+    }
+}
+
+fn header_arg(compression: u8) -> error::Result<Header> {
+    let compression =
+        Compression::try_from(compression).map_err(|_| invalid_argument("unknown compression"))?;
+    Ok(Header::new(compression))
+}
+
+unsafe fn write_buffer(out: *mut FeBuffer, bytes: Vec<u8>) -> error::Result<()> {
+    let Some(out) = out.as_mut() else {
+        return Err(invalid_argument("null output buffer"));
+    };
+    let bytes = Box::into_raw(bytes.into_boxed_slice());
+    *out = FeBuffer {
+        data: bytes.cast::<u8>(),
+        len: bytes.len(),
+    };
+    Ok(())
+}
+
+struct CallbackReader {
+    read: unsafe extern "C" fn(*mut c_void, *mut u8, usize) -> isize,
+    ctx: *mut c_void,
+}
+
+impl CallbackReader {
+    fn new(read: FeReadFn, ctx: *mut c_void) -> error::Result<Self> {
+        let read = read.ok_or_else(|| invalid_argument("null read callback"))?;
+        Ok(Self { read, ctx })
+    }
+}
+
+impl Read for CallbackReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = unsafe { (self.read)(self.ctx, buf.as_mut_ptr(), buf.len()) };
+        usize::try_from(n)
+            .ok()
+            .filter(|&n| n <= buf.len())
+            .ok_or_else(|| io::Error::other("read callback failed"))
+    }
+}
+
+struct CallbackWriter {
+    write: unsafe extern "C" fn(*mut c_void, *const u8, usize) -> isize,
+    ctx: *mut c_void,
+}
+
+impl CallbackWriter {
+    fn new(write: FeWriteFn, ctx: *mut c_void) -> error::Result<Self> {
+        let write = write.ok_or_else(|| invalid_argument("null write callback"))?;
+        Ok(Self { write, ctx })
+    }
+}
+
+impl Write for CallbackWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = unsafe { (self.write)(self.ctx, buf.as_ptr(), buf.len()) };
+        usize::try_from(n)
+            .ok()
+            .filter(|&n| n <= buf.len())
+            .ok_or_else(|| io::Error::other("write callback failed"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use file_encryptor::error::Location;
+
+    #[test]
+    fn status_codes_match_errors() {
+        let errors = [
+            (FE_OTHER, error::Error::Other(String::new())),
+            (FE_IO, io::Error::other("").into()),
+            (FE_WRONG_KEY, error::Error::WrongKey(Location::default())),
+            (
+                FE_TAG_MISMATCH,
+                error::Error::TagMismatch(Location::default()),
+            ),
+            (FE_TRUNCATED, error::Error::Truncated(Location::default())),
+            (
+                FE_BAD_HEADER,
+                error::Error::BadHeader("", Location::default(), None),
+            ),
+            (
+                FE_UNSUPPORTED_VERSION,
+                error::Error::UnsupportedVersion(0, Location::default()),
+            ),
+            (
+                FE_KEY_FILE_INVALID,
+                error::Error::KeyFileInvalid(Location::default(), None),
+            ),
+            (
+                FE_UNAUTHENTICATED,
+                error::Error::Unauthenticated(Location::default()),
+            ),
+        ];
+        for (status, err) in errors {
+            assert_eq!(c_int::from(err.status_code()), status, "{}", err.name());
+        }
+    }
+}
@@ -0,0 +1,45 @@
+//! Builds `smoke.c` with the C compiler (`$CC`, or `cc`) against the header of the build and the
+//! cdylib, runs it, and opens the file it sealed with the Rust library.
+
+#![cfg(unix)]
+
+use std::{env, fs, path::Path, process::Command};
+
+use file_encryptor::{kdf, OpenReader};
+
+#[test]
+fn c_smoke() {
+    let manifest = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let tmp = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    // the cdylib is built next to the test binary, in `deps`
+    let test_exe = env::current_exe().unwrap();
+    let lib_dir = test_exe.parent().unwrap();
+
+    let exe = tmp.join("smoke");
+    let status = Command::new(env::var("CC").unwrap_or_else(|_| "cc".to_string()))
+        .arg(manifest.join("tests/smoke.c"))
+        .arg("-I")
+        .arg(env!("OUT_DIR"))
+        .arg("-L")
+        .arg(lib_dir)
+        .arg(format!("-Wl,-rpath,{}", lib_dir.display()))
+        .args(["-lfile_encryptor_ffi", "-Wall", "-Werror", "-o"])
+        .arg(&exe)
+        .status()
+        .expect("running the C compiler");
+    assert!(status.success(), "compiling smoke.c");
+
+    let sealed_path = tmp.join("smoke.sealed");
+    let status = Command::new(&exe).arg(&sealed_path).status().unwrap();
+    assert!(status.success(), "smoke.c failed");
+
+    let key = kdf::password_key(b"correct horse battery staple");
+    let sealed = fs::File::open(&sealed_path).unwrap();
+    let mut opened = Vec::new();
+    std::io::Read::read_to_end(&mut OpenReader::new(sealed, key).unwrap(), &mut opened).unwrap();
+
+    let plaintext: Vec<u8> = (0..100_000_usize)
+        .map(|i| (i.wrapping_mul(31) + i / 7) as u8)
+        .collect();
+    assert_eq!(opened, plaintext);
+}
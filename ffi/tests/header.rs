@@ -0,0 +1,12 @@
+//! The committed header must be the one the build generates, regenerate it with
+//! `FILE_ENCRYPTOR_UPDATE_HEADER=1 cargo build -p file-encryptor-ffi`.
+
+#[test]
+fn committed_header_is_up_to_date() {
+    let generated = include_str!(concat!(env!("OUT_DIR"), "/file_encryptor.h"));
+    let committed = include_str!("../include/file_encryptor.h");
+    assert!(
+        generated == committed,
+        "ffi/include/file_encryptor.h is stale, regenerate it with FILE_ENCRYPTOR_UPDATE_HEADER=1"
+    );
+}
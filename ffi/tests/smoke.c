@@ -0,0 +1,111 @@
+/* Exercises the C API end to end. Run by tests/c_smoke.rs, which opens `argv[1]` afterwards. */
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "file_encryptor.h"
+
+#define CHECK(cond)                                                                  \
+    do {                                                                             \
+        if (!(cond)) {                                                               \
+            const char *err = fe_last_error();                                       \
+            fprintf(stderr, "%s:%d: %s (%s)\n", __FILE__, __LINE__, #cond,           \
+                    err ? err : "no error");                                         \
+            exit(1);                                                                 \
+        }                                                                            \
+    } while (0)
+
+struct memory {
+    const uint8_t *data;
+    size_t len;
+    size_t pos;
+};
+
+static ptrdiff_t read_memory(void *ctx, uint8_t *buf, size_t len) {
+    struct memory *mem = ctx;
+    size_t n = mem->len - mem->pos < len ? mem->len - mem->pos : len;
+    memcpy(buf, mem->data + mem->pos, n);
+    mem->pos += n;
+    return (ptrdiff_t)n;
+}
+
+static ptrdiff_t write_file(void *ctx, const uint8_t *buf, size_t len) {
+    return fwrite(buf, 1, len, ctx) == len ? (ptrdiff_t)len : -1;
+}
+
+static ptrdiff_t read_file(void *ctx, uint8_t *buf, size_t len) {
+    size_t n = fread(buf, 1, len, ctx);
+    return ferror(ctx) ? -1 : (ptrdiff_t)n;
+}
+
+static ptrdiff_t read_failing(void *ctx, uint8_t *buf, size_t len) {
+    (void)ctx, (void)buf, (void)len;
+    return -1;
+}
+
+int main(int argc, char **argv) {
+    CHECK(argc == 2);
+
+    static const char password[] = "correct horse battery staple";
+    uint8_t key[FE_KEY_SIZE], other[FE_KEY_SIZE];
+    CHECK(fe_keygen_password((const uint8_t *)password, strlen(password), key) == FE_OK);
+    CHECK(fe_keygen_random(other) == FE_OK);
+    CHECK(memcmp(key, other, FE_KEY_SIZE) != 0);
+
+    size_t len = 100000;
+    uint8_t *plaintext = malloc(len);
+    for (size_t i = 0; i < len; i++) {
+        plaintext[i] = (uint8_t)(i * 31 + i / 7);
+    }
+
+    /* buffers */
+    FeBuffer sealed = {0}, opened = {0};
+    CHECK(fe_seal(key, FE_COMPRESSION_NONE, plaintext, len, &sealed) == FE_OK);
+    CHECK(fe_open(key, sealed.data, sealed.len, &opened) == FE_OK);
+    CHECK(opened.len == len && memcmp(opened.data, plaintext, len) == 0);
+    CHECK(fe_last_error() == NULL);
+    fe_buffer_free(&opened);
+    CHECK(opened.data == NULL && opened.len == 0);
+
+    /* each failure with its own code */
+    CHECK(fe_open(other, sealed.data, sealed.len, &opened) == FE_WRONG_KEY);
+    CHECK(fe_last_error() != NULL);
+    sealed.data[sealed.len / 2] ^= 1;
+    CHECK(fe_open(key, sealed.data, sealed.len, &opened) == FE_TAG_MISMATCH);
+    sealed.data[sealed.len / 2] ^= 1;
+    CHECK(fe_open(key, sealed.data, sealed.len - 3, &opened) == FE_TRUNCATED);
+    CHECK(opened.data == NULL);
+    CHECK(fe_seal(key, 42, plaintext, len, &sealed) == FE_OTHER);
+    CHECK(fe_seal(NULL, FE_COMPRESSION_NONE, plaintext, len, &sealed) == FE_OTHER);
+    fe_buffer_free(&sealed);
+    fe_buffer_free(NULL);
+
+    /* streams, through a file the test opens with the library */
+    FILE *file = fopen(argv[1], "wb");
+    CHECK(file != NULL);
+    struct memory input = {plaintext, len, 0};
+    CHECK(fe_seal_stream(key, FE_COMPRESSION_ZSTD, read_memory, &input, write_file, file) ==
+          FE_OK);
+    CHECK(fclose(file) == 0);
+
+    file = fopen(argv[1], "rb");
+    CHECK(file != NULL);
+    FILE *out = tmpfile();
+    CHECK(out != NULL);
+    CHECK(fe_open_stream(key, read_file, file, write_file, out) == FE_OK);
+    fclose(file);
+    CHECK((size_t)ftell(out) == len);
+    rewind(out);
+    uint8_t *roundtrip = malloc(len);
+    CHECK(fread(roundtrip, 1, len, out) == len && memcmp(roundtrip, plaintext, len) == 0);
+    fclose(out);
+
+    CHECK(fe_seal_stream(key, FE_COMPRESSION_NONE, read_failing, NULL, write_file, stdout) ==
+          FE_IO);
+    CHECK(fe_open_stream(key, NULL, NULL, write_file, stdout) == FE_OTHER);
+
+    free(roundtrip);
+    free(plaintext);
+    return 0;
+}
@@ -0,0 +1,24 @@
+//! A header that parses writes back to the bytes it was read from, and `parse` agrees with
+//! `read`.
+
+#![no_main]
+
+use file_encryptor::format::Header;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut rest = data;
+    let read = Header::read(&mut rest);
+    let parsed = Header::parse(data);
+    assert_eq!(read.is_ok(), parsed.is_ok());
+    let Ok(header) = read else {
+        return;
+    };
+    assert_eq!(parsed.expect("checked above").aad(), header.aad());
+
+    let mut written = Vec::new();
+    header.write(&mut written).expect("writing to a vec");
+    assert_eq!(written, data[..data.len() - rest.len()]);
+    assert!(header.size() >= written.len());
+    let _ = header.check_key(&[7; 32]);
+});
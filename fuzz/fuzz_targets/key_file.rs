@@ -0,0 +1,13 @@
+//! Only a file of exactly one key is a key file.
+
+#![no_main]
+
+use file_encryptor::{crypto::KEY_SIZE, ioutils};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    match ioutils::read_key_file(data) {
+        Ok(key) => assert_eq!(key.as_slice(), data),
+        Err(_) => assert_ne!(data.len(), KEY_SIZE),
+    }
+});
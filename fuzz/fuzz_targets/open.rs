@@ -0,0 +1,40 @@
+//! Arbitrary bytes through every reader of sealed data: none of them may panic, whatever the
+//! input, only return an error.
+
+#![no_main]
+
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+use file_encryptor::{seekable::SeekableOpenReader, stream::Opener, OpenReader};
+use libfuzzer_sys::fuzz_target;
+
+const KEY: [u8; 32] = [7; 32];
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(mut reader) = OpenReader::new(data, KEY) {
+        let _ = reader.read_to_end(&mut Vec::new());
+    }
+
+    // in uneven pieces, the first byte sets their size
+    if let Some((&piece, rest)) = data.split_first() {
+        // legacy headerless streams included, they are refused by default
+        let mut opener = Opener::with_unauthenticated_legacy(KEY);
+        let mut out = Vec::new();
+        let opened = rest
+            .chunks(piece as usize + 1)
+            .all(|piece| opener.update(piece, &mut out).is_ok());
+        if opened {
+            let _ = opener.finish(&mut out);
+        }
+    }
+
+    if let Ok(mut reader) = SeekableOpenReader::new(Cursor::new(data), KEY) {
+        let middle = reader.len() / 2;
+        let _ = reader
+            .seek(SeekFrom::Start(middle))
+            .and_then(|_| reader.read(&mut [0; 100]));
+        let _ = reader
+            .seek(SeekFrom::Start(0))
+            .and_then(|_| reader.read_to_end(&mut Vec::new()));
+    }
+});
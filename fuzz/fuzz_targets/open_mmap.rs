@@ -0,0 +1,28 @@
+//! Arbitrary bytes through the memory mapped `open`, from a file.
+
+#![no_main]
+
+use std::{fs, path::PathBuf, sync::OnceLock};
+
+use file_encryptor::mmap;
+use libfuzzer_sys::fuzz_target;
+
+const KEY: [u8; 32] = [7; 32];
+
+fn dir() -> &'static PathBuf {
+    static DIR: OnceLock<PathBuf> = OnceLock::new();
+    DIR.get_or_init(|| {
+        let dir = std::env::temp_dir().join(format!("file-encryptor-fuzz-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("temporary directory");
+        dir
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let (input, output) = (dir().join("sealed"), dir().join("opened"));
+    fs::write(&input, data).expect("temporary file");
+
+    if mmap::can_map(&input, &output) {
+        let _ = mmap::open(&input, &output, KEY, |_| {});
+    }
+});
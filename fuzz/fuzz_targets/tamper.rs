@@ -0,0 +1,61 @@
+//! Seals the input, then flips some of the sealed bits: opening must fail, unless nothing was
+//! flipped, in which case it must give the input back. This gets past the key check, which
+//! arbitrary bytes hardly ever do.
+
+#![no_main]
+
+use std::io::{Read, Write};
+
+use file_encryptor::{
+    error,
+    format::{Compression, Header},
+    metadata::Metadata,
+    OpenReader, SealWriter,
+};
+use libfuzzer_sys::fuzz_target;
+
+const KEY: [u8; 32] = [7; 32];
+
+fuzz_target!(|input: (u8, Vec<u8>, Vec<(u16, u8)>, u8)| {
+    let (options, plaintext, flips, cut) = input;
+
+    let mut header = Header::new(match options % 3 {
+        0 => Compression::None,
+        1 => Compression::Zstd,
+        _ => Compression::Gzip,
+    });
+    if options & 0b100 != 0 {
+        header.chunk_size = Some(64);
+    }
+
+    let metadata = Metadata {
+        name: Some(String::from("fuzz")),
+        ..Default::default()
+    };
+    let mut writer = if options & 0b1000 != 0 {
+        SealWriter::with_metadata(Vec::new(), KEY, header, &metadata)
+    } else {
+        SealWriter::new(Vec::new(), KEY, header)
+    }
+    .expect("a valid header");
+    writer.write_all(&plaintext).expect("sealing into a vec");
+    let original = writer.finish().expect("sealing into a vec");
+
+    let mut sealed = original.clone();
+    for (at, bits) in flips {
+        let at = at as usize % sealed.len();
+        sealed[at] ^= bits;
+    }
+    sealed.truncate(sealed.len() - cut as usize % 8);
+
+    let mut opened = Vec::new();
+    let result = OpenReader::new(sealed.as_slice(), KEY)
+        .and_then(|mut reader| reader.read_to_end(&mut opened).map_err(error::Error::from));
+
+    if sealed == original {
+        assert!(result.is_ok());
+        assert_eq!(opened, plaintext);
+    } else {
+        assert!(result.is_err(), "a tampered file opened");
+    }
+});
@@ -0,0 +1,20 @@
+//! Unpadding any block removes at most a block, and only what `pad` would have added.
+
+#![no_main]
+
+use file_encryptor::crypto::{block::Block, pkcs7, BLOCK_SIZE};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|bytes: [u8; BLOCK_SIZE]| {
+    let mut block = Block::default();
+    block.bytes_mut().copy_from_slice(&bytes);
+
+    let removed = pkcs7::unpad(&mut block);
+    assert!(removed <= BLOCK_SIZE);
+    if removed > 0 {
+        assert_eq!(bytes[BLOCK_SIZE - 1] as usize, removed);
+        assert!(bytes[BLOCK_SIZE - removed..]
+            .iter()
+            .all(|&byte| byte as usize == removed));
+    }
+});
@@ -0,0 +1,756 @@
+//! The age v1 file format (<https://age-encryption.org/v1>), so that files can go back and forth
+//! with `age` and `rage`. Binary files only, not the ASCII armor.
+//!
+//! An age file is sealed under a random 16 byte file key, wrapped once per recipient in a stanza
+//! of the text header: an X25519 public key (`age1...`), or a passphrase through scrypt, which
+//! must then be the only one. The header ends with an HMAC of itself under the file key, then
+//! the payload is split in 64 KiB chunks, each sealed with ChaCha20-Poly1305 (age's STREAM).
+//!
+//! Errors follow the classes of the age test vectors: a malformed header is a `BadHeader`, no
+//! identity matching a stanza a `WrongKey`, a bad header MAC or chunk a `TagMismatch`.
+
+use std::{
+    fmt,
+    io::{self, BufRead, BufReader, Read, Write},
+};
+
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use bech32::{FromBase32, ToBase32, Variant};
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, KeyInit};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::{
+    error::{self, Location},
+    ioutils,
+};
+
+/// Every age file starts with these bytes, then its version.
+pub const MAGIC: &[u8] = b"age-encryption.org/";
+const VERSION_LINE: &[u8] = b"age-encryption.org/v1\n";
+
+/// Plaintext bytes per chunk of the payload.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_SIZE: usize = 16;
+const NONCE_SIZE: usize = 16;
+const FILE_KEY_SIZE: usize = 16;
+
+/// The work factor passphrases are sealed with, as `age` does.
+pub const SCRYPT_LOG_N: u8 = 18;
+/// Higher work factors are refused when opening, they would take minutes.
+pub const MAX_SCRYPT_LOG_N: u8 = 22;
+
+/// Larger headers are refused, a stanza is a hundred bytes.
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+
+const X25519_INFO: &[u8] = b"age-encryption.org/v1/X25519";
+const SCRYPT_SALT_LABEL: &[u8] = b"age-encryption.org/v1/scrypt";
+const RECIPIENT_HRP: &str = "age";
+const IDENTITY_HRP: &str = "age-secret-key-";
+
+type FileKey = [u8; FILE_KEY_SIZE];
+
+/// Whether `prefix`, the first bytes of a file, are those of an age file.
+pub fn is_age(prefix: &[u8]) -> bool {
+    prefix.starts_with(MAGIC)
+}
+
+/// Who an age file is sealed for.
+#[derive(Clone)]
+pub enum Recipient {
+    X25519(PublicKey),
+    Scrypt { passphrase: String, log_n: u8 },
+}
+
+impl Recipient {
+    /// Parses an `age1...` public key.
+    pub fn parse(s: &str) -> error::Result<Self> {
+        let key = bech32_decode(s, RECIPIENT_HRP)
+            .ok_or_else(|| error::Error::Other(format!("invalid age recipient `{}`", s)))?;
+        Ok(Self::X25519(PublicKey::from(key)))
+    }
+
+    pub fn passphrase(passphrase: &str) -> Self {
+        Self::Scrypt {
+            passphrase: passphrase.to_string(),
+            log_n: SCRYPT_LOG_N,
+        }
+    }
+
+    fn stanza(&self, file_key: &FileKey) -> Stanza {
+        match self {
+            Self::X25519(recipient) => {
+                let ephemeral = StaticSecret::random_from_rng(rand::thread_rng());
+                let share = PublicKey::from(&ephemeral);
+                let shared = ephemeral.diffie_hellman(recipient);
+                let key = x25519_key(shared.as_bytes(), &share, recipient);
+                Stanza {
+                    tag: String::from("X25519"),
+                    args: vec![STANDARD_NO_PAD.encode(share.as_bytes())],
+                    body: seal_file_key(&key, file_key),
+                }
+            }
+            Self::Scrypt { passphrase, log_n } => {
+                let mut salt = [0_u8; 16];
+                rand::thread_rng().fill_bytes(&mut salt);
+                let key = scrypt_key(passphrase, &salt, *log_n);
+                Stanza {
+                    tag: String::from("scrypt"),
+                    args: vec![STANDARD_NO_PAD.encode(salt), log_n.to_string()],
+                    body: seal_file_key(&key, file_key),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::X25519(key) => f.write_str(&bech32_encode(RECIPIENT_HRP, key.as_bytes())),
+            Self::Scrypt { .. } => f.write_str("scrypt"),
+        }
+    }
+}
+
+/// What opens an age file.
+#[derive(Clone)]
+pub enum Identity {
+    X25519(StaticSecret),
+    Scrypt(String),
+}
+
+impl Identity {
+    /// A new random X25519 identity, as `age-keygen` makes.
+    pub fn generate() -> Self {
+        Self::X25519(StaticSecret::random_from_rng(rand::thread_rng()))
+    }
+
+    /// Parses an `AGE-SECRET-KEY-1...` secret key.
+    pub fn parse(s: &str) -> error::Result<Self> {
+        let key = bech32_decode(s, IDENTITY_HRP)
+            .ok_or_else(|| error::Error::Other(String::from("invalid age secret key")))?;
+        Ok(Self::X25519(StaticSecret::from(key)))
+    }
+
+    /// The secret keys of an identity file, as `age-keygen` writes them: one per line, with
+    /// `#` comments.
+    pub fn parse_file(text: &str) -> error::Result<Vec<Self>> {
+        text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(Self::parse)
+            .collect()
+    }
+
+    /// The recipient that files are sealed for so that this identity opens them.
+    pub fn recipient(&self) -> Recipient {
+        match self {
+            Self::X25519(secret) => Recipient::X25519(PublicKey::from(secret)),
+            Self::Scrypt(passphrase) => Recipient::passphrase(passphrase),
+        }
+    }
+
+    /// The file key of `stanza`, `None` if it is not for this identity. A stanza of a known type
+    /// that does not parse is an error.
+    fn unwrap(&self, stanza: &Stanza) -> Result<Option<FileKey>, &'static str> {
+        match (self, stanza.tag.as_str()) {
+            (Self::X25519(secret), "X25519") => {
+                let [share] = stanza.args.as_slice() else {
+                    return Err("an X25519 stanza has a single argument");
+                };
+                let share: [u8; 32] = STANDARD_NO_PAD
+                    .decode(share)
+                    .ok()
+                    .and_then(|share| share.try_into().ok())
+                    .ok_or("invalid X25519 share")?;
+                check_body(&stanza.body)?;
+
+                let share = PublicKey::from(share);
+                let shared = secret.diffie_hellman(&share);
+                if !shared.was_contributory() {
+                    return Err("the X25519 share is a low order point");
+                }
+                let key = x25519_key(shared.as_bytes(), &share, &PublicKey::from(secret));
+                Ok(open_file_key(&key, &stanza.body))
+            }
+            (Self::Scrypt(passphrase), "scrypt") => {
+                let [salt, work_factor] = stanza.args.as_slice() else {
+                    return Err("a scrypt stanza has a salt and a work factor");
+                };
+                let salt: [u8; 16] = STANDARD_NO_PAD
+                    .decode(salt)
+                    .ok()
+                    .and_then(|salt| salt.try_into().ok())
+                    .ok_or("invalid scrypt salt")?;
+                // decimal, without a sign or leading zeros
+                let log_n = match work_factor.parse::<u8>() {
+                    Ok(log_n) if log_n.to_string() == *work_factor => log_n,
+                    _ => return Err("invalid scrypt work factor"),
+                };
+                if log_n == 0 || log_n > MAX_SCRYPT_LOG_N {
+                    return Err("scrypt work factor out of range");
+                }
+                check_body(&stanza.body)?;
+
+                let key = scrypt_key(passphrase, &salt, log_n);
+                Ok(open_file_key(&key, &stanza.body))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::X25519(secret) => {
+                f.write_str(&bech32_encode(IDENTITY_HRP, &secret.to_bytes()).to_uppercase())
+            }
+            Self::Scrypt(_) => f.write_str("scrypt"),
+        }
+    }
+}
+
+/// A wrapped file key, one line of arguments then the body.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Stanza {
+    tag: String,
+    args: Vec<String>,
+    body: Vec<u8>,
+}
+
+impl Stanza {
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(b"->");
+        for arg in std::iter::once(&self.tag).chain(&self.args) {
+            out.push(b' ');
+            out.extend_from_slice(arg.as_bytes());
+        }
+        out.push(b'\n');
+
+        // 64 columns a line, the last one shorter, even if empty
+        let body = STANDARD_NO_PAD.encode(&self.body);
+        let mut lines = body.as_bytes().chunks(64).peekable();
+        while let Some(line) = lines.next() {
+            out.extend_from_slice(line);
+            out.push(b'\n');
+            if lines.peek().is_none() && line.len() == 64 {
+                out.push(b'\n');
+            }
+        }
+        if body.is_empty() {
+            out.push(b'\n');
+        }
+    }
+}
+
+/// The header of an age file, up to the payload nonce.
+pub struct Header {
+    stanzas: Vec<Stanza>,
+    mac: [u8; 32],
+    /// the header up to `---`, which the MAC covers
+    macced: Vec<u8>,
+    size: usize,
+}
+
+impl Header {
+    /// Reads the header at the start of `r`. Only what belongs to the header is consumed.
+    pub fn read<R: BufRead>(r: &mut R) -> error::Result<Self> {
+        let mut macced = Vec::new();
+
+        let version = read_line(r, &mut macced)?;
+        if version != VERSION_LINE {
+            let unsupported = version.starts_with(MAGIC);
+            return Err(bad_header(
+                match unsupported {
+                    true => "unsupported age version",
+                    false => "not an age file",
+                },
+                0,
+            ));
+        }
+
+        let mut stanzas = Vec::new();
+        loop {
+            let at = macced.len();
+            let line = read_line(r, &mut macced)?;
+            let line = &line[..line.len() - 1];
+
+            if let Some(mac) = line.strip_prefix(b"---") {
+                // the MAC covers `---`, not the space after it
+                macced.truncate(at + 3);
+                let mac: [u8; 32] = mac
+                    .strip_prefix(b" ")
+                    .and_then(|mac| STANDARD_NO_PAD.decode(mac).ok())
+                    .and_then(|mac| mac.try_into().ok())
+                    .ok_or_else(|| bad_header("invalid age header MAC", at))?;
+                let size = at + line.len() + 1;
+                return Ok(Self {
+                    stanzas,
+                    mac,
+                    macced,
+                    size,
+                });
+            }
+
+            let args = line
+                .strip_prefix(b"-> ")
+                .ok_or_else(|| bad_header("invalid age stanza", at))?;
+            let args: Vec<String> = args
+                .split(|&b| b == b' ')
+                .map(|arg| match arg {
+                    [] => None,
+                    arg if arg.iter().all(|b| (33..=126).contains(b)) => {
+                        Some(String::from_utf8_lossy(arg).into_owned())
+                    }
+                    _ => None,
+                })
+                .collect::<Option<_>>()
+                .ok_or_else(|| bad_header("invalid age stanza argument", at))?;
+            let (tag, args) = args.split_first().expect("split yields at least one");
+
+            let mut body = Vec::new();
+            loop {
+                let at = macced.len();
+                let line = read_line(r, &mut macced)?;
+                let line = &line[..line.len() - 1];
+                if line.len() > 64 {
+                    return Err(bad_header("age stanza body line too long", at));
+                }
+                let decoded = STANDARD_NO_PAD
+                    .decode(line)
+                    .map_err(|err| bad_header("invalid age stanza body", at).caused_by(err))?;
+                body.extend_from_slice(&decoded);
+                if line.len() < 64 {
+                    break;
+                }
+            }
+
+            stanzas.push(Stanza {
+                tag: tag.clone(),
+                args: args.to_vec(),
+                body,
+            });
+        }
+    }
+
+    /// Whether the file is sealed under a passphrase, rather than for public keys.
+    pub fn is_scrypt(&self) -> bool {
+        self.stanzas.iter().any(|stanza| stanza.tag == "scrypt")
+    }
+
+    /// Size of the header, the payload nonce follows.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn file_key(&self, identities: &[Identity]) -> error::Result<FileKey> {
+        if self.is_scrypt() && self.stanzas.len() > 1 {
+            return Err(bad_header(
+                "a scrypt stanza must be alone in an age header",
+                0,
+            ));
+        }
+
+        for stanza in &self.stanzas {
+            for identity in identities {
+                let file_key = identity
+                    .unwrap(stanza)
+                    .map_err(|message| bad_header(message, 0))?;
+                let Some(file_key) = file_key else {
+                    continue;
+                };
+
+                if header_mac(&file_key, &self.macced)
+                    .verify_slice(&self.mac)
+                    .is_err()
+                {
+                    return Err(error::Error::TagMismatch(Location::offset(
+                        self.macced.len() as u64,
+                    )));
+                }
+                return Ok(file_key);
+            }
+        }
+
+        Err(error::Error::WrongKey(Location::default()))
+    }
+}
+
+/// Reads a line, newline included, appending it to `header`.
+fn read_line<R: BufRead>(r: &mut R, header: &mut Vec<u8>) -> error::Result<Vec<u8>> {
+    let at = header.len();
+    let mut line = Vec::new();
+    let limit = (MAX_HEADER_SIZE - at.min(MAX_HEADER_SIZE)) as u64;
+    r.take(limit).read_until(b'\n', &mut line)?;
+    header.extend_from_slice(&line);
+
+    match line.last() {
+        Some(b'\n') => Ok(line),
+        _ if header.len() >= MAX_HEADER_SIZE => Err(bad_header("age header too large", at)),
+        _ => Err(error::Error::Truncated(Location::offset(
+            header.len() as u64
+        ))),
+    }
+}
+
+/// The MAC of the header up to `---`, under a key derived from the file key.
+fn header_mac(file_key: &FileKey, macced: &[u8]) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&hkdf(file_key, b"", b"header"))
+        .expect("HMAC takes keys of any size");
+    mac.update(macced);
+    mac
+}
+
+/// A stanza body is a sealed file key, and nothing else.
+fn check_body(body: &[u8]) -> Result<(), &'static str> {
+    match body.len() == FILE_KEY_SIZE + TAG_SIZE {
+        true => Ok(()),
+        false => Err("an age stanza body is a 16 byte file key and its tag"),
+    }
+}
+
+fn seal_file_key(key: &[u8; 32], file_key: &FileKey) -> Vec<u8> {
+    ChaCha20Poly1305::new(key.into())
+        .encrypt(&Default::default(), file_key.as_slice())
+        .expect("a file key is small")
+}
+
+fn open_file_key(key: &[u8; 32], body: &[u8]) -> Option<FileKey> {
+    let file_key = ChaCha20Poly1305::new(key.into())
+        .decrypt(&Default::default(), body)
+        .ok()?;
+    file_key.try_into().ok()
+}
+
+fn x25519_key(shared: &[u8; 32], share: &PublicKey, recipient: &PublicKey) -> [u8; 32] {
+    let mut salt = share.as_bytes().to_vec();
+    salt.extend_from_slice(recipient.as_bytes());
+    hkdf(shared, &salt, X25519_INFO)
+}
+
+fn scrypt_key(passphrase: &str, salt: &[u8; 16], log_n: u8) -> [u8; 32] {
+    let mut labelled = SCRYPT_SALT_LABEL.to_vec();
+    labelled.extend_from_slice(salt);
+    let params = scrypt::Params::new(log_n, 8, 1, 32).expect("valid scrypt parameters");
+    let mut key = [0_u8; 32];
+    scrypt::scrypt(passphrase.as_bytes(), &labelled, &params, &mut key)
+        .expect("32 bytes is a valid output size");
+    key
+}
+
+fn hkdf(ikm: &[u8], salt: &[u8], info: &[u8]) -> [u8; 32] {
+    let mut okm = [0_u8; 32];
+    Hkdf::<Sha256>::new(Some(salt), ikm)
+        .expand(info, &mut okm)
+        .expect("32 bytes is a valid output size");
+    okm
+}
+
+fn bech32_decode(s: &str, hrp: &str) -> Option<[u8; 32]> {
+    let (decoded_hrp, data, variant) = bech32::decode(s).ok()?;
+    if decoded_hrp != hrp || variant != Variant::Bech32 {
+        return None;
+    }
+    Vec::<u8>::from_base32(&data).ok()?.try_into().ok()
+}
+
+fn bech32_encode(hrp: &str, bytes: &[u8]) -> String {
+    bech32::encode(hrp, bytes.to_base32(), Variant::Bech32).expect("a valid prefix")
+}
+
+fn bad_header(message: &'static str, at: usize) -> error::Error {
+    error::Error::BadHeader(message, Location::offset(at as u64), None)
+}
+
+/// The key of the STREAM payload, and the nonce of its chunk `index`.
+struct Stream {
+    cipher: ChaCha20Poly1305,
+    index: u64,
+}
+
+impl Stream {
+    fn new(file_key: &FileKey, nonce: &[u8; NONCE_SIZE]) -> Self {
+        let key = hkdf(file_key, nonce, b"payload");
+        Self {
+            cipher: ChaCha20Poly1305::new(&key.into()),
+            index: 0,
+        }
+    }
+
+    fn nonce(&self, last: bool) -> chacha20poly1305::Nonce {
+        let mut nonce = chacha20poly1305::Nonce::default();
+        nonce[3..11].copy_from_slice(&self.index.to_be_bytes());
+        nonce[11] = last as u8;
+        nonce
+    }
+
+    fn seal(&mut self, chunk: &[u8], last: bool) -> Vec<u8> {
+        let sealed = self
+            .cipher
+            .encrypt(&self.nonce(last), chunk)
+            .expect("a chunk is small");
+        self.index += 1;
+        sealed
+    }
+
+    fn open(&mut self, chunk: &[u8], last: bool) -> Option<Vec<u8>> {
+        let opened = self.cipher.decrypt(&self.nonce(last), chunk).ok()?;
+        self.index += 1;
+        Some(opened)
+    }
+}
+
+/// Seals everything written to it into an age file. As with `stream::SealWriter`, the file is
+/// only complete once `finish` is called.
+pub struct SealWriter<W: Write> {
+    inner: W,
+    stream: Stream,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> SealWriter<W> {
+    /// Writes the header for `recipients`. A passphrase must be the only recipient.
+    pub fn new(mut inner: W, recipients: &[Recipient]) -> error::Result<Self> {
+        let scrypt = recipients
+            .iter()
+            .any(|recipient| matches!(recipient, Recipient::Scrypt { .. }));
+        if recipients.is_empty() || (scrypt && recipients.len() > 1) {
+            return Err(error::Error::Other(String::from(
+                "an age file needs recipients, or a passphrase alone",
+            )));
+        }
+
+        let mut file_key = FileKey::default();
+        rand::thread_rng().fill_bytes(&mut file_key);
+
+        let mut header = VERSION_LINE.to_vec();
+        for recipient in recipients {
+            recipient.stanza(&file_key).write(&mut header);
+        }
+        header.extend_from_slice(b"---");
+        let mac = header_mac(&file_key, &header).finalize().into_bytes();
+        header.push(b' ');
+        header.extend_from_slice(STANDARD_NO_PAD.encode(mac).as_bytes());
+        header.push(b'\n');
+
+        let mut nonce = [0_u8; NONCE_SIZE];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        header.extend_from_slice(&nonce);
+        inner.write_all(&header)?;
+
+        Ok(Self {
+            inner,
+            stream: Stream::new(&file_key, &nonce),
+            buf: Vec::with_capacity(CHUNK_SIZE + 1),
+        })
+    }
+
+    /// Seals the last chunk, then hands back the inner writer.
+    pub fn finish(mut self) -> error::Result<W> {
+        let sealed = self.stream.seal(&self.buf, true);
+        self.inner.write_all(&sealed)?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for SealWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // a full chunk is held back until more comes in, it may be the last one
+        let n = buf.len().min(CHUNK_SIZE + 1 - self.buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        if self.buf.len() > CHUNK_SIZE {
+            let sealed = self.stream.seal(&self.buf[..CHUNK_SIZE], false);
+            self.inner.write_all(&sealed)?;
+            self.buf.drain(..CHUNK_SIZE);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads the plaintext of an age file. Every chunk is authenticated before it is handed out, a
+/// truncated file is only told apart at its end: the plaintext can only be trusted in full once
+/// `read` returned 0. Errors are `io::ErrorKind::InvalidData`, as for `stream::OpenReader`.
+pub struct OpenReader<R: Read> {
+    inner: BufReader<R>,
+    stream: Stream,
+    /// the next sealed chunk, read ahead to tell whether the current one is the last
+    next: Vec<u8>,
+    /// where `next` starts in the file
+    offset: u64,
+    plain: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<R: Read> OpenReader<R> {
+    /// Reads the header, and finds the file key with one of `identities`.
+    pub fn new(inner: R, identities: &[Identity]) -> error::Result<Self> {
+        let mut inner = BufReader::with_capacity(CHUNK_SIZE + TAG_SIZE, inner);
+        let header = Header::read(&mut inner)?;
+        Self::with_header(inner, &header, identities)
+    }
+
+    /// Same as `new`, for a header read from `inner` already, see `Header::read`.
+    pub fn with_header(
+        mut inner: BufReader<R>,
+        header: &Header,
+        identities: &[Identity],
+    ) -> error::Result<Self> {
+        let file_key = header.file_key(identities)?;
+
+        let mut nonce = [0_u8; NONCE_SIZE];
+        if ioutils::read_full(&mut inner, &mut nonce)? < NONCE_SIZE {
+            return Err(bad_header("age payload nonce missing", header.size()));
+        }
+
+        let offset = (header.size() + NONCE_SIZE) as u64;
+        let mut reader = Self {
+            inner,
+            stream: Stream::new(&file_key, &nonce),
+            next: Vec::new(),
+            offset,
+            plain: Vec::new(),
+            pos: 0,
+            done: false,
+        };
+        reader.next = reader.read_chunk()?;
+        Ok(reader)
+    }
+
+    fn read_chunk(&mut self) -> io::Result<Vec<u8>> {
+        let mut chunk = vec![0_u8; CHUNK_SIZE + TAG_SIZE];
+        let n = ioutils::read_full(&mut self.inner, &mut chunk)?;
+        chunk.truncate(n);
+        Ok(chunk)
+    }
+
+    /// Opens the next chunk into `plain`, returns false once the payload is over.
+    fn fill(&mut self) -> error::Result<bool> {
+        if self.done {
+            return Ok(false);
+        }
+
+        let chunk = std::mem::take(&mut self.next);
+        let at = self.offset;
+        if chunk.len() < TAG_SIZE {
+            return Err(error::Error::Truncated(Location::offset(
+                at + chunk.len() as u64,
+            )));
+        }
+        // a full chunk is the last one only if nothing follows
+        if chunk.len() == CHUNK_SIZE + TAG_SIZE {
+            self.next = self.read_chunk()?;
+        }
+        let last = self.next.is_empty();
+
+        let first = self.stream.index == 0;
+        self.plain = self
+            .stream
+            .open(&chunk, last)
+            .filter(|plain| !last || first || !plain.is_empty())
+            .ok_or(error::Error::TagMismatch(Location::offset(at)))?;
+        self.pos = 0;
+        self.offset += chunk.len() as u64;
+        self.done = last;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for OpenReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.plain.len() {
+            if !self.fill()? {
+                return Ok(0);
+            }
+        }
+
+        let n = buf.len().min(self.plain.len() - self.pos);
+        buf[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(recipients: &[Recipient], identities: &[Identity], plaintext: &[u8]) -> Vec<u8> {
+        let mut writer = SealWriter::new(Vec::new(), recipients).unwrap();
+        writer.write_all(plaintext).unwrap();
+        let sealed = writer.finish().unwrap();
+
+        let mut opened = Vec::new();
+        OpenReader::new(sealed.as_slice(), identities)
+            .unwrap()
+            .read_to_end(&mut opened)
+            .unwrap();
+        assert_eq!(opened, plaintext);
+        sealed
+    }
+
+    #[test]
+    fn age_roundtrip() {
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let recipients = [alice.recipient(), bob.recipient()];
+        for size in [
+            0,
+            1,
+            CHUNK_SIZE - 1,
+            CHUNK_SIZE,
+            CHUNK_SIZE + 1,
+            3 * CHUNK_SIZE,
+        ] {
+            let plaintext: Vec<u8> = (0..size).map(|i| i as u8).collect();
+            roundtrip(&recipients, std::slice::from_ref(&bob), &plaintext);
+        }
+
+        let sealed = roundtrip(&recipients, std::slice::from_ref(&alice), b"hello");
+        let carol = Identity::generate();
+        assert!(matches!(
+            OpenReader::new(sealed.as_slice(), &[carol]),
+            Err(error::Error::WrongKey(_))
+        ));
+
+        let parsed = Identity::parse(&alice.to_string()).unwrap();
+        assert_eq!(
+            parsed.recipient().to_string(),
+            alice.recipient().to_string()
+        );
+        assert!(alice.to_string().starts_with("AGE-SECRET-KEY-1"));
+        let recipient = alice.recipient().to_string();
+        assert!(Recipient::parse(&recipient).is_ok());
+        assert!(Recipient::parse(&recipient[..recipient.len() - 1]).is_err());
+
+        let passphrase = Recipient::Scrypt {
+            passphrase: String::from("secret"),
+            log_n: 10,
+        };
+        roundtrip(
+            std::slice::from_ref(&passphrase),
+            &[Identity::Scrypt(String::from("secret"))],
+            b"hello",
+        );
+        assert!(SealWriter::new(Vec::new(), &[passphrase, alice.recipient()]).is_err());
+    }
+
+    #[test]
+    fn age_identity_file() {
+        let text = "# created: 2021-01-01\n# public key: age1...\n\
+                    AGE-SECRET-KEY-1XMWWC06LY3EE5RYTXM9MFLAZ2U56JJJ36S0MYPDRWSVLUL66MV4QX3S7F6\n";
+        let identities = Identity::parse_file(text).unwrap();
+        assert_eq!(identities.len(), 1);
+        assert!(Identity::parse_file("not a key").is_err());
+    }
+}
@@ -0,0 +1,363 @@
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use crate::error;
+
+/// Writes `dir` as a tar stream, entries are named relative to `dir` and symlinks are stored as
+/// links rather than followed.
+pub fn pack<W: Write>(dir: &Path, w: W) -> error::Result<W> {
+    let mut builder = tar::Builder::new(w);
+    builder.follow_symlinks(false);
+
+    append_dir(&mut builder, dir, Path::new(""))?;
+
+    builder
+        .into_inner()
+        .map_err(|err| error::Error::io(err, dir))
+}
+
+fn append_dir<W: Write>(
+    builder: &mut tar::Builder<W>,
+    root: &Path,
+    rel: &Path,
+) -> error::Result<()> {
+    let dir = root.join(rel);
+    let mut entries = fs::read_dir(&dir)
+        .and_then(|entries| entries.collect::<io::Result<Vec<_>>>())
+        .map_err(|err| error::Error::io(err, &dir))?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let name = rel.join(entry.file_name());
+        let path = entry.path();
+        builder
+            .append_path_with_name(&path, &name)
+            .map_err(|err| error::Error::io(err, &path))?;
+
+        // `DirEntry::file_type` does not follow symlinks
+        if entry
+            .file_type()
+            .map_err(|err| error::Error::io(err, &path))?
+            .is_dir()
+        {
+            append_dir(builder, root, &name)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Restores a tar stream under `dst`, refusing any entry that would land outside of it. Nothing
+/// lands in `dst` itself until `Staged::commit`: the stream is being decrypted while it is
+/// unpacked, and only its tag tells whether it was tampered with.
+pub fn unpack<R: Read>(r: R, dst: &Path) -> error::Result<Staged> {
+    fs::create_dir_all(dst).map_err(|err| error::Error::io(err, dst))?;
+    let staged = Staged::new(dst)?;
+    let stage = staged.dir.as_path();
+
+    let mut archive = tar::Archive::new(r);
+    archive.set_preserve_permissions(true);
+    archive.set_preserve_mtime(true);
+
+    // directories are restored last, so that writing their content does not bump the mtime
+    let mut directories = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        check_path(&path)?;
+
+        if entry.header().entry_type().is_dir() {
+            directories.push(entry);
+            continue;
+        }
+
+        if !entry
+            .unpack_in(stage)
+            .map_err(|err| error::Error::io(err, &path))?
+        {
+            return Err(outside_dst(&path));
+        }
+    }
+
+    // deepest first, a read-only parent would refuse its children otherwise
+    directories.sort_by(|a, b| b.path_bytes().cmp(&a.path_bytes()));
+    for mut dir in directories {
+        let path = dir.path()?.into_owned();
+        if !dir
+            .unpack_in(stage)
+            .map_err(|err| error::Error::io(err, &path))?
+        {
+            return Err(outside_dst(&path));
+        }
+    }
+
+    drain(archive.into_inner())?;
+    Ok(staged)
+}
+
+/// An unpacked archive, in a hidden staging directory of its destination. `commit` moves its
+/// entries into place, dropping it first removes them.
+pub struct Staged {
+    dst: PathBuf,
+    dir: PathBuf,
+    committed: bool,
+}
+
+impl Staged {
+    fn new(dst: &Path) -> error::Result<Self> {
+        use rand::Rng;
+
+        let mut builder = fs::DirBuilder::new();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::DirBuilderExt;
+            builder.mode(0o700);
+        }
+
+        loop {
+            let dir = dst.join(format!(
+                ".file-encryptor.{:08x}.tmp",
+                rand::thread_rng().gen::<u32>()
+            ));
+            match builder.create(&dir) {
+                Ok(()) => {
+                    return Ok(Self {
+                        dst: dst.to_path_buf(),
+                        dir,
+                        committed: false,
+                    })
+                }
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(error::Error::io(err, &dir)),
+            }
+        }
+    }
+
+    /// Moves the unpacked entries into the destination, merged with the directories already
+    /// there. Files of the same name are replaced.
+    pub fn commit(mut self) -> error::Result<()> {
+        move_entries(&self.dir, &self.dst)?;
+        fs::remove_dir(&self.dir).map_err(|err| error::Error::io(err, &self.dir))?;
+        self.committed = true;
+        Ok(())
+    }
+}
+
+impl Drop for Staged {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = remove_all(&self.dir);
+        }
+    }
+}
+
+/// Moves the entries of `from` into `to`, merging the directories found in both.
+fn move_entries(from: &Path, to: &Path) -> error::Result<()> {
+    let entries = fs::read_dir(from)
+        .and_then(|entries| entries.collect::<io::Result<Vec<_>>>())
+        .map_err(|err| error::Error::io(err, from))?;
+
+    for entry in entries {
+        let src = entry.path();
+        let dst = to.join(entry.file_name());
+        let is_dir = entry
+            .file_type()
+            .map_err(|err| error::Error::io(err, &src))?
+            .is_dir();
+
+        if is_dir && fs::symlink_metadata(&dst).is_ok_and(|metadata| metadata.is_dir()) {
+            // its entries move out, a read-only directory would keep them
+            make_writable(&src).map_err(|err| error::Error::io(err, &src))?;
+            move_entries(&src, &dst)?;
+            fs::remove_dir(&src).map_err(|err| error::Error::io(err, &src))?;
+        } else {
+            fs::rename(&src, &dst).map_err(|err| error::Error::io(err, &dst))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes `dir` and everything below it, read-only directories included.
+fn remove_all(dir: &Path) -> io::Result<()> {
+    make_writable(dir)?;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            remove_all(&entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    fs::remove_dir(dir)
+}
+
+fn make_writable(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    }
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+/// Writes one line per entry of a tar stream, in the spirit of `tar -tv`.
+pub fn list<R: Read, W: Write>(r: R, mut w: W) -> error::Result<()> {
+    let mut archive = tar::Archive::new(r);
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let header = entry.header();
+        let path = entry.path()?;
+
+        write!(
+            w,
+            "{} {:>12} {}",
+            mode_string(header),
+            header.size()?,
+            path.display()
+        )?;
+        if let Some(link) = entry.link_name()? {
+            write!(w, " -> {}", link.display())?;
+        }
+        writeln!(w)?;
+    }
+
+    w.flush()?;
+    drain(archive.into_inner())
+}
+
+/// An entry may only name a path below the destination, no root and no `..`.
+fn check_path(path: &Path) -> error::Result<()> {
+    let safe = path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+
+    if safe {
+        Ok(())
+    } else {
+        Err(outside_dst(path))
+    }
+}
+
+/// The writer on the other end expects the whole stream to be consumed, including the padding
+/// after the end of archive marker.
+fn drain<R: Read>(mut r: R) -> error::Result<()> {
+    io::copy(&mut r, &mut io::sink())?;
+    Ok(())
+}
+
+fn mode_string(header: &tar::Header) -> String {
+    let kind = match header.entry_type() {
+        t if t.is_dir() => 'd',
+        t if t.is_symlink() => 'l',
+        t if t.is_hard_link() => 'h',
+        _ => '-',
+    };
+
+    let mode = header.mode().unwrap_or(0);
+    let mut s = String::from(kind);
+    for shift in [6, 3, 0] {
+        let bits = (mode >> shift) & 0o7;
+        s.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+        s.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+        s.push(if bits & 0o1 != 0 { 'x' } else { '-' });
+    }
+
+    s
+}
+
+fn outside_dst(path: &Path) -> error::Error {
+    let err = io::Error::new(
+        io::ErrorKind::InvalidData,
+        "archive entry escapes the target directory",
+    );
+    error::Error::io(err, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::{symlink, PermissionsExt};
+
+    #[test]
+    fn archive_roundtrip() {
+        let src = tempfile::tempdir().unwrap();
+        fs::create_dir_all(src.path().join("a/b")).unwrap();
+        fs::write(src.path().join("a/b/file.txt"), b"hello").unwrap();
+        fs::write(src.path().join("run.sh"), b"#!/bin/sh").unwrap();
+        fs::set_permissions(src.path().join("run.sh"), fs::Permissions::from_mode(0o750)).unwrap();
+        symlink("a/b/file.txt", src.path().join("link")).unwrap();
+
+        let tarball = pack(src.path(), Vec::new()).unwrap();
+
+        let dst = tempfile::tempdir().unwrap();
+        unpack(tarball.as_slice(), dst.path())
+            .unwrap()
+            .commit()
+            .unwrap();
+
+        assert_eq!(fs::read(dst.path().join("a/b/file.txt")).unwrap(), b"hello");
+        let mode = fs::metadata(dst.path().join("run.sh"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o750);
+        assert_eq!(
+            fs::read_link(dst.path().join("link")).unwrap(),
+            Path::new("a/b/file.txt")
+        );
+
+        let src_mtime = fs::metadata(src.path().join("a/b/file.txt"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        let dst_mtime = fs::metadata(dst.path().join("a/b/file.txt"))
+            .unwrap()
+            .modified()
+            .unwrap();
+        assert_eq!(
+            src_mtime
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            dst_mtime
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        );
+
+        let mut listing = Vec::new();
+        list(tarball.as_slice(), &mut listing).unwrap();
+        let listing = String::from_utf8(listing).unwrap();
+        assert!(listing.contains("-rwxr-x---"));
+        assert!(listing.contains("link -> a/b/file.txt"));
+    }
+
+    #[test]
+    fn archive_rejects_traversal() {
+        for name in ["../escape", "/etc/escape", "a/../../escape"] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(4);
+            header.set_mode(0o644);
+            header.set_entry_type(tar::EntryType::Regular);
+            // `set_path` refuses `..`, so write the raw name the way a hostile archive would
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_cksum();
+
+            let mut builder = tar::Builder::new(Vec::new());
+            builder.append(&header, &b"evil"[..]).unwrap();
+            let tarball = builder.into_inner().unwrap();
+
+            let dst = tempfile::tempdir().unwrap();
+            assert!(unpack(tarball.as_slice(), &dst.path().join("out")).is_err());
+            assert!(!dst.path().join("escape").exists());
+            // nor is the staging directory left behind
+            assert_eq!(fs::read_dir(dst.path().join("out")).unwrap().count(), 0);
+        }
+    }
+}
@@ -0,0 +1,278 @@
+//! Tokio counterparts of `SealWriter` and `OpenReader`, behind the `async` feature. They drive
+//! the same `Sealer` and `Opener`, so either side can be sync or async.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+use crate::{
+    crypto::Key,
+    error,
+    format::Header,
+    stream::{self, Opener, Sealer},
+};
+
+/// Seals everything written to it into `inner`. The stream is only complete once the writer is
+/// shut down, which writes the tag.
+pub struct AsyncSealWriter<W> {
+    inner: W,
+    /// `None` once shut down
+    sealer: Option<Sealer>,
+    out: Vec<u8>,
+    pos: usize,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncSealWriter<W> {
+    pub fn new(inner: W, key: Key, header: Header) -> error::Result<Self> {
+        Ok(Self {
+            inner,
+            sealer: Some(Sealer::new(key, header)?),
+            out: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Writes out the ciphertext held back, if any.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.pos < self.out.len() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.out[self.pos..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pos += n;
+        }
+
+        self.out.clear();
+        self.pos = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for AsyncSealWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // new input is only taken once the previous ciphertext is out
+        ready!(this.poll_drain(cx))?;
+
+        let Some(sealer) = this.sealer.as_mut() else {
+            return Poll::Ready(Err(io::Error::other("write after shutdown")));
+        };
+        sealer.update(buf, &mut this.out)?;
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let Some(sealer) = this.sealer.take() {
+            sealer.finish(&mut this.out)?;
+        }
+
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+/// Reads the plaintext of a sealed stream, decompressing it if need be.
+///
+/// As with `OpenReader`, the tag is only checked at the end of the stream: the plaintext read so
+/// far can only be trusted once a read hits the end.
+pub struct AsyncOpenReader<R> {
+    inner: R,
+    header: Header,
+    /// `None` once the end of the stream was opened
+    opener: Option<Opener>,
+    buf: Vec<u8>,
+    out: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: AsyncRead + Unpin> AsyncOpenReader<R> {
+    /// Reads the header of the stream.
+    pub async fn new(mut inner: R, key: Key) -> error::Result<Self> {
+        let mut opener = Opener::new(key);
+        let mut buf = vec![0; stream::BUFFER_SIZE];
+        let mut out = Vec::new();
+
+        let header = loop {
+            if let Some(header) = opener.header() {
+                break *header;
+            }
+
+            let n = inner.read(&mut buf).await?;
+            if n == 0 {
+                // without a header, `finish` tells where the stream was cut
+                return Err(opener.finish(&mut out).expect_err("no header yet"));
+            }
+            opener.update(&buf[..n], &mut out)?;
+        };
+
+        Ok(Self {
+            inner,
+            header,
+            opener: Some(opener),
+            buf,
+            out,
+            pos: 0,
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for AsyncOpenReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        while this.pos == this.out.len() {
+            let Some(opener) = this.opener.as_mut() else {
+                return Poll::Ready(Ok(()));
+            };
+
+            let mut read_buf = ReadBuf::new(&mut this.buf);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read_buf))?;
+            let n = read_buf.filled().len();
+
+            this.out.clear();
+            this.pos = 0;
+            if n == 0 {
+                let opener = this.opener.take().expect("checked above");
+                opener.finish(&mut this.out)?;
+            } else {
+                opener.update(&this.buf[..n], &mut this.out)?;
+            }
+        }
+
+        let n = buf.remaining().min(this.out.len() - this.pos);
+        buf.put_slice(&this.out[this.pos..this.pos + n]);
+        this.pos += n;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        crypto::BLOCK_SIZE,
+        format::Compression,
+        stream::{OpenReader, SealWriter},
+    };
+    use rand::RngCore;
+    use std::io::{Read, Write};
+    use tokio::io::AsyncWriteExt;
+
+    fn random_bytes(size: usize) -> Vec<u8> {
+        let mut bytes = vec![0_u8; size];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        bytes
+    }
+
+    #[tokio::test]
+    async fn async_roundtrip_over_duplex() {
+        let key: Key = [4; 32];
+        for compression in [Compression::None, Compression::Zstd, Compression::Gzip] {
+            for chunk_size in [None, Some(64)] {
+                let mut header = Header::new(compression);
+                header.chunk_size = chunk_size;
+                let plaintext = random_bytes(100_003);
+
+                // a small duplex buffer, so that both ends wait on each other
+                let (client, server) = tokio::io::duplex(1024);
+                let sent = plaintext.clone();
+                let writer = tokio::spawn(async move {
+                    let mut writer = AsyncSealWriter::new(client, key, header).unwrap();
+                    for piece in sent.chunks(1000) {
+                        writer.write_all(piece).await.unwrap();
+                    }
+                    writer.shutdown().await.unwrap();
+                });
+
+                let mut reader = AsyncOpenReader::new(server, key).await.unwrap();
+                assert_eq!(reader.header().compression, compression);
+                let mut opened = Vec::new();
+                reader.read_to_end(&mut opened).await.unwrap();
+                writer.await.unwrap();
+
+                assert_eq!(opened, plaintext, "{:?} {:?}", compression, chunk_size);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn async_interop_with_sync() {
+        let key: Key = [6; 32];
+        let plaintext = random_bytes(5000);
+
+        let mut writer = SealWriter::new(Vec::new(), key, Header::new(Compression::Zstd)).unwrap();
+        writer.write_all(&plaintext).unwrap();
+        let sealed = writer.finish().unwrap();
+        let mut opened = Vec::new();
+        AsyncOpenReader::new(sealed.as_slice(), key)
+            .await
+            .unwrap()
+            .read_to_end(&mut opened)
+            .await
+            .unwrap();
+        assert_eq!(opened, plaintext);
+
+        let mut writer =
+            AsyncSealWriter::new(Vec::new(), key, Header::new(Compression::Gzip)).unwrap();
+        writer.write_all(&plaintext).await.unwrap();
+        writer.shutdown().await.unwrap();
+        let sealed = writer.into_inner();
+        let mut opened = Vec::new();
+        OpenReader::new(sealed.as_slice(), key)
+            .unwrap()
+            .read_to_end(&mut opened)
+            .unwrap();
+        assert_eq!(opened, plaintext);
+    }
+
+    #[tokio::test]
+    async fn async_tamper() {
+        let key: Key = [8; 32];
+        let mut writer =
+            AsyncSealWriter::new(Vec::new(), key, Header::new(Compression::None)).unwrap();
+        writer.write_all(&random_bytes(300)).await.unwrap();
+        writer.shutdown().await.unwrap();
+        let mut sealed = writer.into_inner();
+        let tag_at = sealed.len() - BLOCK_SIZE;
+        sealed[tag_at] ^= 1;
+
+        let mut reader = AsyncOpenReader::new(sealed.as_slice(), key).await.unwrap();
+        let err = reader.read_to_end(&mut Vec::new()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(
+            error::Error::from(err),
+            error::Error::TagMismatch(_)
+        ));
+
+        assert!(AsyncOpenReader::new(&b"FE"[..], key).await.is_err());
+    }
+}
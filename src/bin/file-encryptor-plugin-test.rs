@@ -0,0 +1,122 @@
+//! The reference plugin, see `file_encryptor::plugin`: it wraps file keys under the key of a key
+//! file, whose path is both the recipient and the identity. Good for trying out the protocol, a
+//! wrapped key is no safer than the key file.
+
+use std::{
+    env,
+    io::{self, BufRead},
+};
+
+use file_encryptor::{
+    crypto::{
+        self, block::Block, cipher::Cipher, Key, BLOCK_SIZE, FINGERPRINT_SIZE, IV_SIZE, KEY_SIZE,
+    },
+    hex, ioutils,
+};
+
+const AAD: &[u8] = b"file-encryptor-plugin-test";
+
+fn main() {
+    let phase = env::args().nth(1).unwrap_or_default();
+    let answer = match read_request() {
+        Err(err) => Err(err.to_string()),
+        Ok(request) if field(&request, "version") != Some("1") => {
+            Err(String::from("unsupported protocol version"))
+        }
+        Ok(request) => match phase.as_str() {
+            "wrap" => wrap(&request),
+            "unwrap" => unwrap(&request),
+            _ => Err(format!(
+                "unknown phase `{}`, expected wrap or unwrap",
+                phase
+            )),
+        },
+    };
+
+    match answer {
+        Ok(answer) => println!("{}", answer),
+        Err(message) => println!("error {}", message),
+    }
+}
+
+/// The lines up to `done`, as their first word and the rest.
+fn read_request() -> io::Result<Vec<(String, String)>> {
+    let mut request = Vec::new();
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        if line == "done" {
+            break;
+        }
+        let (word, data) = line.split_once(' ').unwrap_or((&line, ""));
+        request.push((word.to_string(), data.to_string()));
+    }
+    Ok(request)
+}
+
+fn field<'a>(request: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    request
+        .iter()
+        .find(|(word, _)| word == name)
+        .map(|(_, data)| data.as_str())
+}
+
+/// `wrapped` is the fingerprint of the wrapping key, the iv, the sealed file key, then the tag.
+fn wrap(request: &[(String, String)]) -> Result<String, String> {
+    let recipient = field(request, "recipient").ok_or("no recipient, expected a key file")?;
+    let mut key = Key::default();
+    match field(request, "file-key").and_then(hex::decode) {
+        Some(bytes) if bytes.len() == KEY_SIZE => key.copy_from_slice(&bytes),
+        _ => return Err(String::from("malformed file key")),
+    }
+
+    let wrapping_key = wrapping_key(recipient)?;
+    let iv = Block::new_iv();
+    let mut cipher = Cipher::new(wrapping_key, iv, AAD);
+    cipher
+        .encrypt_blocks_inplace(&mut key)
+        .map_err(|err| err.to_string())?;
+
+    let mut wrapped = crypto::fingerprint(&wrapping_key).to_vec();
+    wrapped.extend_from_slice(iv.iv_bytes());
+    wrapped.extend_from_slice(&key);
+    wrapped.extend_from_slice(cipher.tag().bytes());
+    Ok(format!("wrapped {}", hex::encode(&wrapped)))
+}
+
+/// Answers `wrong-identity` when the fingerprint of the wrapping key is not the identity's, and
+/// `corrupt` when the wrapped key fails its tag nonetheless.
+fn unwrap(request: &[(String, String)]) -> Result<String, String> {
+    let identity = field(request, "identity").ok_or("no identity, expected a key file")?;
+    let wrapped = match field(request, "wrapped").and_then(hex::decode) {
+        Some(bytes) if bytes.len() == FINGERPRINT_SIZE + IV_SIZE + KEY_SIZE + BLOCK_SIZE => bytes,
+        _ => return Ok(String::from("corrupt malformed wrapped key")),
+    };
+    let (id, rest) = wrapped.split_at(FINGERPRINT_SIZE);
+    let (nonce, rest) = rest.split_at(IV_SIZE);
+    let (sealed, tag) = rest.split_at(KEY_SIZE);
+
+    let wrapping_key = wrapping_key(identity)?;
+    if id != crypto::fingerprint(&wrapping_key) {
+        return Ok(String::from("wrong-identity wrapped for another key file"));
+    }
+
+    let mut iv = Block::default();
+    iv.bytes_mut()[..IV_SIZE].copy_from_slice(nonce);
+    let mut key = Key::default();
+    key.copy_from_slice(sealed);
+    let mut cipher = Cipher::new(wrapping_key, iv, AAD);
+    cipher
+        .decrypt_blocks_inplace(&mut key)
+        .map_err(|err| err.to_string())?;
+    if cipher.tag().bytes() != tag {
+        return Ok(String::from(
+            "corrupt invalid tag, the wrapped key was tampered with",
+        ));
+    }
+
+    Ok(format!("file-key {}", hex::encode(&key)))
+}
+
+fn wrapping_key(key_file: &str) -> Result<Key, String> {
+    ioutils::read_key(&Some(key_file.to_string())).map_err(|err| err.to_string())
+}
@@ -2,23 +2,50 @@ use std::process;
 
 use clap::Parser;
 use file_encryptor::{
-    command::{open, seal, Cli, Command},
+    command::{self, bench, config, inspect, key, open, seal, verify, Cli, Command},
     error,
 };
 
 fn main() -> error::Result<()> {
-    let cmd = Cli::parse();
+    let cli = Cli::try_parse().unwrap_or_else(|err| {
+        let _ = err.print();
+        if !err.use_stderr() {
+            // --help
+            process::exit(0);
+        }
+        // clap's own code for usage errors is 2, the code of `io` errors
+        process::exit(error::Error::Other(err.to_string()).status_code().into())
+    });
+    let error_format = cli.error_format;
 
-    let result = match cmd.cmd {
-        Command::Open(f) => open::open(&f),
-        Command::Seal(f) => seal::seal(&f),
-        Command::Keygen(k) => k.gen(),
-    };
-
-    if let Err(err) = &result {
-        eprintln!("{}", err);
+    if let Err(err) = &run(cli) {
+        command::report(err, error_format);
         process::exit(err.status_code().into());
     }
 
     Ok(())
 }
+
+fn run(cli: Cli) -> error::Result<()> {
+    let settings = config::load(cli.config.as_deref(), cli.profile.as_deref())?;
+    let mut cmd = cli.cmd;
+    settings.configure(&mut cmd);
+
+    match cmd {
+        Command::Open(f) => open::open(&f),
+        Command::Seal(f) => seal::seal(&f),
+        Command::Keygen(k) => k.gen(),
+        Command::Bench(b) => bench::bench(&b),
+        Command::Verify(v) => verify::verify(&v),
+        Command::Inspect(i) => inspect::inspect(&i),
+        Command::Key(k) => key::key(&k),
+        #[cfg(unix)]
+        Command::Agent(a) => command::agent::agent(&a),
+        Command::Config(c) => match c.cmd {
+            config::ConfigCommand::Show => {
+                config::show(cli.config.as_deref(), cli.profile.as_deref(), &settings);
+                Ok(())
+            }
+        },
+    }
+}
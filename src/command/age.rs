@@ -0,0 +1,35 @@
+use std::{env, fs, path::Path};
+
+use crate::{age::Identity, error};
+
+/// Read instead of asking for the passphrase of an age file on the terminal, for scripts.
+pub const PASSPHRASE_VAR: &str = "FILE_ENCRYPTOR_AGE_PASSPHRASE";
+
+/// The identities of the age identity files `files`, see `Identity::parse_file`.
+pub fn identities(files: &[String]) -> error::Result<Vec<Identity>> {
+    let mut identities = Vec::new();
+    for file in files {
+        let path = Path::new(file);
+        let text = fs::read_to_string(path).map_err(|err| error::Error::io(err, path))?;
+        identities.extend(Identity::parse_file(&text).map_err(|_| {
+            error::Error::Other(format!(
+                "{}: not an age identity file, expected `AGE-SECRET-KEY-1...` lines",
+                path.display()
+            ))
+        })?);
+    }
+    Ok(identities)
+}
+
+/// The passphrase of an age file, from `PASSPHRASE_VAR` or the terminal.
+pub fn passphrase(confirm: bool) -> error::Result<String> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_VAR) {
+        return Ok(passphrase);
+    }
+
+    let passphrase = rpassword::prompt_password("age passphrase: ")?;
+    if confirm && rpassword::prompt_password("confirm passphrase: ")? != passphrase {
+        return Err(error::Error::Other(String::from("the passphrases differ")));
+    }
+    Ok(passphrase)
+}
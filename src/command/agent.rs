@@ -0,0 +1,661 @@
+//! `file-encryptor agent`, which holds keys in memory for a while, so that opening many files
+//! neither asks for the keystore passphrase nor runs scrypt each time.
+//!
+//! The agent listens on a Unix socket in a directory only its user can enter, and only answers
+//! processes of that user. A connection carries one request and its response, each a line of
+//! JSON.
+
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::{self, DirBuilder},
+    io::{self, BufRead, BufReader, Write},
+    os::unix::{
+        fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use clap::{Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    command::key,
+    crypto::{self, Key},
+    error, hex, kdf,
+    keystore::Keystore,
+};
+
+/// Socket of the agent `seal`, `open` and `verify` ask for keys, see `socket_path`.
+pub const SOCKET_VAR: &str = "FILE_ENCRYPTOR_AGENT";
+/// Read instead of asking for the password of `agent add --password` on the terminal.
+pub const PASSWORD_VAR: &str = "FILE_ENCRYPTOR_PASSWORD";
+
+/// a client that sends nothing must not hold the agent up
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Parser, Debug, Clone)]
+pub struct AgentArg {
+    /// (optional) socket of the agent, `$FILE_ENCRYPTOR_AGENT` or
+    /// `$XDG_RUNTIME_DIR/file-encryptor/agent.sock` by default
+    #[arg(long, global = true)]
+    pub socket: Option<String>,
+
+    /// (optional) keystore directory, `$XDG_DATA_HOME/file-encryptor/keys` by default
+    #[arg(long, global = true)]
+    pub keystore: Option<String>,
+
+    /// scrypt parameters of password keys, from the config file
+    #[arg(skip)]
+    pub(crate) kdf: kdf::Params,
+
+    #[command(subcommand)]
+    pub cmd: AgentCommand,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum AgentCommand {
+    /// run the agent, until `agent stop`
+    Start {
+        /// (optional) seconds a key is held, unless added with another lifetime
+        #[arg(long, default_value_t = 900)]
+        timeout: u64,
+    },
+
+    /// unlock the key NAME of the keystore, or derive one from a password, and hand it to the
+    /// agent. `--key-name NAME` then gets it from the agent, as long as it is the key NAME of
+    /// the keystore in use, or that keystore has none
+    Add {
+        name: String,
+
+        /// (optional) derive the key from a password as `keygen --password` does, instead of
+        /// unlocking it from the keystore
+        #[arg(long, default_value_t = false)]
+        password: bool,
+
+        /// (optional) seconds the agent holds this key, its timeout by default
+        #[arg(long)]
+        lifetime: Option<u64>,
+    },
+
+    /// list the keys held, with their ids and the seconds they have left
+    List,
+
+    /// forget the keys NAME, of every keystore and password
+    Remove { name: String },
+
+    /// forget every key, the agent keeps running
+    Lock,
+
+    /// forget every key, and stop the agent
+    Stop,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Request {
+    /// `keystore` is the one the key was unlocked from, see `keystore_id`, `None` for a key
+    /// derived from a password
+    Add {
+        name: String,
+        #[serde(default)]
+        keystore: Option<PathBuf>,
+        key: Key,
+        lifetime: Option<u64>,
+    },
+    Get {
+        name: String,
+        #[serde(default)]
+        keystore: Option<PathBuf>,
+    },
+    List,
+    Remove {
+        name: String,
+    },
+    Lock,
+    Stop,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Response {
+    Ok,
+    Key { key: Key },
+    Keys { keys: Vec<Held> },
+    NotFound,
+    Error { message: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Held {
+    pub name: String,
+    #[serde(default)]
+    pub keystore: Option<PathBuf>,
+    pub id: String,
+    pub expires_in: u64,
+}
+
+pub fn agent(arg: &AgentArg) -> error::Result<()> {
+    let socket = match &arg.socket {
+        Some(socket) => PathBuf::from(socket),
+        None => socket_path(),
+    };
+
+    let request = match &arg.cmd {
+        AgentCommand::Start { timeout } => {
+            eprintln!("agent listening on {}", socket.display());
+            return serve(&socket, Duration::from_secs(*timeout));
+        }
+        AgentCommand::Add {
+            name,
+            password,
+            lifetime,
+        } => {
+            let (keystore, key) = if *password {
+                let password = match env::var(PASSWORD_VAR) {
+                    Ok(password) => password,
+                    Err(_) => rpassword::prompt_password("password: ")?,
+                };
+                (None, kdf::Hash::new(arg.kdf)?.hash(password.as_bytes()))
+            } else {
+                let store = key::keystore(arg.keystore.as_deref())?;
+                let key = key::read_key(&None, Some(name), arg.keystore.as_deref())?;
+                (Some(keystore_id(&store)), key)
+            };
+            Request::Add {
+                name: name.clone(),
+                keystore,
+                key,
+                lifetime: *lifetime,
+            }
+        }
+        AgentCommand::List => Request::List,
+        AgentCommand::Remove { name } => Request::Remove { name: name.clone() },
+        AgentCommand::Lock => Request::Lock,
+        AgentCommand::Stop => Request::Stop,
+    };
+
+    match request_to(&socket, &request)? {
+        Response::Keys { keys } => {
+            for held in keys {
+                let from = match &held.keystore {
+                    Some(dir) => dir.display().to_string(),
+                    None => String::from("(password)"),
+                };
+                let expires_in = format!("{}s", held.expires_in);
+                println!("{:<24}{:<20}{:<8}{}", held.name, held.id, expires_in, from);
+            }
+            Ok(())
+        }
+        Response::NotFound => Err(error::Error::Other(format!(
+            "the agent holds no key {}",
+            match request {
+                Request::Remove { name } => format!("`{}`", name),
+                _ => String::from("of that name"),
+            }
+        ))),
+        Response::Error { message } => Err(error::Error::Other(message)),
+        Response::Ok | Response::Key { .. } => Ok(()),
+    }
+}
+
+/// The key `name` of `keystore` (see `keystore_id`), or derived from a password when `None`, held
+/// by the agent. `None` when it does not hold it, or when no agent runs.
+pub fn held_key(name: &str, keystore: Option<PathBuf>) -> error::Result<Option<Key>> {
+    let socket = socket_path();
+    if !socket.exists() {
+        return Ok(None);
+    }
+
+    let request = Request::Get {
+        name: name.to_string(),
+        keystore,
+    };
+    match request_to(&socket, &request) {
+        Ok(Response::Key { key }) => Ok(Some(key)),
+        Ok(Response::Error { message }) => Err(error::Error::Other(message)),
+        Ok(_) => Ok(None),
+        // a stale socket, of an agent that is gone
+        Err(error::Error::IO(err, _)) if err.kind() == io::ErrorKind::ConnectionRefused => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// What the agent tells keystores apart by: their canonical directory, so that another spelling
+/// of the same one still finds its keys.
+pub fn keystore_id(store: &Keystore) -> PathBuf {
+    fs::canonicalize(store.dir()).unwrap_or_else(|_| store.dir().to_path_buf())
+}
+
+/// `$FILE_ENCRYPTOR_AGENT`, `$XDG_RUNTIME_DIR/file-encryptor/agent.sock`, or
+/// `/tmp/file-encryptor-UID/agent.sock`.
+pub fn socket_path() -> PathBuf {
+    if let Some(socket) = env::var_os(SOCKET_VAR) {
+        return PathBuf::from(socket);
+    }
+
+    let dir = env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .map(|dir| dir.join("file-encryptor"))
+        .unwrap_or_else(|| env::temp_dir().join(format!("file-encryptor-{}", uid())));
+    dir.join("agent.sock")
+}
+
+/// Sends `request` to the agent at `socket`, once the socket is checked to belong to this user.
+pub fn request_to(socket: &Path, request: &Request) -> error::Result<Response> {
+    check_owner(socket)?;
+    let stream = UnixStream::connect(socket).map_err(|err| error::Error::io(err, socket))?;
+    stream.set_read_timeout(Some(IO_TIMEOUT))?;
+    stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+    let mut line = serde_json::to_vec(request).expect("plain data always serializes");
+    line.push(b'\n');
+    (&stream).write_all(&line)?;
+
+    let mut response = String::new();
+    BufReader::new(&stream).read_line(&mut response)?;
+    serde_json::from_str(&response)
+        .map_err(|_| error::Error::Other(String::from("invalid response from the agent")))
+}
+
+/// Runs the agent on `socket` until a `Stop` request. Keys are held for `timeout`, unless added
+/// with a lifetime of their own.
+pub fn serve(socket: &Path, timeout: Duration) -> error::Result<()> {
+    let dir = socket.parent().unwrap_or(Path::new("."));
+    DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+        .map_err(|err| error::Error::io(err, dir))?;
+    check_dir(dir)?;
+
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            return Err(error::Error::Other(format!(
+                "{}: an agent is already running",
+                socket.display()
+            )));
+        }
+        fs::remove_file(socket).map_err(|err| error::Error::io(err, socket))?;
+    }
+    let listener = UnixListener::bind(socket).map_err(|err| error::Error::io(err, socket))?;
+    fs::set_permissions(socket, fs::Permissions::from_mode(0o600))?;
+
+    let agent = Agent::new(timeout);
+    let stopping = AtomicBool::new(false);
+    thread::scope(|scope| {
+        let (agent, stopping) = (&agent, &stopping);
+        // purges every second, until the sender is dropped
+        let (stop_purger, stopped) = mpsc::channel::<()>();
+        scope.spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(Duration::from_secs(1))
+            {
+                agent.purge();
+            }
+        });
+
+        for stream in listener.incoming() {
+            if stopping.load(Ordering::SeqCst) {
+                break;
+            }
+            let Ok(stream) = stream else {
+                continue;
+            };
+            // a thread each, so that a slow client only holds itself up
+            scope.spawn(move || {
+                if let Ok(Request::Stop) = agent.serve_client(&stream) {
+                    stopping.store(true, Ordering::SeqCst);
+                    // wakes the loop up, blocked until the next connection
+                    let _ = UnixStream::connect(socket);
+                }
+            });
+        }
+
+        drop(stop_purger);
+    });
+
+    agent.lock();
+    fs::remove_file(socket).map_err(|err| error::Error::io(err, socket))
+}
+
+struct Agent {
+    /// by name, then keystore
+    keys: Mutex<BTreeMap<(String, Option<PathBuf>), Entry>>,
+    timeout: Duration,
+}
+
+struct Entry {
+    key: Key,
+    expires: Instant,
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        for byte in self.key.iter_mut() {
+            // not optimized away, unlike a plain fill
+            unsafe { std::ptr::write_volatile(byte, 0) };
+        }
+    }
+}
+
+impl Agent {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            keys: Mutex::new(BTreeMap::new()),
+            timeout,
+        }
+    }
+
+    fn serve_client(&self, stream: &UnixStream) -> io::Result<Request> {
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line)?;
+        let request = serde_json::from_str::<Request>(&line);
+        let response = match (&request, peer_uid(stream)? == uid()) {
+            (_, false) => Response::Error {
+                message: String::from("the agent only answers its own user"),
+            },
+            (Ok(request), true) => self.handle(request),
+            (Err(_), true) => Response::Error {
+                message: String::from("invalid request"),
+            },
+        };
+
+        let mut line = serde_json::to_vec(&response).expect("plain data always serializes");
+        line.push(b'\n');
+        (&*stream).write_all(&line)?;
+        request.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    fn handle(&self, request: &Request) -> Response {
+        self.purge();
+        let mut keys = self.keys.lock().expect("agent lock poisoned");
+        match request {
+            Request::Add {
+                name,
+                keystore,
+                key,
+                lifetime,
+            } => {
+                let lifetime = lifetime.map_or(self.timeout, Duration::from_secs);
+                let entry = Entry {
+                    key: *key,
+                    expires: Instant::now() + lifetime,
+                };
+                keys.insert((name.clone(), keystore.clone()), entry);
+                Response::Ok
+            }
+            Request::Get { name, keystore } => match keys.get(&(name.clone(), keystore.clone())) {
+                Some(entry) => Response::Key { key: entry.key },
+                None => Response::NotFound,
+            },
+            Request::List => {
+                let now = Instant::now();
+                let keys = keys
+                    .iter()
+                    .map(|((name, keystore), entry)| Held {
+                        name: name.clone(),
+                        keystore: keystore.clone(),
+                        id: hex::encode(&crypto::fingerprint(&entry.key)),
+                        expires_in: entry.expires.saturating_duration_since(now).as_secs(),
+                    })
+                    .collect();
+                Response::Keys { keys }
+            }
+            Request::Remove { name } => {
+                let held = keys.len();
+                keys.retain(|(held_name, _), _| held_name != name);
+                if keys.len() < held {
+                    Response::Ok
+                } else {
+                    Response::NotFound
+                }
+            }
+            Request::Lock | Request::Stop => {
+                keys.clear();
+                Response::Ok
+            }
+        }
+    }
+
+    fn purge(&self) {
+        let now = Instant::now();
+        let mut keys = self.keys.lock().expect("agent lock poisoned");
+        keys.retain(|_, entry| entry.expires > now);
+    }
+
+    fn lock(&self) {
+        self.keys.lock().expect("agent lock poisoned").clear();
+    }
+}
+
+/// The agent's directory must be this user's alone, or another user could swap the socket.
+fn check_dir(dir: &Path) -> error::Result<()> {
+    let metadata = fs::metadata(dir).map_err(|err| error::Error::io(err, dir))?;
+    if metadata.uid() != uid() || metadata.mode() & 0o077 != 0 {
+        return Err(error::Error::Other(format!(
+            "{}: the agent directory must belong to this user, with mode 700",
+            dir.display()
+        )));
+    }
+
+    Ok(())
+}
+
+/// A socket of another user could be an agent collecting keys.
+fn check_owner(socket: &Path) -> error::Result<()> {
+    let metadata = fs::metadata(socket).map_err(|err| error::Error::io(err, socket))?;
+    if metadata.uid() != uid() {
+        return Err(error::Error::Other(format!(
+            "{}: the agent socket belongs to another user",
+            socket.display()
+        )));
+    }
+
+    Ok(())
+}
+
+fn uid() -> u32 {
+    // never fails
+    unsafe { libc::getuid() }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: `cred` and `len` describe a buffer of the size SO_PEERCRED writes
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut cred as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let (mut uid, mut gid) = (0, 0);
+    // SAFETY: both are plain out parameters
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(uid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start(dir: &Path, timeout: Duration) -> (PathBuf, thread::JoinHandle<error::Result<()>>) {
+        let socket = dir.join("agent/agent.sock");
+        let server = {
+            let socket = socket.clone();
+            thread::spawn(move || serve(&socket, timeout))
+        };
+        while UnixStream::connect(&socket).is_err() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        (socket, server)
+    }
+
+    fn get(socket: &Path, name: &str) -> Response {
+        let name = name.to_string();
+        request_to(
+            socket,
+            &Request::Get {
+                name,
+                keystore: None,
+            },
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn agent_holds_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let (socket, server) = start(dir.path(), Duration::from_secs(60));
+        let mode = fs::metadata(&socket).unwrap().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(serve(&socket, Duration::from_secs(60)).is_err());
+
+        for (name, key) in [("a", [1; 32]), ("b", [2; 32])] {
+            let add = Request::Add {
+                name: name.to_string(),
+                keystore: None,
+                key,
+                lifetime: None,
+            };
+            assert_eq!(request_to(&socket, &add).unwrap(), Response::Ok);
+        }
+        assert_eq!(get(&socket, "a"), Response::Key { key: [1; 32] });
+        assert_eq!(get(&socket, "c"), Response::NotFound);
+
+        let Response::Keys { keys } = request_to(&socket, &Request::List).unwrap() else {
+            panic!("expected keys");
+        };
+        assert_eq!(keys.len(), 2);
+        assert_eq!((keys[1].name.as_str(), keys[1].id.len()), ("b", 16));
+        assert!(keys[0].expires_in > 50);
+
+        let remove = Request::Remove {
+            name: String::from("a"),
+        };
+        assert_eq!(request_to(&socket, &remove).unwrap(), Response::Ok);
+        assert_eq!(request_to(&socket, &remove).unwrap(), Response::NotFound);
+        assert_eq!(request_to(&socket, &Request::Lock).unwrap(), Response::Ok);
+        assert_eq!(get(&socket, "b"), Response::NotFound);
+
+        assert_eq!(request_to(&socket, &Request::Stop).unwrap(), Response::Ok);
+        server.join().unwrap().unwrap();
+        assert!(!socket.exists());
+    }
+
+    #[test]
+    fn agent_keys_by_keystore() {
+        let dir = tempfile::tempdir().unwrap();
+        let (socket, server) = start(dir.path(), Duration::from_secs(60));
+        let work = Some(dir.path().join("work"));
+        for (keystore, key) in [(None, [1; 32]), (work.clone(), [2; 32])] {
+            let add = Request::Add {
+                name: String::from("a"),
+                keystore,
+                key,
+                lifetime: None,
+            };
+            request_to(&socket, &add).unwrap();
+        }
+
+        // a key of one keystore, or of a password, never stands in for another of the same name
+        let get = |keystore: Option<PathBuf>| {
+            let name = String::from("a");
+            request_to(&socket, &Request::Get { name, keystore }).unwrap()
+        };
+        assert_eq!(get(None), Response::Key { key: [1; 32] });
+        assert_eq!(get(work), Response::Key { key: [2; 32] });
+        assert_eq!(get(Some(dir.path().join("home"))), Response::NotFound);
+
+        let remove = Request::Remove {
+            name: String::from("a"),
+        };
+        assert_eq!(request_to(&socket, &remove).unwrap(), Response::Ok);
+        let Response::Keys { keys } = request_to(&socket, &Request::List).unwrap() else {
+            panic!("expected keys");
+        };
+        assert!(keys.is_empty());
+
+        request_to(&socket, &Request::Stop).unwrap();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn agent_key_lifetimes() {
+        let dir = tempfile::tempdir().unwrap();
+        let (socket, server) = start(dir.path(), Duration::from_millis(200));
+        for (name, lifetime) in [("default", None), ("long", Some(60))] {
+            let add = Request::Add {
+                name: name.to_string(),
+                keystore: None,
+                key: [1; 32],
+                lifetime,
+            };
+            request_to(&socket, &add).unwrap();
+        }
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(get(&socket, "default"), Response::NotFound);
+        assert_eq!(get(&socket, "long"), Response::Key { key: [1; 32] });
+
+        request_to(&socket, &Request::Stop).unwrap();
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn agent_serves_clients_concurrently() {
+        let dir = tempfile::tempdir().unwrap();
+        let (socket, server) = start(dir.path(), Duration::from_secs(60));
+
+        // a client that never sends its request holds up no one but itself
+        let idle = UnixStream::connect(&socket).unwrap();
+        let started = Instant::now();
+        assert_eq!(get(&socket, "a"), Response::NotFound);
+        assert!(started.elapsed() < IO_TIMEOUT / 2);
+
+        // nor does it keep the agent, its purger included, from stopping
+        request_to(&socket, &Request::Stop).unwrap();
+        drop(idle);
+        server.join().unwrap().unwrap();
+        assert!(!socket.exists());
+    }
+
+    #[test]
+    fn agent_refuses_shared_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = dir.path().join("agent");
+        fs::create_dir(&shared).unwrap();
+        fs::set_permissions(&shared, fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(serve(&shared.join("agent.sock"), Duration::from_secs(1)).is_err());
+    }
+}
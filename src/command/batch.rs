@@ -0,0 +1,274 @@
+use std::{
+    collections::HashSet,
+    ffi::{OsStr, OsString},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use rayon::prelude::*;
+
+use crate::{error, ioutils};
+
+/// Suffix added to sealed files, and stripped from opened ones, unless `--suffix` or the config
+/// file set another.
+pub const SUFFIX: &str = ".enc";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Job {
+    pub input: PathBuf,
+    pub output: PathBuf,
+}
+
+/// The suffix of `--suffix` (or the config file), `SUFFIX` by default. An empty one is refused,
+/// it would map every file onto itself.
+pub fn suffix(suffix: Option<&str>) -> error::Result<&str> {
+    match suffix.unwrap_or(SUFFIX) {
+        "" => Err(error::Error::Other(String::from(
+            "the batch suffix cannot be empty, outputs would overwrite their inputs",
+        ))),
+        suffix => Ok(suffix),
+    }
+}
+
+/// Name of the sealed file, `report.pdf` becomes `report.pdf.enc`.
+pub fn sealed_name(name: &OsStr, suffix: &str) -> Option<OsString> {
+    let mut name = name.to_os_string();
+    name.push(suffix);
+    Some(name)
+}
+
+/// Name of the opened file, `None` unless the name ends with the suffix. The rest of the name
+/// need not be UTF-8.
+pub fn opened_name(name: &OsStr, suffix: &str) -> Option<OsString> {
+    let stripped = name.as_encoded_bytes().strip_suffix(suffix.as_bytes())?;
+    if stripped.is_empty() {
+        return None;
+    }
+
+    // split right before `suffix`, which is UTF-8, as `from_encoded_bytes_unchecked` allows
+    Some(unsafe { OsStr::from_encoded_bytes_unchecked(stripped) }.to_os_string())
+}
+
+/// Collects the files to process, mirrored below `out_dir`.
+///
+/// A file given by name lands directly in `out_dir`, the files found (recursively) in a
+/// directory keep their path relative to it. Files `rename` has no name for are skipped while
+/// walking a directory, and refused when they were given by name.
+pub fn jobs<F>(paths: &[String], out_dir: &Path, rename: F) -> error::Result<Vec<Job>>
+where
+    F: Fn(&OsStr) -> Option<OsString>,
+{
+    let mut jobs = Vec::new();
+    for path in paths.iter().map(PathBuf::from) {
+        let metadata = fs::metadata(&path).map_err(|err| error::Error::io(err, &path))?;
+        if metadata.is_dir() {
+            walk(&path, out_dir, &rename, &mut jobs)?;
+            continue;
+        }
+
+        let name = path.file_name().unwrap_or_default();
+        let Some(output) = rename(name) else {
+            return Err(error::Error::Other(format!(
+                "{}: unexpected file name, sealed files end with the batch suffix",
+                path.display()
+            )));
+        };
+        jobs.push(Job {
+            input: path,
+            output: out_dir.join(output),
+        });
+    }
+
+    // two inputs writing to the same output would race each other
+    let mut outputs = HashSet::new();
+    for job in &jobs {
+        if !outputs.insert(&job.output) {
+            return Err(error::Error::Other(format!(
+                "{}: more than one input maps to this output",
+                job.output.display()
+            )));
+        }
+    }
+
+    Ok(jobs)
+}
+
+fn walk<F>(dir: &Path, out_dir: &Path, rename: &F, jobs: &mut Vec<Job>) -> error::Result<()>
+where
+    F: Fn(&OsStr) -> Option<OsString>,
+{
+    let mut entries = fs::read_dir(dir)
+        .and_then(|entries| entries.collect::<std::io::Result<Vec<_>>>())
+        .map_err(|err| error::Error::io(err, dir))?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        // symlinks are not followed, so a link cannot pull in files from outside the tree
+        let file_type = entry
+            .file_type()
+            .map_err(|err| error::Error::io(err, &path))?;
+
+        if file_type.is_dir() {
+            walk(&path, &out_dir.join(entry.file_name()), rename, jobs)?;
+        } else if file_type.is_file() {
+            if let Some(output) = rename(&entry.file_name()) {
+                jobs.push(Job {
+                    input: path,
+                    output: out_dir.join(output),
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs `f` over every job in parallel, then prints a summary to stderr. Fails if any job did. A
+/// job whose output is its input fails without running, `f` would truncate it.
+pub fn run<F>(jobs: &[Job], verb: &str, f: F) -> error::Result<()>
+where
+    F: Fn(&Job) -> error::Result<()> + Sync,
+{
+    let failures: Vec<(&Job, error::Error)> = jobs
+        .par_iter()
+        .filter_map(|job| {
+            let result = job
+                .output
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .and_then(|_| ioutils::ensure_distinct(Some(&job.input), Some(&job.output)))
+                .map_err(error::Error::from)
+                .and_then(|_| f(job));
+
+            result.err().map(|err| (job, err))
+        })
+        .collect();
+
+    for (job, err) in &failures {
+        match err.location().and_then(|at| at.path.as_ref()) {
+            Some(_) => eprintln!("{}", err),
+            None => eprintln!("{}: {}", job.input.display(), err),
+        }
+    }
+    eprintln!(
+        "{} {} file(s), {} failed",
+        verb,
+        jobs.len() - failures.len(),
+        failures.len()
+    );
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(error::Error::Other(format!(
+            "{} of {} file(s) failed",
+            failures.len(),
+            jobs.len()
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batch_names() {
+        let sealed = |name| sealed_name(OsStr::new(name), SUFFIX);
+        let opened = |name| opened_name(OsStr::new(name), SUFFIX);
+        assert_eq!(sealed("a.txt"), Some("a.txt.enc".into()));
+        assert_eq!(opened("a.txt.enc"), Some("a.txt".into()));
+        assert_eq!(opened("a.txt"), None);
+        assert_eq!(opened(".enc"), None);
+        assert_eq!(suffix(None).unwrap(), SUFFIX);
+        assert!(suffix(Some("")).is_err());
+        assert_eq!(
+            opened_name(OsStr::new("a.txt.bak"), ".bak"),
+            Some("a.txt".into())
+        );
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            let latin1 = OsStr::from_bytes(b"caf\xe9.txt.enc");
+            assert_eq!(
+                opened_name(latin1, SUFFIX).unwrap(),
+                OsStr::from_bytes(b"caf\xe9.txt")
+            );
+        }
+    }
+
+    #[test]
+    fn batch_jobs_mirror_tree() {
+        let sealed_name = |name: &OsStr| sealed_name(name, SUFFIX);
+        let opened_name = |name: &OsStr| opened_name(name, SUFFIX);
+        let src = tempfile::tempdir().unwrap();
+        fs::create_dir_all(src.path().join("a/b")).unwrap();
+        fs::write(src.path().join("a/b/one.log"), b"1").unwrap();
+        fs::write(src.path().join("two.log.enc"), b"2").unwrap();
+        let single = src.path().join("a/b/one.log");
+
+        let dir = src.path().to_str().unwrap().to_string();
+        let out = Path::new("out");
+        let jobs = jobs(std::slice::from_ref(&dir), out, sealed_name).unwrap();
+        assert_eq!(
+            jobs,
+            vec![
+                Job {
+                    input: src.path().join("a/b/one.log"),
+                    output: out.join("a/b/one.log.enc"),
+                },
+                Job {
+                    input: src.path().join("two.log.enc"),
+                    output: out.join("two.log.enc.enc"),
+                },
+            ]
+        );
+
+        let jobs = super::jobs(std::slice::from_ref(&dir), out, opened_name).unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].output, out.join("two.log"));
+
+        let single = single.to_str().unwrap().to_string();
+        assert!(super::jobs(std::slice::from_ref(&single), out, opened_name).is_err());
+        assert!(super::jobs(&[single.clone(), dir], out, sealed_name).is_ok());
+        assert!(super::jobs(&[single.clone(), single], out, sealed_name).is_err());
+    }
+
+    #[test]
+    fn batch_run_reports_failures() {
+        let out = tempfile::tempdir().unwrap();
+        let jobs: Vec<Job> = (0..8)
+            .map(|i| Job {
+                input: PathBuf::from(i.to_string()),
+                output: out.path().join(i.to_string()),
+            })
+            .collect();
+
+        assert!(run(&jobs, "sealed", |_| Ok(())).is_ok());
+
+        // never run onto its own input
+        let file = out.path().join("file");
+        fs::write(&file, b"plaintext").unwrap();
+        let onto_itself = [Job {
+            input: file.clone(),
+            output: file.clone(),
+        }];
+        assert!(run(&onto_itself, "sealed", |job| Ok(fs::write(
+            &job.output,
+            b""
+        )?))
+        .is_err());
+        assert_eq!(fs::read(&file).unwrap(), b"plaintext");
+
+        assert!(run(&jobs, "sealed", |job| {
+            if job.input == Path::new("3") {
+                Err(error::Error::Other("boom".to_string()))
+            } else {
+                Ok(())
+            }
+        })
+        .is_err());
+    }
+}
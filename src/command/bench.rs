@@ -0,0 +1,76 @@
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use rand::RngCore;
+
+use crate::{
+    crypto::Key,
+    error,
+    format::{Compression, Header},
+    seekable,
+    stream::{Opener, Sealer, BUFFER_SIZE},
+};
+
+#[derive(Parser, Debug, Clone)]
+pub struct BenchArg {
+    /// (optional) MiB of random data to seal, then open
+    #[arg(short, long, default_value_t = 64)]
+    pub size: usize,
+
+    /// (optional) compress the plaintext before encrypting it
+    #[arg(short, long, value_enum)]
+    pub compress: Option<Compression>,
+
+    /// (optional) seal in independently authenticated chunks
+    #[arg(long, default_value_t = false, conflicts_with = "compress")]
+    pub seekable: bool,
+}
+
+/// Seals and opens random data in memory, with a random key, and prints the throughput of each.
+/// No disk is involved, so this is the ceiling of what `seal` and `open` can do.
+pub fn bench(arg: &BenchArg) -> error::Result<()> {
+    let mut rng = rand::thread_rng();
+    let mut plaintext = vec![0_u8; arg.size * 1024 * 1024];
+    rng.fill_bytes(&mut plaintext);
+    let mut key = Key::default();
+    rng.fill_bytes(&mut key);
+
+    let mut header = Header::new(arg.compress.unwrap_or_default());
+    header.chunk_size = arg.seekable.then_some(seekable::DEFAULT_CHUNK_SIZE);
+
+    let start = Instant::now();
+    let mut sealed = Vec::with_capacity(plaintext.len() + BUFFER_SIZE);
+    let mut sealer = Sealer::new(key, header)?;
+    for piece in plaintext.chunks(BUFFER_SIZE) {
+        sealer.update(piece, &mut sealed)?;
+    }
+    sealer.finish(&mut sealed)?;
+    report("seal", plaintext.len(), start.elapsed());
+
+    let start = Instant::now();
+    let mut opened = Vec::with_capacity(plaintext.len());
+    let mut opener = Opener::new(key);
+    for piece in sealed.chunks(BUFFER_SIZE) {
+        opener.update(piece, &mut opened)?;
+    }
+    opener.finish(&mut opened)?;
+    report("open", plaintext.len(), start.elapsed());
+
+    if opened != plaintext {
+        return Err(error::Error::Other(String::from(
+            "opened data differs from the sealed data",
+        )));
+    }
+
+    Ok(())
+}
+
+fn report(verb: &str, size: usize, elapsed: Duration) {
+    println!(
+        "{}: {} MiB in {:.2?}, {:.1} MB/s",
+        verb,
+        size / (1024 * 1024),
+        elapsed,
+        size as f64 / 1e6 / elapsed.as_secs_f64()
+    );
+}
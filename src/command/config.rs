@@ -0,0 +1,418 @@
+//! The config file, `$XDG_CONFIG_HOME/file-encryptor/config.toml` (`~/.config` when unset) or
+//! the one given with `--config`. Its top level holds defaults, and each `[profiles.NAME]` table
+//! overrides them when picked with `--profile NAME`. Flags given on the command line always win.
+//!
+//! ```toml
+//! key = "~/.keys/default.key"
+//!
+//! [profiles.backups]
+//! key_name = "prod-backups"
+//! compression = "zstd"
+//! suffix = ".bak"
+//! kdf = { log_n = 17, r = 8, p = 1 }
+//! ```
+
+use std::{
+    collections::BTreeMap,
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use clap::{Parser, Subcommand};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    command::{batch, Command},
+    error,
+    format::Compression,
+    kdf,
+    keystore::Keystore,
+};
+
+#[derive(Parser, Debug, Clone)]
+pub struct ConfigArg {
+    #[command(subcommand)]
+    pub cmd: ConfigCommand,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ConfigCommand {
+    /// print the settings in effect, with `--profile` applied, as TOML
+    Show,
+}
+
+/// The settings of the top level, or of a profile. Unset ones fall back to the top level, then
+/// to the built-in defaults.
+#[derive(Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// key file of `seal`, `open` and `verify`, a leading `~/` is the home directory
+    pub key: Option<String>,
+    /// or the name of a key of the keystore, see `key`
+    pub key_name: Option<String>,
+    /// keystore directory, a leading `~/` is the home directory
+    pub keystore: Option<String>,
+    /// compression of `seal`, ignored with `--seekable`
+    pub compression: Option<Compression>,
+    /// suffix of sealed files in batch mode
+    pub suffix: Option<String>,
+    /// scrypt parameters of `keygen`
+    pub kdf: Option<kdf::Params>,
+}
+
+impl Settings {
+    fn or(self, defaults: Settings) -> Settings {
+        // a key file and a key name replace each other
+        let (key, key_name) = match (&self.key, &self.key_name) {
+            (None, None) => (defaults.key, defaults.key_name),
+            _ => (self.key, self.key_name),
+        };
+        Settings {
+            key,
+            key_name,
+            keystore: self.keystore.or(defaults.keystore),
+            compression: self.compression.or(defaults.compression),
+            suffix: self.suffix.or(defaults.suffix),
+            kdf: self.kdf.or(defaults.kdf),
+        }
+    }
+
+    /// Fills the options `cmd` was not given on the command line.
+    pub fn configure(&self, cmd: &mut Command) {
+        let keystore = self.keystore.as_deref().map(expand_home);
+        // a key given any way on the command line, a plugin included, replaces the configured one
+        let fill_key = |key: &mut Option<String>, key_name: &mut Option<String>, plugin: bool| {
+            if key.is_none() && key_name.is_none() && !plugin {
+                *key = self.key.as_deref().map(expand_home);
+                *key_name = self.key_name.clone();
+            }
+        };
+        match cmd {
+            Command::Seal(arg) => {
+                let plugin = arg.file.plugin.is_some();
+                fill_key(&mut arg.file.key, &mut arg.file.key_name, plugin);
+                arg.file.keystore = arg.file.keystore.take().or(keystore);
+                // seekable files are never compressed
+                if !arg.seekable {
+                    arg.compress = arg.compress.or(self.compression);
+                }
+                arg.suffix = arg.suffix.take().or_else(|| self.suffix.clone());
+            }
+            Command::Open(arg) => {
+                let plugin = arg.file.plugin.is_some();
+                fill_key(&mut arg.file.key, &mut arg.file.key_name, plugin);
+                arg.file.keystore = arg.file.keystore.take().or(keystore);
+                arg.suffix = arg.suffix.take().or_else(|| self.suffix.clone());
+            }
+            Command::Verify(arg) => {
+                fill_key(&mut arg.key, &mut arg.key_name, arg.plugin.is_some());
+                arg.keystore = arg.keystore.take().or(keystore);
+            }
+            Command::Key(arg) => arg.keystore = arg.keystore.take().or(keystore),
+            #[cfg(unix)]
+            Command::Agent(arg) => {
+                arg.keystore = arg.keystore.take().or(keystore);
+                arg.kdf = self.kdf.unwrap_or_default();
+            }
+            Command::Keygen(arg) => arg.kdf = self.kdf.unwrap_or_default(),
+            Command::Inspect(arg) => arg.kdf = self.kdf.unwrap_or_default(),
+            Command::Bench(_) | Command::Config(_) => {}
+        }
+    }
+}
+
+/// A parsed config file.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct File {
+    defaults: Settings,
+    profiles: BTreeMap<String, Settings>,
+}
+
+impl File {
+    fn parse(text: &str) -> Result<Self, String> {
+        fn settings<T: DeserializeOwned>(value: toml::Value) -> Result<T, String> {
+            value
+                .try_into()
+                .map_err(|err: toml::de::Error| err.message().to_string())
+        }
+
+        let mut table: toml::Table = text
+            .parse()
+            .map_err(|err: toml::de::Error| err.to_string())?;
+        let profiles = match table.remove("profiles") {
+            None => BTreeMap::new(),
+            Some(profiles) => settings(profiles).map_err(|err| format!("in a profile, {}", err))?,
+        };
+        let defaults = settings(toml::Value::Table(table))?;
+
+        Ok(Self { defaults, profiles })
+    }
+
+    fn settings(mut self, profile: Option<&str>) -> error::Result<Settings> {
+        let both = |settings: &Settings| settings.key.is_some() && settings.key_name.is_some();
+        if let Some(name) = self
+            .profiles
+            .keys()
+            .find(|name| both(&self.profiles[*name]))
+        {
+            return Err(error::Error::Other(format!(
+                "profile `{}` sets both `key` and `key_name`",
+                name
+            )));
+        }
+        if both(&self.defaults) {
+            return Err(error::Error::Other(String::from(
+                "the config file sets both `key` and `key_name`",
+            )));
+        }
+
+        let Some(name) = profile else {
+            return Ok(self.defaults);
+        };
+        match self.profiles.remove(name) {
+            Some(settings) => Ok(settings.or(self.defaults)),
+            None if self.profiles.is_empty() => Err(error::Error::Other(format!(
+                "unknown profile `{}`, the config file defines none",
+                name
+            ))),
+            None => Err(error::Error::Other(format!(
+                "unknown profile `{}`, expected one of: {}",
+                name,
+                self.profiles.into_keys().collect::<Vec<_>>().join(", ")
+            ))),
+        }
+    }
+}
+
+/// Path of the config file, `None` when there is neither `$XDG_CONFIG_HOME` nor `$HOME`.
+pub fn path(explicit: Option<&str>) -> Option<PathBuf> {
+    if let Some(path) = explicit {
+        return Some(PathBuf::from(path));
+    }
+
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config_dir.join("file-encryptor").join("config.toml"))
+}
+
+/// The settings of `profile`, from the config file at `explicit`, or at the default path. Only
+/// a config file given with `--config` has to exist.
+pub fn load(explicit: Option<&str>, profile: Option<&str>) -> error::Result<Settings> {
+    let file = match path(explicit) {
+        None => File::default(),
+        Some(path) => match fs::read_to_string(&path) {
+            Ok(text) => File::parse(&text).map_err(|err| {
+                error::Error::Other(format!("{}: invalid config file, {}", path.display(), err))
+            })?,
+            Err(err) if explicit.is_none() && err.kind() == io::ErrorKind::NotFound => {
+                File::default()
+            }
+            Err(err) => return Err(error::Error::io(err, &path)),
+        },
+    };
+
+    file.settings(profile)
+}
+
+/// `config show`: the settings `load` returned, with the built-in defaults of the unset ones.
+pub fn show(explicit: Option<&str>, profile: Option<&str>, settings: &Settings) {
+    #[derive(Serialize)]
+    struct Effective<'a> {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        key: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        key_name: Option<&'a str>,
+        keystore: Option<String>,
+        compression: Compression,
+        suffix: &'a str,
+        kdf: kdf::Params,
+    }
+
+    let effective = Effective {
+        key: settings.key.as_deref().map(expand_home),
+        key_name: settings.key_name.as_deref(),
+        keystore: settings
+            .keystore
+            .as_deref()
+            .map(expand_home)
+            .or_else(|| Some(Keystore::default_dir()?.display().to_string())),
+        compression: settings.compression.unwrap_or_default(),
+        suffix: settings.suffix.as_deref().unwrap_or(batch::SUFFIX),
+        kdf: settings.kdf.unwrap_or_default(),
+    };
+
+    match path(explicit) {
+        Some(path) if path.is_file() => println!("# config file: {}", path.display()),
+        Some(path) => println!("# config file: {} (not found)", path.display()),
+        None => println!("# config file: none"),
+    }
+    if let Some(profile) = profile {
+        println!("# profile: {}", profile);
+    }
+    if effective.key.is_none() && effective.key_name.is_none() {
+        println!("# key: read from stdin");
+    }
+    print!(
+        "{}",
+        toml::to_string(&effective).expect("plain data always serializes")
+    );
+}
+
+fn expand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), env::var("HOME")) {
+        (Some(rest), Ok(home)) => Path::new(&home).join(rest).display().to_string(),
+        _ => path.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::command::Cli;
+    use clap::Parser;
+
+    const CONFIG: &str = r#"
+        key = "/keys/default.key"
+        suffix = ".sealed"
+
+        [profiles.backups]
+        key = "/keys/backups.key"
+        compression = "zstd"
+        kdf = { log_n = 17 }
+
+        [profiles.fast]
+        kdf = { log_n = 10, r = 8, p = 1 }
+
+        [profiles.stored]
+        key_name = "prod"
+        keystore = "/keys/store"
+    "#;
+
+    fn configured(profile: Option<&str>, args: &[&str]) -> Command {
+        let settings = File::parse(CONFIG).unwrap().settings(profile).unwrap();
+        let mut cmd = Cli::parse_from([&["file-encryptor"], args].concat()).cmd;
+        settings.configure(&mut cmd);
+        cmd
+    }
+
+    #[test]
+    fn config_profiles() {
+        let file = File::parse(CONFIG).unwrap();
+        assert_eq!(file.defaults.compression, None);
+
+        let backups = file.settings(Some("backups")).unwrap();
+        assert_eq!(
+            backups,
+            Settings {
+                key: Some("/keys/backups.key".into()),
+                key_name: None,
+                keystore: None,
+                compression: Some(Compression::Zstd),
+                suffix: Some(".sealed".into()),
+                kdf: Some(kdf::Params {
+                    log_n: 17,
+                    ..Default::default()
+                }),
+            }
+        );
+
+        let err = File::parse(CONFIG).unwrap().settings(Some("nope"));
+        assert!(err
+            .unwrap_err()
+            .to_string()
+            .contains("backups, fast, stored"));
+        assert!(File::default().settings(Some("backups")).is_err());
+        assert_eq!(File::default().settings(None).unwrap(), Settings::default());
+    }
+
+    #[test]
+    fn config_rejects_unknown_settings() {
+        assert!(File::parse("armor = true").unwrap_err().contains("armor"));
+        assert!(File::parse("[profiles.a]\ncipher = \"aes\"")
+            .unwrap_err()
+            .contains("cipher"));
+        assert!(File::parse("compression = \"lz4\"").is_err());
+        assert!(File::parse("kdf = { n = 1 }").is_err());
+        assert!(File::parse("key = ").is_err());
+
+        let both = File::parse("[profiles.a]\nkey = \"k\"\nkey_name = \"n\"").unwrap();
+        assert!(both.settings(None).is_err());
+    }
+
+    #[test]
+    fn config_flags_win() {
+        let Command::Seal(seal) = configured(Some("backups"), &["seal"]) else {
+            unreachable!()
+        };
+        assert_eq!(seal.file.key.as_deref(), Some("/keys/backups.key"));
+        assert_eq!(seal.compress, Some(Compression::Zstd));
+        assert_eq!(seal.suffix.as_deref(), Some(".sealed"));
+
+        let args = ["seal", "-k", "my.key", "-c", "gzip"];
+        let Command::Seal(seal) = configured(Some("backups"), &args) else {
+            unreachable!()
+        };
+        assert_eq!(seal.file.key.as_deref(), Some("my.key"));
+        assert_eq!(seal.compress, Some(Compression::Gzip));
+
+        let Command::Seal(seal) = configured(Some("backups"), &["seal", "--seekable"]) else {
+            unreachable!()
+        };
+        assert_eq!(seal.compress, None);
+
+        let Command::Verify(verify) = configured(None, &["verify"]) else {
+            unreachable!()
+        };
+        assert_eq!(verify.key.as_deref(), Some("/keys/default.key"));
+
+        let Command::Verify(verify) = configured(None, &["verify", "--plugin", "kms"]) else {
+            unreachable!()
+        };
+        assert_eq!(verify.key, None);
+
+        // a key name replaces the key file of the top level, and a key file flag the key name
+        let Command::Open(open) = configured(Some("stored"), &["open"]) else {
+            unreachable!()
+        };
+        assert_eq!(open.file.key, None);
+        assert_eq!(open.file.key_name.as_deref(), Some("prod"));
+        assert_eq!(open.file.keystore.as_deref(), Some("/keys/store"));
+        let Command::Verify(verify) = configured(Some("stored"), &["verify", "-k", "my.key"])
+        else {
+            unreachable!()
+        };
+        assert_eq!(verify.key.as_deref(), Some("my.key"));
+        assert_eq!(verify.key_name, None);
+
+        let Command::Keygen(keygen) = configured(Some("fast"), &["keygen"]) else {
+            unreachable!()
+        };
+        assert_eq!(keygen.kdf.log_n, 10);
+        let Command::Keygen(keygen) = configured(None, &["keygen"]) else {
+            unreachable!()
+        };
+        assert_eq!(keygen.kdf, kdf::Params::default());
+        let Command::Inspect(inspect) = configured(Some("fast"), &["inspect", "my.key"]) else {
+            unreachable!()
+        };
+        assert_eq!(inspect.kdf.log_n, 10);
+    }
+
+    #[test]
+    fn config_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        let path = path.to_str().unwrap();
+        assert!(matches!(load(Some(path), None), Err(error::Error::IO(..))));
+
+        fs::write(path, CONFIG).unwrap();
+        let settings = load(Some(path), Some("backups")).unwrap();
+        assert_eq!(settings.compression, Some(Compression::Zstd));
+
+        fs::write(path, "armor = true").unwrap();
+        let err = load(Some(path), None).unwrap_err().to_string();
+        assert!(err.contains(path) && err.contains("armor"));
+    }
+}
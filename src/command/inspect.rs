@@ -0,0 +1,259 @@
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use clap::{Parser, ValueEnum};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    crypto::{self, Key, BLOCK_SIZE, KEY_SIZE},
+    error,
+    format::Header,
+    hex, kdf,
+    plugin::Section,
+};
+
+#[derive(Parser, Debug, Clone)]
+pub struct InspectArg {
+    /// sealed file, or key file, to describe
+    pub file: String,
+
+    /// (optional) print a single JSON object, for scripts
+    #[arg(long, default_value_t = false)]
+    pub json: bool,
+
+    /// scrypt parameters of `keygen`, from the config file
+    #[arg(skip)]
+    pub(crate) kdf: kdf::Params,
+}
+
+/// What can be learned of a file without the key.
+#[derive(Serialize, Debug)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum Description {
+    Sealed {
+        format_version: u8,
+        cipher: &'static str,
+        /// false for a headerless (version 0) file, whose tag only covers the lengths
+        authenticated: bool,
+        nonce: String,
+        compression: String,
+        archive: bool,
+        chunk_size: Option<u32>,
+        chunks: Option<u64>,
+        /// tells whether a key is the right one, but names no key, see `Header::set_key_check`
+        key_check: Option<String>,
+        /// names the keystore key the file was sealed with, see `key list`
+        key_id: Option<String>,
+        /// whether the original name, mode and mtime are sealed along, see `metadata`
+        metadata: bool,
+        /// the plugin that wrapped the key, see `seal --plugin`
+        plugin: Option<String>,
+        /// always none, the key is used as is, see the `kdf` of a key file
+        kdf: Option<Kdf>,
+        header_length: usize,
+        /// everything past the header, tags and padding included
+        payload_length: u64,
+        /// the tag of the last chunk, for a seekable file
+        tag: String,
+    },
+    Key {
+        length: usize,
+        fingerprint: String,
+        /// how `keygen` derives keys with the settings in effect (`--profile` included), a key
+        /// file doesn't record whether it came from it
+        kdf: Kdf,
+    },
+}
+
+#[derive(Serialize, Debug)]
+struct Kdf {
+    algorithm: &'static str,
+    log_n: u8,
+    r: u32,
+    p: u32,
+}
+
+/// Describes a sealed file or a key file, from its size and header alone.
+pub fn inspect(arg: &InspectArg) -> error::Result<()> {
+    let description = describe(Path::new(&arg.file), arg.kdf)?;
+    let value = serde_json::to_value(&description).expect("plain data always serializes");
+
+    if arg.json {
+        println!("{}", value);
+        return Ok(());
+    }
+
+    for (name, value) in value.as_object().expect("an enum of structs") {
+        let name = format!("{}:", name.replace('_', " "));
+        println!("{:<16}{}", name, text(value));
+    }
+    if value["authenticated"] == false {
+        eprintln!(
+            "warning: {}",
+            error::Error::Unauthenticated(error::Location::path(Path::new(&arg.file)))
+        );
+    }
+
+    Ok(())
+}
+
+fn describe(path: &Path, params: kdf::Params) -> error::Result<Description> {
+    let mut file = File::open(path).map_err(|err| error::Error::io(err, path))?;
+    let len = file.metadata()?.len();
+
+    // shorter than any sealed file, even an empty one
+    if len == KEY_SIZE as u64 {
+        let mut key = Key::default();
+        file.read_exact(&mut key)?;
+
+        return Ok(Description::Key {
+            length: KEY_SIZE,
+            fingerprint: hex::encode(&crypto::fingerprint(&key)),
+            kdf: Kdf {
+                algorithm: "scrypt",
+                log_n: params.log_n,
+                r: params.r,
+                p: params.p,
+            },
+        });
+    }
+
+    // a file that starts as one but has a broken header is reported as such
+    let header = Header::read(&mut file).map_err(|err| match err {
+        error::Error::Truncated(_) => not_sealed(),
+        err => err.with_path(path),
+    })?;
+    let plugin = match header.wrap_size {
+        None => None,
+        Some(size) => {
+            let mut section = vec![0_u8; size as usize];
+            file.read_exact(&mut section).map_err(|_| not_sealed())?;
+            let section = Section::parse(&section).map_err(|err| err.with_path(path))?;
+            Some(section.plugin)
+        }
+    };
+    let payload_length = len.saturating_sub(header.size() as u64);
+    if payload_length < 2 * BLOCK_SIZE as u64 || !payload_length.is_multiple_of(BLOCK_SIZE as u64) {
+        return Err(not_sealed());
+    }
+
+    let mut tag = [0_u8; BLOCK_SIZE];
+    file.seek(SeekFrom::End(-(BLOCK_SIZE as i64)))?;
+    file.read_exact(&mut tag)?;
+
+    let compression = header
+        .compression
+        .to_possible_value()
+        .expect("no skipped variant");
+
+    Ok(Description::Sealed {
+        format_version: header.version,
+        cipher: match header.version {
+            0 => "legacy GCM variant, length-only tag",
+            1 => "legacy GCM variant",
+            _ => "AES-256-GCM",
+        },
+        authenticated: header.version != 0,
+        nonce: hex::encode(header.iv.iv_bytes()),
+        compression: compression.get_name().to_string(),
+        archive: header.archive,
+        chunk_size: header.chunk_size,
+        chunks: header
+            .chunk_size
+            .map(|chunk_size| payload_length.div_ceil(chunk_size as u64 + BLOCK_SIZE as u64)),
+        key_check: header.key_check.map(|key_check| hex::encode(&key_check)),
+        key_id: header.key_id.map(|key_id| hex::encode(&key_id)),
+        metadata: header.metadata_size.is_some(),
+        plugin,
+        kdf: None,
+        header_length: header.size(),
+        payload_length,
+        tag: hex::encode(&tag),
+    })
+}
+
+fn not_sealed() -> error::Error {
+    error::Error::Other(String::from("neither a sealed file nor a key file"))
+}
+
+/// A JSON value as a line of text.
+fn text(value: &Value) -> String {
+    match value {
+        Value::Null => String::from("-"),
+        Value::String(s) => s.clone(),
+        Value::Object(fields) => fields
+            .iter()
+            .map(|(name, value)| format!("{} {}", name, text(value)))
+            .collect::<Vec<_>>()
+            .join(", "),
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{format::Compression, stream::SealWriter};
+    use std::{fs, io::Write};
+
+    #[test]
+    fn inspect_sealed_and_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let key: Key = [9; 32];
+        let key_file = dir.path().join("key");
+        fs::write(&key_file, key).unwrap();
+
+        let value = serde_json::to_value(describe(&key_file, kdf::Params::default()).unwrap());
+        let value = value.unwrap();
+        assert_eq!(value["kind"], "key");
+        assert_eq!(
+            value["fingerprint"],
+            hex::encode(&crypto::fingerprint(&key))
+        );
+        assert_eq!(value["kdf"]["algorithm"], "scrypt");
+        assert_eq!(value["kdf"]["log_n"], kdf::SCRYPT_LOG_N);
+
+        // the parameters of the profile in effect
+        let params = kdf::Params {
+            log_n: 17,
+            ..Default::default()
+        };
+        let value = serde_json::to_value(describe(&key_file, params).unwrap()).unwrap();
+        assert_eq!(value["kdf"]["log_n"], 17);
+
+        let mut header = Header::new(Compression::None);
+        header.chunk_size = Some(64);
+        let mut writer = SealWriter::new(Vec::new(), key, header).unwrap();
+        writer.write_all(&[1; 200]).unwrap();
+        let sealed = writer.finish().unwrap();
+        let sealed_file = dir.path().join("sealed");
+        fs::write(&sealed_file, &sealed).unwrap();
+
+        let value = serde_json::to_value(describe(&sealed_file, kdf::Params::default()).unwrap());
+        let value = value.unwrap();
+        assert_eq!(value["kind"], "sealed");
+        assert_eq!(value["format_version"], 2);
+        assert_eq!(value["authenticated"], true);
+        assert_eq!(value["nonce"], hex::encode(header.iv.iv_bytes()));
+        assert_eq!(value["compression"], "none");
+        assert_eq!(value["chunk_size"], 64);
+        assert_eq!(value["chunks"], 4);
+        assert!(value["key_check"].is_string());
+        assert!(value["kdf"].is_null());
+        assert_eq!(
+            value["tag"],
+            hex::encode(&sealed[sealed.len() - BLOCK_SIZE..])
+        );
+        assert_eq!(
+            value["payload_length"],
+            sealed.len() - value["header_length"].as_u64().unwrap() as usize
+        );
+
+        fs::write(&sealed_file, &sealed[..sealed.len() - 1]).unwrap();
+        assert!(describe(&sealed_file, kdf::Params::default()).is_err());
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    env,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use clap::{Parser, Subcommand};
+use rand::RngCore;
+
+use crate::{crypto::Key, error, ioutils, keystore::Keystore};
+
+/// Read instead of asking for the passphrase on the terminal, for scripts.
+pub const PASSPHRASE_VAR: &str = "FILE_ENCRYPTOR_PASSPHRASE";
+
+#[derive(Parser, Debug, Clone)]
+pub struct KeyArg {
+    /// (optional) keystore directory, `$XDG_DATA_HOME/file-encryptor/keys` by default
+    #[arg(long, global = true)]
+    pub keystore: Option<String>,
+
+    #[command(subcommand)]
+    pub cmd: KeyCommand,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum KeyCommand {
+    /// generate a random key, and store it as NAME
+    Add { name: String },
+
+    /// store the key of a key file as NAME
+    Import {
+        name: String,
+
+        /// (optional) key file, read from stdin by default
+        #[arg(short, long)]
+        input_file: Option<String>,
+    },
+
+    /// write the key NAME to a key file
+    Export {
+        name: String,
+
+        /// (optional) key file, write to stdout by default
+        #[arg(short, long)]
+        output_file: Option<String>,
+    },
+
+    /// list the names and ids of the stored keys
+    List,
+
+    /// describe the key NAME, without the passphrase
+    Show { name: String },
+
+    /// delete the key NAME
+    Remove { name: String },
+}
+
+pub fn key(arg: &KeyArg) -> error::Result<()> {
+    let store = keystore(arg.keystore.as_deref())?;
+    match &arg.cmd {
+        KeyCommand::Add { name } => {
+            let mut key = Key::default();
+            rand::thread_rng().fill_bytes(&mut key);
+            add(&store, name, &key)
+        }
+        KeyCommand::Import { name, input_file } => {
+            let key = ioutils::read_key(input_file)?;
+            add(&store, name, &key)
+        }
+        KeyCommand::Export { name, output_file } => {
+            store.entry(name)?;
+            let key = store.key(name, passphrase(false)?.as_bytes())?;
+            let mut output = ioutils::create_output(output_file.as_deref().map(Path::new))?;
+            Ok(output.write_all(&key)?)
+        }
+        KeyCommand::List => {
+            let listing = store.list()?;
+            for err in &listing.skipped {
+                eprintln!("warning: skipping {}", err);
+            }
+            for entry in &listing.entries {
+                println!("{:<24}{}", entry.name, entry.id);
+            }
+            Ok(())
+        }
+        KeyCommand::Show { name } => {
+            let entry = store.entry(name)?;
+            println!("{:<16}{}", "name:", entry.name);
+            println!("{:<16}{}", "id:", entry.id);
+            println!(
+                "{:<16}scrypt, log_n {}, r {}, p {}",
+                "kdf:", entry.kdf.log_n, entry.kdf.r, entry.kdf.p
+            );
+            println!("{:<16}{}", "file:", store.path(name).display());
+            Ok(())
+        }
+        KeyCommand::Remove { name } => store.remove(name),
+    }
+}
+
+fn add(store: &Keystore, name: &str, key: &Key) -> error::Result<()> {
+    // a typo in the first passphrase would lock the keystore
+    let first = store.list()?.entries.is_empty();
+    let entry = store.add(name, key, passphrase(first)?.as_bytes())?;
+    println!("{}", entry.id);
+    Ok(())
+}
+
+/// The key named `key_name`, held by the agent or in the keystore, or the one of the key file `key`
+/// (stdin by default). The agent only stands in for the keystore with a key unlocked from it, or
+/// with one derived from a password when the keystore holds no key of that name.
+pub fn read_key(
+    key: &Option<String>,
+    key_name: Option<&str>,
+    keystore_dir: Option<&str>,
+) -> error::Result<Key> {
+    let Some(name) = key_name else {
+        return ioutils::read_key(key);
+    };
+
+    let store = keystore(keystore_dir)?;
+    let entry = store.entry(name);
+    #[cfg(unix)]
+    {
+        let keystore = entry.is_ok().then(|| super::agent::keystore_id(&store));
+        if let Some(key) = super::agent::held_key(name, keystore)? {
+            return Ok(key);
+        }
+    }
+
+    entry?;
+    store.key(name, passphrase(false)?.as_bytes())
+}
+
+/// The keystore in `dir`, or in `Keystore::default_dir`.
+pub fn keystore(dir: Option<&str>) -> error::Result<Keystore> {
+    dir.map(PathBuf::from)
+        .or_else(Keystore::default_dir)
+        .map(Keystore::new)
+        .ok_or_else(|| {
+            error::Error::Other(String::from(
+                "no keystore directory, neither `$XDG_DATA_HOME` nor `$HOME` is set, see `--keystore`",
+            ))
+        })
+}
+
+/// The master passphrase of the keystore, from `PASSPHRASE_VAR` or the terminal.
+fn passphrase(confirm: bool) -> error::Result<String> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_VAR) {
+        return Ok(passphrase);
+    }
+
+    let passphrase = rpassword::prompt_password("keystore passphrase: ")?;
+    if confirm && rpassword::prompt_password("confirm passphrase: ")? != passphrase {
+        return Err(error::Error::Other(String::from("the passphrases differ")));
+    }
+    Ok(passphrase)
+}
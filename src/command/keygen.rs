@@ -1,30 +1,16 @@
-use crate::{crypto::KEY_SIZE, error, ioutils::IO};
+use crate::{
+    crypto::KEY_SIZE,
+    error,
+    ioutils::IO,
+    kdf::{self, Hash},
+};
 use clap::Parser;
 use rand::Rng;
-use scrypt;
 use std::sync::{Arc, Mutex};
 
 type Key = [u8; KEY_SIZE];
 const MAX_KEY_SIZE: usize = 0xffff;
 
-struct Hash(scrypt::Params);
-
-impl Default for Hash {
-    fn default() -> Self {
-        // https://tobtu.com/minimum-password-settings/
-        Self(scrypt::Params::new(16, 8, 2, KEY_SIZE).expect("invalid param for scrypt"))
-    }
-}
-
-impl Hash {
-    fn hash(&self, payload: &[u8]) -> Key {
-        let mut key = Key::default();
-        scrypt::scrypt(payload, &[], &self.0, &mut key)
-            .expect("invalid keysize buffer, use constant `KEY_SIZE`");
-        key
-    }
-}
-
 struct Engine {
     key_buf: Key,
 }
@@ -69,12 +55,23 @@ pub struct KeyGen {
     /// (Optional) File to write out, default stdout
     #[arg(short, long)]
     output_file: Option<String>,
+
+    /// scrypt parameters, from the config file
+    #[arg(skip)]
+    pub(crate) kdf: kdf::Params,
 }
 
 impl KeyGen {
     pub fn gen(&self) -> error::Result<()> {
         let mut io = IO::new(&self.input_file, &self.output_file)?;
-        let hash = Hash(scrypt::Params::new(16, 8, 2, KEY_SIZE).expect("invalid param for scrypt"));
+        let hash = Hash::new(self.kdf)?;
+        if self.kdf != kdf::Params::default() {
+            // not the key the defaults (and `fe_keygen_password`) derive from the same password
+            eprintln!(
+                "note: scrypt parameters from the config file: log_n = {}, r = {}, p = {}",
+                self.kdf.log_n, self.kdf.r, self.kdf.p
+            );
+        }
 
         if self.rand {
             Ok(with_rand(&mut io, &hash)?)
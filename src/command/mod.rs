@@ -1,9 +1,27 @@
-use crate::ioutils::FileArg;
-use clap::{Parser, Subcommand};
+use std::{error::Error as _, io::Read};
 
+use clap::{Parser, Subcommand, ValueEnum};
+use serde_json::json;
+
+use crate::{
+    crypto::Key,
+    error,
+    plugin::{self, Peeked, Section},
+};
+
+pub mod age;
+#[cfg(unix)]
+pub mod agent;
+pub mod batch;
+pub mod bench;
+pub mod config;
+pub mod inspect;
+pub mod key;
 pub mod keygen;
 pub mod open;
+pub mod progress;
 pub mod seal;
+pub mod verify;
 
 /// A Rust CLI program that streams files for encryption and decryption.
 #[derive(Parser, Debug)]
@@ -11,16 +29,158 @@ pub struct Cli {
     /// Action to perform on the input file
     #[command(subcommand)]
     pub cmd: Command,
+
+    /// (optional) how errors are printed to stderr
+    #[arg(long, value_enum, global = true, default_value_t)]
+    pub error_format: ErrorFormat,
+
+    /// (optional) config file, `$XDG_CONFIG_HOME/file-encryptor/config.toml` by default
+    #[arg(long, global = true)]
+    pub config: Option<String>,
+
+    /// (optional) profile of the config file whose settings apply, see `config show`
+    #[arg(long, global = true)]
+    pub profile: Option<String>,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ErrorFormat {
+    /// a line of text
+    #[default]
+    Text,
+    /// a JSON object on a single line
+    Json,
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct FileArg {
+    /// (optional) input file, read from stdin by default
+    #[arg(short, long)]
+    pub input_file: Option<String>,
+
+    /// (optional) output file, write to stdout by default
+    #[arg(short, long)]
+    pub output_file: Option<String>,
+
+    /*
+    /// (optional) additional authenticated data
+    #[arg(short, long)]
+    pub aad: Option<String>,
+    */
+    /// (optional) key file, read (the first) 32 byte from stdin by default
+    #[arg(short, long)]
+    pub key: Option<String>,
+
+    /// (optional) name of a key of the keystore to use instead of a key file, see `key`
+    #[arg(long, conflicts_with = "key")]
+    pub key_name: Option<String>,
+
+    /// (optional) keystore directory, `$XDG_DATA_HOME/file-encryptor/keys` by default
+    #[arg(long)]
+    pub keystore: Option<String>,
+
+    /// (optional) have the program `file-encryptor-plugin-NAME` wrap a fresh key for every
+    /// sealed file (NAME:RECIPIENT), or unwrap it when opening (NAME[:IDENTITY]), see `plugin`
+    #[arg(long, value_name = "NAME[:DATA]", conflicts_with_all = ["key", "key_name"])]
+    pub plugin: Option<String>,
+}
+
+impl FileArg {
+    pub fn read_key(&self) -> error::Result<Key> {
+        key::read_key(
+            &self.key,
+            self.key_name.as_deref(),
+            self.keystore.as_deref(),
+        )
+    }
+}
+
+/// `NAME[:DATA]` of `--plugin`, as the plugin name and its recipient or identity.
+pub fn plugin_arg(arg: &str) -> (&str, Option<&str>) {
+    match arg.split_once(':') {
+        None => (arg, None),
+        Some((name, data)) => (name, Some(data)),
+    }
+}
+
+/// Unwraps the key of the sealed stream `r` with the plugin of `--plugin` (`NAME[:IDENTITY]`),
+/// and hands back the stream, whole again.
+pub fn unwrap_key<R: Read>(r: R, plugin: &str) -> error::Result<(Key, Peeked<R>)> {
+    let (name, identity) = plugin_arg(plugin);
+    let (header, section, r) = Section::peek(r)?;
+    let section = section.ok_or_else(|| {
+        error::Error::Other(String::from(
+            "no wrapped key in the header, the file was not sealed with `--plugin`",
+        ))
+    })?;
+    if section.plugin != name {
+        return Err(error::Error::Other(format!(
+            "the key was wrapped by the plugin `{}`, not `{}`",
+            section.plugin, name
+        )));
+    }
+
+    // the section follows the header
+    let key =
+        plugin::unwrap(&section, identity).map_err(|err| err.with_offset(header.size() as u64))?;
+    Ok((key, r))
 }
 
 #[derive(Subcommand, Clone, Debug)]
 pub enum Command {
+    /// hold keys in memory for a while, so that `--key-name` neither asks for the passphrase nor
+    /// runs scrypt again
+    #[cfg(unix)]
+    Agent(agent::AgentArg),
+
+    /// measure the throughput of seal and open, in memory
+    Bench(bench::BenchArg),
+
+    /// show the settings of the config file in effect
+    Config(config::ConfigArg),
+
+    /// describe a sealed file or a key file, without the key
+    Inspect(inspect::InspectArg),
+
+    /// manage the named keys of the keystore, sealed under a master passphrase
+    Key(key::KeyArg),
+
     /// generate a key, from pure random bytes, or from an input password.
     Keygen(keygen::KeyGen),
 
-    /// open an encrypted file
-    Open(FileArg),
+    /// open an encrypted file, or restore an encrypted directory
+    Open(open::OpenArg),
+
+    /// seal a plaintext file, or a whole directory
+    Seal(seal::SealArg),
+
+    /// check that a sealed file opens with a key, without writing its plaintext
+    Verify(verify::VerifyArg),
+}
+
+/// Prints `err` to stderr. As JSON, the object holds the `error::Error` variant name (`error`),
+/// its exit code (`code`), the message, the file and offset where it happened (or null), and the
+/// messages of the errors that caused it (`sources`, outermost first).
+pub fn report(err: &error::Error, format: ErrorFormat) {
+    if format == ErrorFormat::Text {
+        eprintln!("{}", err);
+        return;
+    }
 
-    /// seal a plaintext file
-    Seal(FileArg),
+    let at = err.location();
+    let mut sources = Vec::new();
+    let mut source = err.source();
+    while let Some(err) = source {
+        sources.push(err.to_string());
+        source = err.source();
+    }
+    let report = json!({
+        "error": err.name(),
+        "code": err.status_code(),
+        "message": err.to_string(),
+        "path": at.and_then(|at| at.path.as_ref()).map(|path| path.display().to_string()),
+        "offset": at.and_then(|at| at.offset),
+        "sources": sources,
+    });
+    eprintln!("{}", report);
 }
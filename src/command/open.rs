@@ -1,81 +1,496 @@
-use std::{fs::OpenOptions, io::Read};
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    path::Path,
+    thread,
+};
+
+use clap::Parser;
 
 use crate::{
-    crypto::{block::Block, cipher::Cipher, Key, BLOCK_SIZE, IV_SIZE, KEY_SIZE},
+    age::{self, Identity},
+    archive,
+    command::{self, batch, progress, unwrap_key, FileArg},
+    crypto::Key,
     error,
-    ioutils::{FileArg, IO},
+    format::Header,
+    ioutils,
+    metadata::Metadata,
+    mmap,
+    seekable::SeekableOpenReader,
+    stream::{OpenReader, Progress},
 };
 
-pub fn open(arg: &FileArg) -> error::Result<()> {
-    let mut io = IO::new(&arg.input_file, &arg.output_file)?;
+#[derive(Parser, Debug, Clone)]
+pub struct OpenArg {
+    #[command(flatten)]
+    pub file: FileArg,
+
+    /// (optional) list the content of a sealed directory instead of restoring it, once its tag
+    /// checks
+    #[arg(short, long, default_value_t = false)]
+    pub list: bool,
+
+    /// (optional) open every file of the given paths ending with the suffix (`.enc` by default)
+    /// in parallel, mirroring them into the output directory without it
+    #[arg(
+        short,
+        long,
+        default_value_t = false,
+        requires = "output_file",
+        conflicts_with = "list"
+    )]
+    pub batch: bool,
+
+    /// files or directories to open in batch mode
+    #[arg(requires = "batch")]
+    pub paths: Vec<String>,
+
+    /// (optional) suffix of sealed files in batch mode, stripped from the opened ones
+    #[arg(long, requires = "batch")]
+    pub suffix: Option<String>,
+
+    /// (optional) start of the plaintext range to open, only decrypting the chunks it covers.
+    /// needs a file sealed with `--seekable`
+    #[arg(
+        long,
+        requires = "input_file",
+        conflicts_with_all = ["batch", "list", "in_place"]
+    )]
+    pub offset: Option<u64>,
+
+    /// (optional) length of the plaintext range to open, up to the end by default
+    #[arg(
+        long,
+        requires = "input_file",
+        conflicts_with_all = ["batch", "list", "in_place"]
+    )]
+    pub length: Option<u64>,
+
+    /// (optional) always stream, instead of memory mapping the input and output files
+    #[arg(long, default_value_t = false)]
+    pub no_mmap: bool,
+
+    /// (optional) print the bytes opened, throughput and ETA to stderr
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["batch", "list", "offset", "length"]
+    )]
+    pub progress: bool,
+
+    /// (optional) give the output file the mode and mtime sealed with `seal --metadata`
+    #[arg(
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["list", "offset", "length", "in_place"]
+    )]
+    pub restore_metadata: bool,
+
+    /// (optional) write to the name sealed with `seal --metadata`, inside the output directory
+    /// (the current one by default), and restore its metadata
+    #[arg(
+        short = 'O',
+        long,
+        default_value_t = false,
+        conflicts_with_all = ["batch", "list", "offset", "length", "in_place"]
+    )]
+    pub original_name: bool,
+
+    /// (optional) replace the sealed file with its plaintext, only once the tag is verified
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["input_file", "output_file", "batch", "list"]
+    )]
+    pub in_place: Option<String>,
+
+    /// (optional) age identity file, with `AGE-SECRET-KEY-1...` lines, to open age files with.
+    /// repeatable. the passphrase of an age file is asked for, or read from
+    /// `$FILE_ENCRYPTOR_AGE_PASSPHRASE`
+    #[arg(long, conflicts_with_all = ["key", "key_name", "plugin", "batch"])]
+    pub identity: Vec<String>,
+
+    /// (optional) also open headerless files, sealed before the format had a header (version 0).
+    /// their tag only covers the lengths, anyone may have modified or forged them
+    #[arg(long, default_value_t = false)]
+    pub allow_unauthenticated_legacy: bool,
+}
+
+pub fn open(arg: &OpenArg) -> error::Result<()> {
+    let filearg = &arg.file;
+    let reporter = |input: Option<&Path>| arg.progress.then(|| progress::reporter(input));
+
+    if arg.batch {
+        // with a plugin, every file has a key of its own
+        let key = match filearg.plugin {
+            None => Some(filearg.read_key()?),
+            Some(_) => None,
+        };
+        let paths: Vec<String> = filearg
+            .input_file
+            .iter()
+            .chain(&arg.paths)
+            .cloned()
+            .collect();
+        let out_dir = Path::new(filearg.output_file.as_deref().unwrap_or_default());
+        let suffix = batch::suffix(arg.suffix.as_deref())?;
+        let jobs = batch::jobs(&paths, out_dir, |name| batch::opened_name(name, suffix))?;
+
+        return batch::run(&jobs, "opened", |job| {
+            let reader = ioutils::open_input(Some(&job.input))?;
+            let (reader, key) = match (key, &filearg.plugin) {
+                (Some(key), _) => (reader, key),
+                (None, Some(plugin)) => plugin_key(reader, plugin, Some(&job.input))?,
+                (None, None) => unreachable!("a key is read without a plugin"),
+            };
+            let options = Options {
+                input: Some(&job.input),
+                restore_metadata: arg.restore_metadata,
+                legacy: arg.allow_unauthenticated_legacy,
+                ..Default::default()
+            };
+            open_stream(reader, key, Some(&job.output), options)
+        });
+    }
 
-    let key = match &arg.key {
-        None => {
-            let mut key = Key::default();
-            let _ = std::io::stdin().read(&mut key)?;
-            key
+    if let Some(target) = &arg.in_place {
+        let target = Path::new(target);
+        let replacement = ioutils::Replacement::new(target)?;
+        let reader = ioutils::open_input(Some(target))?;
+        let options = Options {
+            input: Some(target),
+            progress: reporter(Some(target)),
+            legacy: arg.allow_unauthenticated_legacy,
+            ..Default::default()
+        };
+        match with_key(reader, arg, Some(target))? {
+            Sealed::Fenc(reader, key) => {
+                open_stream(reader, key, Some(replacement.path()), options)?
+            }
+            Sealed::Age(reader) => {
+                open_age(reader, &arg.identity, Some(replacement.path()), options)?
+            }
+        }
+
+        return Ok(replacement.commit()?);
+    }
+
+    let input = filearg.input_file.as_deref().map(Path::new);
+    let output = filearg.output_file.as_deref().map(Path::new);
+    ioutils::ensure_distinct(input, output)?;
+
+    // age files are told apart by their first line, see `age`
+    let age_input = match input {
+        Some(input) => is_age_file(input)?,
+        None => false,
+    };
+
+    if arg.offset.is_some() || arg.length.is_some() {
+        let input = input.expect("required by clap");
+        if age_input {
+            return Err(error::Error::Other(String::from(
+                "a range cannot be opened from an age file",
+            )));
         }
-        Some(filename) => {
-            let mut file = OpenOptions::new().read(true).open(filename)?;
-            if file.metadata()?.len() != (KEY_SIZE as u64) {
-                return Err(error::Error::Key);
+        let mut file = File::open(input).map_err(|err| error::Error::io(err, input))?;
+        let key = match &filearg.plugin {
+            None => filearg.read_key()?,
+            Some(plugin) => {
+                unwrap_key(&mut file, plugin)
+                    .map_err(|err| err.with_path(input))?
+                    .0
             }
+        };
+        let mut reader = SeekableOpenReader::new(file, key).map_err(|err| err.with_path(input))?;
+        if reader.header().archive {
+            return Err(error::Error::Other(String::from(
+                "a range cannot be opened from a sealed directory",
+            )));
+        }
+
+        reader.seek(SeekFrom::Start(arg.offset.unwrap_or(0)))?;
+        let mut writer = ioutils::create_output(output)?;
+        ioutils::copy(
+            &mut reader.take(arg.length.unwrap_or(u64::MAX)),
+            &mut writer,
+        )
+        .map_err(|err| error::Error::from(err).with_path(input))?;
+        writer.flush()?;
+
+        return Ok(());
+    }
+
+    // uncompressed regular files are opened through memory maps, unless their key is wrapped
+    if let (Some(input), Some(output)) = (input, output) {
+        let restore = arg.restore_metadata || arg.original_name;
+        let plain_key = filearg.plugin.is_none() && !age_input;
+        if !arg.no_mmap && !arg.list && !restore && plain_key && mmap::can_map(input, output) {
+            let mut file = File::open(input).map_err(|err| error::Error::io(err, input))?;
+            let header = Header::read(&mut file).map_err(|err| err.with_path(input))?;
+            if mmap::supports(&header) {
+                let key = filearg.read_key()?;
+                let mut progress = reporter(Some(input));
+                return mmap::open(input, output, key, |n| {
+                    if let Some(progress) = progress.as_mut() {
+                        progress(n)
+                    }
+                });
+            }
+        }
+    }
+
+    let reader = ioutils::open_input(input)?;
+    let options = Options {
+        input,
+        list: arg.list,
+        restore_metadata: arg.restore_metadata,
+        original_name: arg.original_name,
+        progress: reporter(input),
+        legacy: arg.allow_unauthenticated_legacy,
+    };
+    match with_key(reader, arg, input)? {
+        Sealed::Fenc(reader, key) => open_stream(reader, key, output, options),
+        Sealed::Age(reader) => open_age(reader, &arg.identity, output, options),
+    }
+}
+
+/// A sealed stream, and how to open it.
+enum Sealed {
+    Fenc(Box<dyn Read + Send>, Key),
+    /// the identities come from `--identity`, or the passphrase is asked for
+    Age(Box<dyn Read + Send>),
+}
+
+/// Tells an age file from a sealed one, and finds the key of the latter: the key of the file
+/// arguments, or the one their `--plugin` unwraps from the header.
+fn with_key(
+    reader: Box<dyn Read + Send>,
+    arg: &OpenArg,
+    input: Option<&Path>,
+) -> error::Result<Sealed> {
+    let filearg = &arg.file;
+    if let Some(plugin) = &filearg.plugin {
+        let (reader, key) = plugin_key(reader, plugin, input)?;
+        return Ok(Sealed::Fenc(reader, key));
+    }
 
-            let mut key = Key::default();
-            file.read_exact(&mut key)?;
-            key
+    // a key read from stdin comes first, unless stdin is an age file
+    let stdin_key = input.is_none()
+        && filearg.key.is_none()
+        && filearg.key_name.is_none()
+        && arg.identity.is_empty();
+    let mut reader = reader;
+    let prefix = if stdin_key {
+        let key = filearg.read_key()?;
+        if !age::is_age(&key) {
+            return Ok(Sealed::Fenc(reader, key));
         }
+        key.to_vec()
+    } else {
+        let mut prefix = vec![0_u8; age::MAGIC.len()];
+        let n = ioutils::read_full(&mut reader, &mut prefix)?;
+        prefix.truncate(n);
+        prefix
     };
 
-    let mut iv = Block::default();
-    io.read_bytes(&mut iv.bytes_mut()[0..IV_SIZE])?;
+    let age_file = age::is_age(&prefix);
+    let reader = Box::new(io::Cursor::new(prefix).chain(reader));
+    if age_file {
+        Ok(Sealed::Age(reader))
+    } else {
+        Ok(Sealed::Fenc(reader, filearg.read_key()?))
+    }
+}
+
+/// `reader` and the key `plugin` unwraps from its header.
+fn plugin_key(
+    reader: Box<dyn Read + Send>,
+    plugin: &str,
+    input: Option<&Path>,
+) -> error::Result<(Box<dyn Read + Send>, Key)> {
+    let (key, reader) = unwrap_key(reader, plugin).map_err(|err| match input {
+        Some(input) => err.with_path(input),
+        None => err,
+    })?;
+    Ok((Box::new(reader), key))
+}
 
-    // let mut cipher = Cipher::new(key, iv, &arg.aad);
-    let mut cipher = Cipher::new(key, iv, &None);
+/// Whether the file at `path` is an age file.
+fn is_age_file(path: &Path) -> error::Result<bool> {
+    let mut file = File::open(path).map_err(|err| error::Error::io(err, path))?;
+    let mut prefix = [0_u8; age::MAGIC.len()];
+    let n =
+        ioutils::read_full(&mut file, &mut prefix).map_err(|err| error::Error::io(err, path))?;
+    Ok(age::is_age(&prefix[..n]))
+}
 
-    // read in the first block
-    let mut buf_proc = Block::default();
-    let mut bytes_read = io.read_block(&mut buf_proc)?;
-    if bytes_read != BLOCK_SIZE {
-        // invalid padding
-        return Err(error::Error::Encryption(String::from(
-            "invalid ciphertext file",
+/// Opens an age file into `output`, with the identities of `identity_files`, or the passphrase.
+fn open_age(
+    reader: Box<dyn Read + Send>,
+    identity_files: &[String],
+    output: Option<&Path>,
+    options: Options,
+) -> error::Result<()> {
+    let located = |err: error::Error| match options.input {
+        Some(input) => err.with_path(input),
+        None => err,
+    };
+    if options.list || options.restore_metadata || options.original_name {
+        return Err(error::Error::Other(String::from(
+            "an age file holds a single file, without metadata",
         )));
     }
 
-    let mut buf_read = Block::default();
-    bytes_read = io.read_block(&mut buf_read)?;
-    if bytes_read != BLOCK_SIZE {
-        // missing auth tag
-        return Err(error::Error::Encryption(String::from(
-            "invalid ciphertext file",
+    let mut reader = BufReader::new(reader);
+    let header = age::Header::read(&mut reader).map_err(located)?;
+    let mut identities = command::age::identities(identity_files)?;
+    if header.is_scrypt() {
+        identities.push(Identity::Scrypt(command::age::passphrase(false)?));
+    }
+    let mut opener = age::OpenReader::with_header(reader, &header, &identities).map_err(located)?;
+
+    let mut writer = ioutils::create_output(output)?;
+    ioutils::copy(&mut opener, &mut writer)
+        .and_then(|_| writer.flush())
+        .map_err(|err| located(err.into()))
+}
+
+/// What `open_stream` does besides opening.
+#[derive(Default)]
+struct Options<'a> {
+    /// the sealed file, if it has a path, errors in the sealed data point at it
+    input: Option<&'a Path>,
+    list: bool,
+    restore_metadata: bool,
+    original_name: bool,
+    progress: Option<Progress>,
+    /// whether headerless files are opened, see `OpenReader::with_unauthenticated_legacy`
+    legacy: bool,
+}
+
+/// What the archive thread leaves to do once the tag is verified.
+enum Unpacked {
+    Staged(archive::Staged),
+    Listing(Vec<u8>),
+}
+
+/// Opens a sealed stream into `output`, the target directory if it holds a sealed directory.
+fn open_stream(
+    reader: Box<dyn Read + Send>,
+    key: Key,
+    output: Option<&Path>,
+    options: Options,
+) -> error::Result<()> {
+    let located = |err: error::Error| match options.input {
+        Some(input) => err.with_path(input),
+        None => err,
+    };
+
+    let mut opener = match options.legacy {
+        false => OpenReader::new(reader, key),
+        true => OpenReader::with_unauthenticated_legacy(reader, key),
+    }
+    .map_err(located)?;
+    if opener.header().version == 0 {
+        let unauthenticated = located(error::Error::Unauthenticated(Default::default()));
+        eprintln!("warning: {}", unauthenticated);
+    }
+    if let Some(progress) = options.progress {
+        opener = opener.with_progress(progress);
+    }
+
+    let restore = options.restore_metadata || options.original_name;
+    let metadata = opener.metadata().cloned();
+    if restore && metadata.is_none() {
+        return Err(error::Error::Other(String::from(
+            "no metadata was sealed with this file, see `seal --metadata`",
+        )));
+    }
+
+    // the sealed name is only ever joined to the output directory, as a plain file name
+    let renamed;
+    let output = if options.original_name {
+        let name = metadata
+            .as_ref()
+            .and_then(Metadata::safe_name)
+            .ok_or_else(|| {
+                error::Error::Other(String::from(
+                    "the sealed name is missing, or not a plain file name",
+                ))
+            })?;
+        renamed = output.unwrap_or(Path::new(".")).join(name);
+        ioutils::ensure_distinct(options.input, Some(&renamed))?;
+        Some(renamed.as_path())
+    } else {
+        output
+    };
+    if restore && output.is_none() {
+        return Err(error::Error::Other(String::from(
+            "metadata can only be restored to an output file",
         )));
     }
 
-    let mut loop_buf = Block::default();
-    loop {
-        bytes_read = io.read_block(&mut loop_buf)?;
-        if bytes_read != BLOCK_SIZE {
-            break;
+    // a sealed directory is restored (or listed) on its own thread, reading from a pipe.
+    // the output file is the target directory, the current one by default
+    let (mut writer, unpacker): (Box<dyn Write + Send>, _) = if opener.header().archive {
+        if output.is_some_and(|dst| dst.is_file()) {
+            return Err(error::Error::Other(String::from(
+                "a sealed directory can only be restored into a directory",
+            )));
         }
 
-        cipher.decrypt_block_inplace(&mut buf_proc);
-        io.write_block(&buf_proc, BLOCK_SIZE)?;
+        let (pipe_reader, pipe_writer) = io::pipe()?;
+        let dst = output.unwrap_or(Path::new(".")).to_path_buf();
+        let list = options.list;
+        let unpacker = thread::spawn(move || {
+            if list {
+                let mut listing = Vec::new();
+                archive::list(pipe_reader, &mut listing).map(|()| Unpacked::Listing(listing))
+            } else {
+                archive::unpack(pipe_reader, &dst).map(Unpacked::Staged)
+            }
+        });
+        (Box::new(pipe_writer), Some(unpacker))
+    } else if options.list {
+        return Err(error::Error::Other(String::from(
+            "not a sealed directory, nothing to list",
+        )));
+    } else {
+        (ioutils::create_output(output)?, None)
+    };
 
-        buf_proc.bytes_mut().copy_from_slice(buf_read.bytes());
-        buf_read.bytes_mut().copy_from_slice(loop_buf.bytes());
-    }
+    let opened = ioutils::copy(&mut opener, &mut writer)
+        .and_then(|_| writer.flush())
+        .map_err(|err| located(err.into()));
 
-    // buf_proc contains the last ciphertext
-    // buf_read contains the tag
-    let buf_len = cipher.decrypt_block_inplace(&mut buf_proc);
-    io.write_block(&buf_proc, buf_len)?;
+    // closes the pipe, so the unpacker sees the end of the archive
+    drop(writer);
 
-    let decrypted_tag = buf_read.bytes();
-    for (i, byte) in cipher.tag().bytes().iter().enumerate() {
-        if *byte != decrypted_tag[i] {
-            return Err(error::Error::Encryption("invalid tag".to_string()));
+    let unpacked = match unpacker {
+        Some(unpacker) => unpacker.join().expect("archive thread panicked").map(Some),
+        None => Ok(None),
+    };
+
+    // a bad tag matters most, past that an unpacking error explains a broken pipe. the unpacked
+    // directory is dropped, and removed, with either, and the listing is never printed
+    let unpacked = match opened {
+        Err(err @ (error::Error::TagMismatch(_) | error::Error::Truncated(_))) => return Err(err),
+        opened => unpacked.and_then(|unpacked| opened.map(|()| unpacked))?,
+    };
+    match unpacked {
+        Some(Unpacked::Staged(staged)) => staged.commit()?,
+        Some(Unpacked::Listing(listing)) => {
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(&listing)?;
+            stdout.flush()?;
         }
+        None => {}
+    }
+
+    // only once the tag is verified
+    if let (true, Some(metadata), Some(output)) = (restore, metadata, output) {
+        metadata.restore(output)?;
     }
 
     Ok(())
@@ -0,0 +1,88 @@
+//! The `--progress` line of `seal` and `open`, printed to stderr.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::stream::Progress;
+
+/// How often the line is redrawn at most.
+const INTERVAL: Duration = Duration::from_millis(200);
+
+/// A progress callback printing the bytes processed, the throughput and the ETA on a single
+/// stderr line. The total is the size of `input`, when it is a regular file, and there is no ETA
+/// otherwise. The line is completed once the callback is dropped.
+pub fn reporter(input: Option<&Path>) -> Progress {
+    let total = input
+        .and_then(|path| fs::metadata(path).ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len());
+
+    let mut reporter = Reporter {
+        total,
+        start: Instant::now(),
+        drawn: None,
+        processed: 0,
+    };
+    Box::new(move |processed| reporter.update(processed))
+}
+
+struct Reporter {
+    total: Option<u64>,
+    start: Instant,
+    /// when the line was last drawn
+    drawn: Option<Instant>,
+    processed: u64,
+}
+
+impl Reporter {
+    fn update(&mut self, processed: u64) {
+        self.processed = processed;
+
+        let now = Instant::now();
+        if self.drawn.is_some_and(|drawn| now - drawn < INTERVAL) {
+            return;
+        }
+        self.drawn = Some(now);
+        self.draw();
+    }
+
+    fn draw(&self) {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let rate = self.processed as f64 / elapsed.max(f64::EPSILON);
+
+        let mut line = format!("{:.1} MiB", mib(self.processed));
+        if let Some(total) = self.total {
+            let percent = (self.processed * 100).checked_div(total).unwrap_or(100);
+            line += &format!(" / {:.1} MiB ({}%)", mib(total), percent.min(100));
+        }
+        line += &format!(", {:.1} MB/s", rate / 1e6);
+        if let Some(total) = self.total.filter(|_| rate > 0.0) {
+            let eta = total.saturating_sub(self.processed) as f64 / rate;
+            line += &format!(", ETA {}", clock(eta as u64));
+        }
+
+        // padded, to cover a longer line drawn before
+        let _ = write!(io::stderr(), "\r{:<64}", line);
+    }
+}
+
+impl Drop for Reporter {
+    fn drop(&mut self) {
+        if self.drawn.is_some() {
+            self.draw();
+            let _ = writeln!(io::stderr());
+        }
+    }
+}
+
+fn mib(bytes: u64) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0)
+}
+
+fn clock(secs: u64) -> String {
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
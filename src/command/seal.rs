@@ -1,10 +1,22 @@
 use crate::{
-    crypto::{self, block::Block, cipher, Key, BLOCK_SIZE, IV_SIZE, KEY_SIZE},
+    age::{self, Recipient},
+    archive,
+    command::{self, batch, plugin_arg, progress, FileArg},
+    crypto::{self, Key, BLOCK_SIZE},
     error,
-    ioutils::{FileArg, IO},
+    format::{self, Compression, Header},
+    ioutils::{self, IO},
+    metadata::Metadata,
+    mmap, plugin, seekable,
+    stream::{Progress, SealWriter},
+};
+use clap::{Parser, ValueEnum};
+use rand::RngCore;
+use std::{
+    io,
+    path::{Path, PathBuf},
+    thread,
 };
-use clap::Parser;
-use std::{fs::OpenOptions, io::Read};
 
 #[derive(Parser, Debug, Clone)]
 pub struct Encryptor {
@@ -25,59 +37,308 @@ pub struct Encryptor {
     key: Option<String>,
 }
 
-pub fn seal(filearg: &FileArg) -> error::Result<()> {
-    let mut io = IO::new(&filearg.input_file, &filearg.output_file)?;
+#[derive(Parser, Debug, Clone)]
+pub struct SealArg {
+    #[command(flatten)]
+    pub file: FileArg,
+
+    /// (optional) compress the plaintext before encrypting it
+    #[arg(short, long, value_enum)]
+    pub compress: Option<Compression>,
+
+    /// (optional) seal every file of the given paths in parallel, mirroring them into the output
+    /// directory with a suffix, `.enc` by default
+    #[arg(short, long, default_value_t = false, requires = "output_file")]
+    pub batch: bool,
+
+    /// files or directories to seal in batch mode
+    #[arg(requires = "batch")]
+    pub paths: Vec<String>,
+
+    /// (optional) suffix given to sealed files in batch mode
+    #[arg(long, requires = "batch")]
+    pub suffix: Option<String>,
+
+    /// (optional) seal in independently authenticated chunks, so that `open --offset` can
+    /// decrypt a range without reading the whole file
+    #[arg(long, default_value_t = false, conflicts_with = "compress")]
+    pub seekable: bool,
+
+    /// (optional) plaintext bytes per chunk of a seekable file, a multiple of 16
+    #[arg(long, requires = "seekable", default_value_t = seekable::DEFAULT_CHUNK_SIZE)]
+    pub chunk_size: u32,
+
+    /// (optional) always stream, instead of memory mapping the input and output files
+    #[arg(long, default_value_t = false)]
+    pub no_mmap: bool,
+
+    /// (optional) seal the name, mode, mtime and size of the input file along with it, see
+    /// `open --restore-metadata`
+    #[arg(long, default_value_t = false)]
+    pub metadata: bool,
+
+    /// (optional) print the bytes sealed, throughput and ETA to stderr
+    #[arg(long, default_value_t = false, conflicts_with = "batch")]
+    pub progress: bool,
+
+    /// (optional) replace the file with its sealed version, only once sealing succeeded
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["input_file", "output_file", "batch"])]
+    pub in_place: Option<String>,
+
+    /// (optional) format of the sealed file
+    #[arg(long, value_enum, default_value_t)]
+    pub format: Format,
+
+    /// (optional) age public key (`age1...`) to seal for, with `--format age`. repeatable
+    #[arg(short, long, conflicts_with_all = AGE_CONFLICTS)]
+    pub recipient: Vec<String>,
 
-    // reads in key, if `-k` flag is passed, reads from file
-    // otherwise reads (the first) 32 bytes from stdin
-    let key = match &filearg.key {
-        None => {
-            let mut buf = Key::default();
-            std::io::stdin().read_exact(&mut buf).map_err(|err| {
-                eprintln!("{}", err);
-                error::Error::Key
-            })?;
-
-            buf
+    /// (optional) seal under a passphrase, with `--format age`. it is asked for, or read from
+    /// `$FILE_ENCRYPTOR_AGE_PASSPHRASE`
+    #[arg(long, default_value_t = false, conflicts_with_all = AGE_CONFLICTS)]
+    pub passphrase: bool,
+}
+
+/// what age files have no room for
+const AGE_CONFLICTS: [&str; 9] = [
+    "key", "key_name", "plugin", "compress", "seekable", "metadata", "batch", "in_place",
+    "progress",
+];
+
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// the format of this crate, see `format`
+    #[default]
+    Fenc,
+    /// age v1, opened by `age` and `rage`, see `age`
+    Age,
+}
+
+pub fn seal(arg: &SealArg) -> error::Result<()> {
+    let filearg = &arg.file;
+    let age_recipients = arg.passphrase || !arg.recipient.is_empty();
+    match arg.format {
+        Format::Age => return seal_age(arg),
+        Format::Fenc if age_recipients => {
+            return Err(error::Error::Other(String::from(
+                "`--recipient` and `--passphrase` are for `--format age`",
+            )))
         }
-        Some(key) => {
-            let mut key_file = OpenOptions::new().read(true).open(key)?;
-            if key_file.metadata()?.len() != KEY_SIZE as u64 {
-                return Err(error::Error::Key);
-            }
-            let mut buf = Key::default();
-            key_file.read_exact(&mut buf)?;
+        Format::Fenc => {}
+    }
 
-            buf
+    // a fresh header (and iv) for every file sealed
+    let new_header = |key: &Key| {
+        let mut header = Header::new(arg.compress.unwrap_or_default());
+        header.chunk_size = arg.seekable.then_some(arg.chunk_size);
+        // a key of the keystore is named by its id, see `key list`
+        header.key_id = filearg.key_name.is_some().then(|| crypto::fingerprint(key));
+        header
+    };
+    let reporter = |input: Option<&Path>| arg.progress.then(|| progress::reporter(input));
+    let metadata_of = |input: Option<&Path>| -> error::Result<Option<Metadata>> {
+        match input {
+            _ if !arg.metadata => Ok(None),
+            None => Err(error::Error::Other(String::from(
+                "`--metadata` needs an input file",
+            ))),
+            Some(dir) if dir.is_dir() => Err(error::Error::Other(String::from(
+                "a sealed directory keeps its metadata in the archive, `--metadata` is for files",
+            ))),
+            Some(file) => Metadata::from_file(file)
+                .map(Some)
+                .map_err(|err| error::Error::io(err, file)),
         }
     };
 
-    // iv
-    let iv = Block::new_iv();
-    io.write_block(&iv, IV_SIZE)?;
-
-    // let mut cipher = cipher::Cipher::new(key, iv, &filearg.aad);
-    let mut cipher = cipher::Cipher::new(key, iv, &None);
-
-    // stream file/stdin
-    let mut eof = false;
-    loop {
-        let mut buf = Block::default();
-        let bytes_read = io.read_block(&mut buf)?;
-        if bytes_read != BLOCK_SIZE {
-            crypto::pkcs7::pad(&mut buf, bytes_read);
-            eof = true;
+    if !format::valid_chunk_size(arg.chunk_size) {
+        return Err(error::Error::Other(format!(
+            "invalid chunk size, expected a multiple of {} up to {}",
+            BLOCK_SIZE,
+            format::MAX_CHUNK_SIZE
+        )));
+    }
+
+    // with a plugin, every file is sealed under a key of its own, wrapped in its header
+    let plugin = filearg.plugin.as_deref().map(plugin_arg);
+    let key = match plugin {
+        None => Some(filearg.read_key()?),
+        Some((_, None)) => {
+            return Err(error::Error::Other(String::from(
+                "`--plugin` needs a recipient to seal, as NAME:RECIPIENT",
+            )))
         }
+        Some(_) => None,
+    };
+    let file_key = || -> error::Result<(Key, Vec<u8>)> {
+        match (key, plugin) {
+            (Some(key), _) => Ok((key, Vec::new())),
+            (None, Some((name, Some(recipient)))) => {
+                let mut key = Key::default();
+                rand::thread_rng().fill_bytes(&mut key);
+                let section = plugin::wrap(name, recipient, &key)?;
+                Ok((key, section.to_bytes()))
+            }
+            (None, _) => unreachable!("checked above"),
+        }
+    };
 
-        // cipher block
-        cipher.encrypt_block_inplace(&mut buf, bytes_read);
-        io.write_block(&buf, BLOCK_SIZE)?;
+    if arg.batch {
+        let paths: Vec<String> = filearg
+            .input_file
+            .iter()
+            .chain(&arg.paths)
+            .cloned()
+            .collect();
+        let out_dir = Path::new(filearg.output_file.as_deref().unwrap_or_default());
+        let suffix = batch::suffix(arg.suffix.as_deref())?;
+        let jobs = batch::jobs(&paths, out_dir, |name| batch::sealed_name(name, suffix))?;
 
-        if eof {
-            break;
+        return batch::run(&jobs, "sealed", |job| {
+            let mut io = IO::from_parts(
+                ioutils::open_input(Some(&job.input))?,
+                ioutils::create_output(Some(&job.output))?,
+            );
+            let metadata = metadata_of(Some(&job.input))?;
+            let (key, wrap) = file_key()?;
+            let header = new_header(&key);
+            encrypt(&mut io, key, &header, &wrap, None, metadata.as_ref())
+        });
+    }
+
+    if let Some(target) = &arg.in_place {
+        let target = Path::new(target);
+        let replacement = ioutils::Replacement::new(target)?;
+        let mut io = IO::from_parts(
+            ioutils::open_input(Some(target))?,
+            ioutils::create_output(Some(replacement.path()))?,
+        );
+        let metadata = metadata_of(Some(target))?;
+        let (key, wrap) = file_key()?;
+        encrypt(
+            &mut io,
+            key,
+            &new_header(&key),
+            &wrap,
+            reporter(Some(target)),
+            metadata.as_ref(),
+        )?;
+        drop(io);
+
+        return Ok(replacement.commit()?);
+    }
+
+    let (key, wrap) = file_key()?;
+
+    // regular files are sealed through memory maps, when the output size is known up front
+    let input = filearg.input_file.as_deref().map(Path::new);
+    let output = filearg.output_file.as_deref().map(Path::new);
+    if let (Some(input), Some(output)) = (input, output) {
+        let header = new_header(&key);
+        let mappable = wrap.is_empty() && mmap::supports(&header) && mmap::can_map(input, output);
+        if !arg.no_mmap && !arg.metadata && mappable {
+            ioutils::ensure_distinct(Some(input), Some(output))?;
+            let mut progress = reporter(Some(input));
+            return mmap::seal(input, output, key, &header, |n| {
+                if let Some(progress) = progress.as_mut() {
+                    progress(n)
+                }
+            });
+        }
+    }
+
+    let metadata = metadata_of(input)?;
+
+    // a directory is sealed as a tar stream, packed on its own thread into a pipe
+    let dir = filearg
+        .input_file
+        .as_ref()
+        .map(PathBuf::from)
+        .filter(|path| path.is_dir());
+    let (mut io, packer) = match dir {
+        None => (IO::new(&filearg.input_file, &filearg.output_file)?, None),
+        Some(dir) => {
+            let writer = ioutils::create_output(filearg.output_file.as_deref().map(Path::new))?;
+            let (pipe_reader, pipe_writer) = io::pipe()?;
+            let packer = thread::spawn(move || archive::pack(&dir, pipe_writer).map(drop));
+            (IO::from_parts(Box::new(pipe_reader), writer), Some(packer))
         }
+    };
+
+    let mut header = new_header(&key);
+    header.archive = packer.is_some();
+    let sealed = encrypt(
+        &mut io,
+        key,
+        &header,
+        &wrap,
+        reporter(input),
+        metadata.as_ref(),
+    );
+
+    // closes the pipe, in case sealing stopped before the packer did
+    drop(io);
+
+    // a failed seal breaks the pipe, so its error is the one worth reporting
+    let packed = match packer {
+        Some(packer) => packer.join().expect("archive thread panicked"),
+        None => Ok(()),
+    };
+
+    sealed.and(packed)
+}
+
+/// Seals a single file (or stdin) as an age file, for the recipients of `arg`.
+fn seal_age(arg: &SealArg) -> error::Result<()> {
+    let recipients = if arg.passphrase {
+        vec![Recipient::passphrase(&command::age::passphrase(true)?)]
+    } else {
+        arg.recipient
+            .iter()
+            .map(|recipient| Recipient::parse(recipient))
+            .collect::<error::Result<_>>()?
+    };
+    if recipients.is_empty() {
+        return Err(error::Error::Other(String::from(
+            "`--format age` needs `--recipient` or `--passphrase`",
+        )));
+    }
+
+    let filearg = &arg.file;
+    if filearg
+        .input_file
+        .as_deref()
+        .is_some_and(|input| Path::new(input).is_dir())
+    {
+        return Err(error::Error::Other(String::from(
+            "an age file holds a single file, not a directory",
+        )));
+    }
+
+    let mut io = IO::new(&filearg.input_file, &filearg.output_file)?;
+    let (reader, writer) = io.split();
+    let mut sealer = age::SealWriter::new(writer, &recipients)?;
+    ioutils::copy(reader, &mut sealer)?;
+    sealer.finish()?;
+
+    Ok(())
+}
+
+fn encrypt(
+    io: &mut IO,
+    key: Key,
+    header: &Header,
+    wrap: &[u8],
+    progress: Option<Progress>,
+    metadata: Option<&Metadata>,
+) -> error::Result<()> {
+    let (reader, writer) = io.split();
+    let mut sealer = SealWriter::with_wrap(writer, key, *header, metadata, wrap)?;
+    if let Some(progress) = progress {
+        sealer = sealer.with_progress(progress);
     }
+    ioutils::copy(reader, &mut sealer)?;
+    sealer.finish()?;
 
-    io.write_block(cipher.tag(), BLOCK_SIZE)?;
     Ok(())
 }
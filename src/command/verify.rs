@@ -0,0 +1,63 @@
+use std::{io, path::Path};
+
+use clap::Parser;
+
+use crate::{
+    command::{key, unwrap_key},
+    error, ioutils,
+    stream::OpenReader,
+};
+
+#[derive(Parser, Debug, Clone)]
+pub struct VerifyArg {
+    /// (optional) sealed file, read from stdin by default
+    #[arg(short, long)]
+    pub input_file: Option<String>,
+
+    /// (optional) key file, read (the first) 32 byte from stdin by default
+    #[arg(short, long)]
+    pub key: Option<String>,
+
+    /// (optional) name of a key of the keystore to use instead of a key file, see `key`
+    #[arg(long, conflicts_with = "key")]
+    pub key_name: Option<String>,
+
+    /// (optional) keystore directory, `$XDG_DATA_HOME/file-encryptor/keys` by default
+    #[arg(long)]
+    pub keystore: Option<String>,
+
+    /// (optional) have the program `file-encryptor-plugin-NAME` unwrap the key of the file, with
+    /// IDENTITY if given, see `plugin`
+    #[arg(long, value_name = "NAME[:IDENTITY]", conflicts_with_all = ["key", "key_name"])]
+    pub plugin: Option<String>,
+}
+
+/// Opens the whole file, every tag included, and throws the plaintext away. Nothing is printed
+/// on success, failures exit with the code of their `error::Error` variant: 3 for a wrong key, 4
+/// for a corrupted file, 5 for a truncated one... Files sealed before key checks were added can
+/// only fail their tag with a wrong key. Headerless (version 0) files have no tag worth checking,
+/// they always fail with 9, see `error::Error::Unauthenticated`.
+pub fn verify(arg: &VerifyArg) -> error::Result<()> {
+    let input = arg.input_file.as_deref().map(Path::new);
+    let reader = ioutils::open_input(input)?;
+    let located = |err: error::Error| match input {
+        Some(input) => err.with_path(input),
+        None => err,
+    };
+
+    let (key, reader): (_, Box<dyn io::Read + Send>) = match &arg.plugin {
+        None => (
+            key::read_key(&arg.key, arg.key_name.as_deref(), arg.keystore.as_deref())?,
+            reader,
+        ),
+        Some(plugin) => {
+            let (key, reader) = unwrap_key(reader, plugin).map_err(located)?;
+            (key, Box::new(reader))
+        }
+    };
+
+    let verified = OpenReader::new(reader, key)
+        .and_then(|mut opener| Ok(ioutils::copy(&mut opener, &mut io::sink())?));
+
+    verified.map(drop).map_err(located)
+}
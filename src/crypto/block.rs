@@ -37,6 +37,7 @@ impl From<[u8; IV_SIZE]> for Block {
 }
 
 impl Block {
+    #[cfg(feature = "std")]
     pub fn new_iv() -> Self {
         use rand::RngCore;
 
@@ -99,7 +100,7 @@ impl Block {
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use super::*;
     use rand::Rng;
@@ -1,17 +1,47 @@
-use crate::crypto::{
-    block::{Block, REDUCTION_POLYNOMIAL},
-    Key, BLOCK_SIZE,
+//! AES-256-GCM. The sealed formats pad the plaintext (pkcs7) to a multiple of the block size and
+//! encrypt the padded blocks, messages of any length go through `encrypt_tail_inplace`.
+//!
+//! Format versions 0 and 1 used a construction of their own, close to GCM but not quite:
+//! counters start one block earlier (the tag mask is `E(iv || 0)`), the hash multiplies modulo
+//! another, reducible, polynomial, and the length block holds byte counts, the payload first.
+//! `Cipher::legacy` keeps it, only to open their files.
+//!
+//! The GCM hash comes from the `ghash` crate, constant time. The legacy one looks up tables
+//! indexed by the running hash, whose timing may leak it through the cache: it only ever hashes
+//! files that are opened, never sealed, see `Tag::table`.
+
+use alloc::{boxed::Box, string::String};
+
+use crate::{
+    crypto::{
+        block::{Block, REDUCTION_POLYNOMIAL},
+        Key, BLOCK_SIZE, IV_SIZE,
+    },
+    error::{self, Location},
 };
 use aes::{
     cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
     Aes256,
 };
+use ghash::{universal_hash::UniversalHash, GHash};
 
 use super::pkcs7;
 
+/// Counter blocks encrypted at once, so that the aes implementation can pipeline them.
+const KEYSTREAM_BATCH: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    /// NIST SP 800-38D
+    Gcm,
+    /// format versions 0 and 1, see the module doc
+    Legacy,
+}
+
 #[derive(Clone)]
 #[allow(dead_code)]
 pub struct Cipher {
+    mode: Mode,
     payload_len: usize,
     aad_len: usize,
     aes: Aes256,
@@ -21,24 +51,39 @@ pub struct Cipher {
 }
 
 impl Cipher {
-    pub fn new(key: Key, iv: Block, aad: &Option<String>) -> Self {
+    /// AES-256-GCM with a 96 bit `iv`, the last four bytes of the block are the counter and must
+    /// be zero.
+    pub fn new(key: Key, iv: Block, aad: &[u8]) -> Self {
+        Self::with_mode(Mode::Gcm, key, iv, aad)
+    }
+
+    /// The cipher of format versions 0 and 1.
+    pub fn legacy(key: Key, iv: Block, aad: &[u8]) -> Self {
+        Self::with_mode(Mode::Legacy, key, iv, aad)
+    }
+
+    fn with_mode(mode: Mode, key: Key, iv: Block, aad: &[u8]) -> Self {
         let aes = Aes256::new(&key.into());
 
+        // the tag is masked with the first counter block, the payload takes the next ones
+        let mut iv = iv;
+        if mode == Mode::Gcm {
+            iv.inc_counter();
+        }
         let mut counter0 = iv;
         aes.encrypt_block((&mut counter0).into());
 
         let mut h = Block::default();
         aes.encrypt_block((&mut h).into());
 
-        let mut tag = Tag::new(counter0, h);
-        if let Some(aad) = aad {
-            tag.with_aad(aad.as_bytes());
-        }
+        let mut tag = Tag::new(counter0, h, mode);
+        tag.with_aad(aad);
 
-        let aad_len = if let Some(aad) = aad { aad.len() } else { 0 };
+        let aad_len = aad.len();
         let payload_len = 0;
 
         Self {
+            mode,
             payload_len,
             aad_len,
             aes,
@@ -48,8 +93,14 @@ impl Cipher {
         }
     }
 
-    pub fn encrypt_block_inplace(&mut self, block: &mut Block, size: usize) {
-        self.payload_len += size;
+    /// Encrypts a block holding `size` bytes of plaintext, padded if it is the last one.
+    pub fn encrypt_block_inplace(&mut self, block: &mut Block, size: usize) -> error::Result<()> {
+        self.reserve(1, false)?;
+        // the legacy tag only counts the plaintext, GCM every byte it encrypts
+        self.payload_len += match self.mode {
+            Mode::Gcm => BLOCK_SIZE,
+            Mode::Legacy => size,
+        };
         self.iv.inc_counter();
 
         let mut ctr = self.iv;
@@ -58,31 +109,163 @@ impl Cipher {
         block.xor(&ctr);
 
         self.tag.compute(block);
+        Ok(())
     }
 
-    pub fn decrypt_block_inplace(&mut self, block: &mut Block) -> usize {
+    pub fn decrypt_block_inplace(&mut self, block: &mut Block) -> error::Result<()> {
+        self.reserve(1, true)?;
         self.tag.compute(block);
         self.iv.inc_counter();
         let mut ctr = self.iv;
         self.aes.encrypt_block((&mut ctr).into());
         block.xor(&ctr);
 
-        let size = BLOCK_SIZE - pkcs7::unpad(block);
-        self.payload_len += size;
+        self.payload_len += BLOCK_SIZE;
+        Ok(())
+    }
+
+    /// Encrypts whole blocks in place, `data` holds a multiple of `BLOCK_SIZE` bytes.
+    pub fn encrypt_blocks_inplace(&mut self, data: &mut [u8]) -> error::Result<()> {
+        self.reserve(data.len() / BLOCK_SIZE, false)?;
+        self.apply_keystream(data);
+        self.compute_tag(data);
+        self.payload_len += data.len();
+        Ok(())
+    }
+
+    /// Decrypts whole blocks in place, `data` holds a multiple of `BLOCK_SIZE` bytes. None of
+    /// them is unpadded, see `decrypt_last_block_inplace`.
+    pub fn decrypt_blocks_inplace(&mut self, data: &mut [u8]) -> error::Result<()> {
+        self.reserve(data.len() / BLOCK_SIZE, true)?;
+        self.compute_tag(data);
+        self.apply_keystream(data);
+        self.payload_len += data.len();
+        Ok(())
+    }
+
+    /// decrypts the last (padded) block of the stream, returns the number of plaintext bytes
+    /// left after the padding is removed
+    pub fn decrypt_last_block_inplace(&mut self, block: &mut Block) -> error::Result<usize> {
+        self.decrypt_block_inplace(block)?;
+
+        let padding = pkcs7::unpad(block);
+        if self.mode == Mode::Legacy {
+            self.payload_len -= padding;
+        }
+
+        Ok(BLOCK_SIZE - padding)
+    }
+
+    /// Encrypts the end of a message that is not a whole block, the keystream is cut short and
+    /// the ciphertext hashed zero padded. Only the tag may follow. GCM only, the sealed formats
+    /// pad their last block instead.
+    pub fn encrypt_tail_inplace(&mut self, tail: &mut [u8]) -> error::Result<()> {
+        self.reserve(usize::from(!tail.is_empty()), false)?;
+        self.apply_tail_keystream(tail);
+        self.compute_tail_tag(tail);
+        Ok(())
+    }
 
-        size
+    /// Decrypts the end of a message, see `encrypt_tail_inplace`.
+    pub fn decrypt_tail_inplace(&mut self, tail: &mut [u8]) -> error::Result<()> {
+        self.reserve(usize::from(!tail.is_empty()), true)?;
+        self.compute_tail_tag(tail);
+        self.apply_tail_keystream(tail);
+        Ok(())
+    }
+
+    /// Fails unless `blocks` more counter blocks are left. The 32 bit counter must not wrap back
+    /// to the block that masks the tag, the keystream would repeat: a GCM message holds at most
+    /// 2^32 - 2 blocks, about 64 GiB. Nothing seals more, so a longer message to open was not
+    /// what was sealed.
+    fn reserve(&self, blocks: usize, opening: bool) -> error::Result<()> {
+        let counter = u32::from_be_bytes(
+            self.iv.bytes()[IV_SIZE..]
+                .try_into()
+                .expect("a four byte counter"),
+        );
+        if blocks as u64 <= u64::from(u32::MAX - counter) {
+            return Ok(());
+        }
+
+        Err(if opening {
+            error::Error::TagMismatch(Location::default())
+        } else {
+            error::Error::Other(String::from(
+                "too much data for one GCM message (64 GiB), seal it as a seekable stream",
+            ))
+        })
     }
 
     pub fn tag(&mut self) -> &Block {
+        let block = self.len_block();
+        self.tag.finish(&block)
+    }
+
+    /// The tag written by the headerless (version 0) format. It cancels out the running hash, so
+    /// it only covers the lengths, and is kept solely to open files sealed before the header.
+    pub fn legacy_tag(&mut self) -> &Block {
+        debug_assert_eq!(self.mode, Mode::Legacy);
+        let mut block = self.len_block();
+        block.xor(&self.tag.hash());
+        self.tag.finish(&block)
+    }
+
+    fn len_block(&self) -> Block {
+        let (first, second) = match self.mode {
+            Mode::Gcm => (self.aad_len as u64 * 8, self.payload_len as u64 * 8),
+            Mode::Legacy => (self.payload_len as u64, self.aad_len as u64),
+        };
+
         let mut block = Block::default();
-        block.bytes_mut()[..8].copy_from_slice(&self.payload_len.to_be_bytes());
-        block.bytes_mut()[8..].copy_from_slice(&self.aad_len.to_be_bytes());
-        block.xor(self.tag.block());
-        self.tag.compute(&block);
+        block.bytes_mut()[..8].copy_from_slice(&first.to_be_bytes());
+        block.bytes_mut()[8..].copy_from_slice(&second.to_be_bytes());
+        block
+    }
 
-        self.tag.tag_buf.xor(&self.tag.counter_0);
+    /// Xors `data` with the next counter blocks, encrypted a batch at a time.
+    fn apply_keystream(&mut self, data: &mut [u8]) {
+        assert!(
+            data.len().is_multiple_of(BLOCK_SIZE),
+            "expected whole blocks"
+        );
+
+        let mut counters = [GenericArray::default(); KEYSTREAM_BATCH];
+        for batch in data.chunks_mut(KEYSTREAM_BATCH * BLOCK_SIZE) {
+            let counters = &mut counters[..batch.len() / BLOCK_SIZE];
+            for counter in counters.iter_mut() {
+                self.iv.inc_counter();
+                counter.copy_from_slice(self.iv.bytes());
+            }
 
-        &self.tag.tag_buf
+            self.aes.encrypt_blocks(counters);
+            for (byte, key) in batch.iter_mut().zip(counters.iter().flatten()) {
+                *byte ^= key;
+            }
+        }
+    }
+
+    fn apply_tail_keystream(&mut self, tail: &mut [u8]) {
+        assert!(self.mode == Mode::Gcm && tail.len() < BLOCK_SIZE);
+        if tail.is_empty() {
+            return;
+        }
+
+        self.iv.inc_counter();
+        let mut ctr = self.iv;
+        self.encrypt_block(&mut ctr);
+        for (byte, key) in tail.iter_mut().zip(ctr.bytes()) {
+            *byte ^= key;
+        }
+    }
+
+    fn compute_tag(&mut self, data: &[u8]) {
+        self.tag.update(data);
+    }
+
+    fn compute_tail_tag(&mut self, tail: &[u8]) {
+        self.tag.update(tail);
+        self.payload_len += tail.len();
     }
 
     fn encrypt_block(&self, block: &mut Block) {
@@ -94,54 +277,133 @@ impl Cipher {
 #[derive(Clone, Debug)]
 pub struct Tag {
     counter_0: Block,
-    h: Block,
+    hash: Hash,
     tag_buf: Block,
 }
 
+#[derive(Clone, Debug)]
+enum Hash {
+    Gcm(GHash),
+    /// products with h, see `Tag::table`, the running hash is `Tag::tag_buf`
+    Legacy(Box<[[u128; 16]; 32]>),
+}
+
 impl Tag {
-    fn new(counter_0: Block, h: Block) -> Self {
+    fn new(counter_0: Block, h: Block, mode: Mode) -> Self {
+        let hash = match mode {
+            Mode::Gcm => Hash::Gcm(GHash::new(&(*h.bytes()).into())),
+            Mode::Legacy => Hash::Legacy(Self::table(&h)),
+        };
+
         Self {
             counter_0,
-            h,
+            hash,
             tag_buf: Block::default(),
         }
     }
 
-    fn with_aad(&mut self, mut auth_data: &[u8]) {
-        let mut eof = false;
-        let mut block = Block::default();
-        while !eof {
-            let aad_block = if auth_data.len() != BLOCK_SIZE {
-                block = Block::default();
-                block.bytes_mut().copy_from_slice(auth_data.as_ref());
-                eof = true;
-                block
-            } else {
-                block
-                    .bytes_mut()
-                    .copy_from_slice(auth_data[..BLOCK_SIZE].as_ref());
-                block
-            };
-
-            self.compute(&aad_block);
-
-            if eof {
-                break;
+    /// The multiplication by h is linear, so a product is the xor of the products of each bit of
+    /// the other operand, looked up four bits at a time: `table[i][n]` is the product of h and
+    /// the nibble `n` at bits `4 * i..4 * i + 4` (of the block read as a big endian integer).
+    ///
+    /// The lookups depend on the hashed data and on h, so their timing is not constant. Only
+    /// files of the legacy formats are hashed this way, when they are opened: an attacker able to
+    /// time the cache of the machine opening one learns about h, and could forge the tag of
+    /// another legacy file for the same key and iv. Re-sealing them in the current format avoids
+    /// it.
+    fn table(h: &Block) -> Box<[[u128; 16]; 32]> {
+        let reduction = u128::from_be_bytes(*REDUCTION_POLYNOMIAL.bytes());
+
+        // `bits[i]` is the product of h and bit `i` alone, each one is h times x once more than
+        // the previous, a left shift
+        let mut bits = [0_u128; 128];
+        let mut v = u128::from_be_bytes(*h.bytes());
+        for bit in bits.iter_mut() {
+            *bit = v;
+            let msb_set = v >> 127 == 1;
+            v <<= 1;
+            if msb_set {
+                v ^= reduction;
             }
+        }
 
-            auth_data = &auth_data[BLOCK_SIZE..];
+        let mut table = Box::new([[0_u128; 16]; 32]);
+        for (i, row) in table.iter_mut().enumerate() {
+            for (n, product) in row.iter_mut().enumerate() {
+                *product = (0..4)
+                    .filter(|b| n & (1 << b) != 0)
+                    .fold(0, |acc, b| acc ^ bits[4 * i + b]);
+            }
         }
+
+        table
     }
 
-    fn block(&self) -> &Block {
-        &self.tag_buf
+    fn with_aad(&mut self, auth_data: &[u8]) {
+        self.update(auth_data);
+    }
+
+    /// Hashes `data`, its last block zero padded.
+    fn update(&mut self, data: &[u8]) {
+        match &mut self.hash {
+            Hash::Gcm(ghash) => ghash.update_padded(data),
+            Hash::Legacy(_) => {
+                for chunk in data.chunks(BLOCK_SIZE) {
+                    let mut block = Block::default();
+                    block.bytes_mut()[..chunk.len()].copy_from_slice(chunk);
+                    self.compute(&block);
+                }
+            }
+        }
     }
 
     fn compute(&mut self, block: &Block) {
+        let table = match &mut self.hash {
+            Hash::Gcm(ghash) => return ghash.update(&[(*block.bytes()).into()]),
+            Hash::Legacy(table) => table,
+        };
+
         self.tag_buf.xor(block);
-        self.tag_buf = Self::galois_multiply(&self.tag_buf, &self.h);
+        let x = u128::from_be_bytes(*self.tag_buf.bytes());
+        let product = table
+            .iter()
+            .enumerate()
+            .fold(0, |acc, (i, row)| acc ^ row[(x >> (4 * i)) as usize & 0xf]);
+        *self.tag_buf.bytes_mut() = product.to_be_bytes();
+    }
+
+    /// The hash of the blocks so far.
+    fn hash(&self) -> Block {
+        match &self.hash {
+            Hash::Gcm(ghash) => {
+                let mut block = Block::default();
+                block.bytes_mut().copy_from_slice(&ghash.clone().finalize());
+                block
+            }
+            Hash::Legacy(_) => self.tag_buf,
+        }
+    }
+
+    /// Hashes the lengths block and masks the hash, which gives the tag.
+    fn finish(&mut self, lengths: &Block) -> &Block {
+        self.compute(lengths);
+        self.tag_buf = self.hash();
+        self.tag_buf.xor(&self.counter_0);
+        &self.tag_buf
     }
+}
 
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::{
+        crypto::{BLOCK_SIZE, IV_SIZE},
+        hex,
+    };
+    use proptest::prelude::*;
+    use rand::RngCore;
+
+    /// The bit at a time multiplication the legacy tables replace.
     fn galois_multiply(x: &Block, y: &Block) -> Block {
         let mut z = Block::default();
         let mut v = *x;
@@ -160,12 +422,318 @@ impl Tag {
 
         z
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::crypto::{BLOCK_SIZE, IV_SIZE};
+    /// The multiplication of GF(2^128) as NIST SP 800-38D (algorithm 1) spells it out.
+    fn gcm_multiply(x: &Block, y: &Block) -> Block {
+        let (x, y) = (
+            u128::from_be_bytes(*x.bytes()),
+            u128::from_be_bytes(*y.bytes()),
+        );
+        let reduction = u128::from_be_bytes(*REDUCTION_POLYNOMIAL.bytes());
+
+        let mut z = 0;
+        let mut v = y;
+        for i in 0..128 {
+            if x >> (127 - i) & 1 == 1 {
+                z ^= v;
+            }
+            v = if v & 1 == 1 {
+                (v >> 1) ^ reduction
+            } else {
+                v >> 1
+            };
+        }
+
+        Block::from(z.to_be_bytes())
+    }
+
+    fn random_block() -> Block {
+        let mut block = Block::default();
+        rand::thread_rng().fill_bytes(block.bytes_mut());
+        block
+    }
+
+    /// Seals a message of any length: whole blocks, then the tail.
+    fn seal(mut cipher: Cipher, plaintext: &[u8]) -> (Vec<u8>, Block) {
+        let mut sealed = plaintext.to_vec();
+        let (blocks, tail) = sealed.split_at_mut(plaintext.len() / BLOCK_SIZE * BLOCK_SIZE);
+        cipher.encrypt_blocks_inplace(blocks).unwrap();
+        cipher.encrypt_tail_inplace(tail).unwrap();
+        let tag = *cipher.tag();
+        (sealed, tag)
+    }
+
+    fn open(mut cipher: Cipher, sealed: &[u8]) -> (Vec<u8>, Block) {
+        let mut opened = sealed.to_vec();
+        let (blocks, tail) = opened.split_at_mut(sealed.len() / BLOCK_SIZE * BLOCK_SIZE);
+        cipher.decrypt_blocks_inplace(blocks).unwrap();
+        cipher.decrypt_tail_inplace(tail).unwrap();
+        let tag = *cipher.tag();
+        (opened, tag)
+    }
+
+    #[test]
+    fn test_tag_table_matches_multiply() {
+        for _ in 0..100 {
+            let (h, x) = (random_block(), random_block());
+
+            let mut tag = Tag::new(Block::default(), h, Mode::Gcm);
+            tag.compute(&x);
+            assert_eq!(tag.hash().bytes(), gcm_multiply(&x, &h).bytes());
+
+            let mut tag = Tag::new(Block::default(), h, Mode::Legacy);
+            tag.compute(&x);
+            assert_eq!(tag.hash().bytes(), galois_multiply(&x, &h).bytes());
+        }
+    }
+
+    /// The AES-256 test cases (13 to 16) of the GCM specification, McGrew and Viega, also in
+    /// NIST's validation suite. Cases 17 and 18 take IVs other than 96 bits, which the sealed
+    /// formats never use.
+    #[test]
+    fn test_cipher_nist_vectors() {
+        struct Case {
+            key: &'static str,
+            iv: &'static str,
+            aad: &'static str,
+            plaintext: &'static str,
+            ciphertext: &'static str,
+            tag: &'static str,
+        }
+
+        const ZERO_KEY: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+        const ZERO_IV: &str = "000000000000000000000000";
+        const ZERO_BLOCK: &str = "00000000000000000000000000000000";
+
+        let key = "feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308";
+        let plaintext = "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72\
+                         1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b391aafd255";
+        let ciphertext = "522dc1f099567d07f47f37a32a84427d643a8cdcbfe5c0c97598a2bd2555d1aa\
+                          8cb08e48590dbb3da7b08b1056828838c5f61e6393ba7a0abcc9f662898015ad";
+        let cases = [
+            Case {
+                key: ZERO_KEY,
+                iv: ZERO_IV,
+                aad: "",
+                plaintext: "",
+                ciphertext: "",
+                tag: "530f8afbc74536b9a963b4f1c4cb738b",
+            },
+            Case {
+                key: ZERO_KEY,
+                iv: ZERO_IV,
+                aad: "",
+                plaintext: ZERO_BLOCK,
+                ciphertext: "cea7403d4d606b6e074ec5d3baf39d18",
+                tag: "d0d1c8a799996bf0265b98b5d48ab919",
+            },
+            Case {
+                key,
+                iv: "cafebabefacedbaddecaf888",
+                aad: "",
+                plaintext,
+                ciphertext,
+                tag: "b094dac5d93471bdec1a502270e3cc6c",
+            },
+            // aad and plaintext that do not fill their last block
+            Case {
+                key,
+                iv: "cafebabefacedbaddecaf888",
+                aad: "feedfacedeadbeeffeedfacedeadbeefabaddad2",
+                plaintext: &plaintext[..120],
+                ciphertext: &ciphertext[..120],
+                tag: "76fc6ece0f4e1768cddf8853bb2d551b",
+            },
+        ];
+
+        for case in cases {
+            let key: Key = hex::decode(case.key).unwrap().try_into().unwrap();
+            let iv: [u8; IV_SIZE] = hex::decode(case.iv).unwrap().try_into().unwrap();
+            let aad = hex::decode(case.aad).unwrap();
+
+            let cipher = Cipher::new(key, Block::from(iv), &aad);
+            let (sealed, tag) = seal(cipher.clone(), &hex::decode(case.plaintext).unwrap());
+            assert_eq!(sealed, hex::decode(case.ciphertext).unwrap());
+            assert_eq!(tag.bytes().as_slice(), hex::decode(case.tag).unwrap());
+
+            let (opened, tag) = open(cipher, &sealed);
+            assert_eq!(opened, hex::decode(case.plaintext).unwrap());
+            assert_eq!(tag.bytes().as_slice(), hex::decode(case.tag).unwrap());
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_cipher_matches_aes_gcm_crate(
+            key in any::<Key>(),
+            iv in any::<[u8; IV_SIZE]>(),
+            aad in prop::collection::vec(any::<u8>(), 0..70),
+            plaintext in prop::collection::vec(any::<u8>(), 0..600),
+        ) {
+            use aes_gcm::{
+                aead::{Aead, Payload},
+                Aes256Gcm, KeyInit, Nonce,
+            };
+
+            let (mut sealed, tag) = seal(Cipher::new(key, Block::from(iv), &aad), &plaintext);
+            sealed.extend_from_slice(tag.bytes());
+
+            let expected = Aes256Gcm::new(&key.into())
+                .encrypt(
+                    Nonce::from_slice(&iv),
+                    Payload {
+                        msg: &plaintext,
+                        aad: &aad,
+                    },
+                )
+                .unwrap();
+            prop_assert_eq!(sealed, expected);
+        }
+
+        /// Any number of blocks, sealed partly in a batch and partly one at a time, opens to
+        /// itself under the same tag, in both modes. A flipped bit changes the GCM tag; the legacy
+        /// hash reduces modulo a reducible polynomial, it may keep its tag, see
+        /// `test_cipher_legacy_vector`.
+        #[test]
+        fn test_cipher_roundtrip_and_tamper(
+            legacy in any::<bool>(),
+            key in any::<Key>(),
+            iv in any::<[u8; IV_SIZE]>(),
+            aad in prop::collection::vec(any::<u8>(), 0..70),
+            blocks in prop::collection::vec(any::<[u8; BLOCK_SIZE]>(), 0..64),
+            split in any::<prop::sample::Index>(),
+            flip in any::<prop::sample::Index>(),
+            bit in 0..8_u8,
+        ) {
+            let cipher = if legacy { Cipher::legacy } else { Cipher::new };
+            let cipher = cipher(key, Block::from(iv), &aad);
+            let plaintext = blocks.concat();
+
+            let mut sealer = cipher.clone();
+            let mut sealed = plaintext.clone();
+            let (batch, rest) = sealed.split_at_mut(split.index(blocks.len() + 1) * BLOCK_SIZE);
+            sealer.encrypt_blocks_inplace(batch).unwrap();
+            for bytes in rest.chunks_exact_mut(BLOCK_SIZE) {
+                let mut block = Block::default();
+                block.bytes_mut().copy_from_slice(bytes);
+                sealer.encrypt_block_inplace(&mut block, BLOCK_SIZE).unwrap();
+                bytes.copy_from_slice(block.bytes());
+            }
+            let tag = *sealer.tag();
+
+            let mut opener = cipher.clone();
+            let mut opened = sealed.clone();
+            opener.decrypt_blocks_inplace(&mut opened).unwrap();
+            prop_assert_eq!(&opened, &plaintext);
+            prop_assert_eq!(opener.tag().bytes(), tag.bytes());
+
+            if !legacy && !sealed.is_empty() {
+                let at = flip.index(sealed.len());
+                sealed[at] ^= 1 << bit;
+                let mut opener = cipher;
+                opener.decrypt_blocks_inplace(&mut sealed).unwrap();
+                prop_assert_ne!(opener.tag().bytes(), tag.bytes());
+            }
+        }
+    }
+
+    /// Versions 0 and 1 files must keep opening: their cipher, pinned on the inputs of the last
+    /// NIST case, the last block padded as the formats do.
+    #[test]
+    fn test_cipher_legacy_vector() {
+        let key: Key =
+            hex::decode("feffe9928665731c6d6a8f9467308308feffe9928665731c6d6a8f9467308308")
+                .unwrap()
+                .try_into()
+                .unwrap();
+        let iv = Block::from([
+            0xca, 0xfe, 0xba, 0xbe, 0xfa, 0xce, 0xdb, 0xad, 0xde, 0xca, 0xf8, 0x88,
+        ]);
+        let aad = hex::decode("feedfacedeadbeeffeedfacedeadbeefabaddad2").unwrap();
+        let plaintext = hex::decode(
+            "d9313225f88406e5a55909c5aff5269a86a7a9531534f7da2e4c303d8a318a72\
+             1c3c0c95956809532fcf0e2449a6b525b16aedf5aa0de657ba637b39",
+        )
+        .unwrap();
+
+        let mut cipher = Cipher::legacy(key, iv, &aad);
+        let mut opener = cipher.clone();
+        let mut sealed = plaintext[..48].to_vec();
+        cipher.encrypt_blocks_inplace(&mut sealed).unwrap();
+        let mut last = Block::default();
+        last.bytes_mut()[..12].copy_from_slice(&plaintext[48..]);
+        pkcs7::pad(&mut last, 12);
+        cipher.encrypt_block_inplace(&mut last, 12).unwrap();
+        sealed.extend_from_slice(last.bytes());
+
+        assert_eq!(
+            sealed,
+            hex::decode(
+                "241d98335d0728930f4a25d1fc1bfce40dbb5a8674e68c387f6a0e5b0f40ee95\
+                 fea1291a3fb93e40741b9ca4e6c2eefd21e66f2866685439321cfe0d1b203919"
+            )
+            .unwrap()
+        );
+        let mut legacy = cipher.clone();
+        let tag = *cipher.tag();
+        assert_eq!(
+            tag.bytes().as_slice(),
+            hex::decode("26c9a600289f92f4e9c86090deb3b473").unwrap()
+        );
+        assert_eq!(
+            legacy.legacy_tag().bytes().as_slice(),
+            hex::decode("81c799451b22e38cfd1bd51a23be3bb1").unwrap()
+        );
+
+        let mut tampered = sealed.clone();
+        opener.decrypt_blocks_inplace(&mut sealed[..48]).unwrap();
+        let mut last = Block::default();
+        last.bytes_mut().copy_from_slice(&sealed[48..]);
+        assert_eq!(opener.decrypt_last_block_inplace(&mut last).unwrap(), 12);
+        assert_eq!(&sealed[..48], &plaintext[..48]);
+        assert_eq!(&last.bytes()[..12], &plaintext[48..]);
+        assert_eq!(opener.tag().bytes(), tag.bytes());
+
+        // the legacy hash is not injective, but this flipped bit does change the tag
+        tampered[0] ^= 1;
+        let mut opener = Cipher::legacy(key, iv, &aad);
+        opener.decrypt_blocks_inplace(&mut tampered[..48]).unwrap();
+        last.bytes_mut().copy_from_slice(&tampered[48..]);
+        assert_eq!(opener.decrypt_last_block_inplace(&mut last).unwrap(), 12);
+        assert_ne!(opener.tag().bytes(), tag.bytes());
+    }
+
+    #[test]
+    fn test_cipher_blocks_match_block_at_a_time() {
+        let key: Key = [3; 32];
+        let iv = Block::from([4_u8; IV_SIZE]);
+        let mut data = vec![0_u8; 100 * BLOCK_SIZE];
+        rand::thread_rng().fill_bytes(&mut data);
+
+        let mut cipher = Cipher::new(key, iv, b"aad");
+        let mut expected = data.clone();
+        for bytes in expected.chunks_exact_mut(BLOCK_SIZE) {
+            let mut block = Block::default();
+            block.bytes_mut().copy_from_slice(bytes);
+            cipher
+                .encrypt_block_inplace(&mut block, BLOCK_SIZE)
+                .unwrap();
+            bytes.copy_from_slice(block.bytes());
+        }
+
+        let tag = *cipher.tag();
+
+        let mut batched = Cipher::new(key, iv, b"aad");
+        let mut sealed = data.clone();
+        batched.encrypt_blocks_inplace(&mut sealed).unwrap();
+        assert_eq!(sealed, expected);
+        assert_eq!(batched.tag().bytes(), tag.bytes());
+
+        let mut opener = Cipher::new(key, iv, b"aad");
+        opener.decrypt_blocks_inplace(&mut sealed).unwrap();
+        assert_eq!(sealed, data);
+        assert_eq!(opener.tag().bytes(), tag.bytes());
+    }
 
     #[test]
     fn test_iv_new() {
@@ -219,7 +787,7 @@ mod tests {
     }
 
     #[test]
-    fn test_cipher_encrypt_in_place() {
+    fn test_cipher_encryption() {
         let key: Key = [
             0x6B, 0x38, 0x46, 0x7A, 0x58, 0x77, 0x32, 0x61, 0x4C, 0x70, 0x51, 0x39, 0x76, 0x4A,
             0x33, 0x6E, 0x47, 0x6D, 0x34, 0x52, 0x30, 0x79, 0x55, 0x63, 0x48, 0x74, 0x42, 0x73,
@@ -230,50 +798,111 @@ mod tests {
             0x3A, 0x9F, 0xB4, 0x7E, 0x2D, 0x1C, 0xF8, 0x05, 0x9C, 0x7B, 0xA2, 0x6D,
         ]);
 
-        let mut cipher = Cipher::new(key, iv, &None);
+        let mut cipher = Cipher::new(key, iv, &[]);
+        let mut cipher2 = cipher.clone();
 
         let plaintext = Block::from([
-            0x5A, 0x37, 0x71, 0x50, 0x39, 0x6B, 0x54, 0x62, 0x58, 0x31, 0x4C, 0x72, 0x34, 0x57,
-            0x6D, 0x4A,
-        ]);
-
-        let ciphertext = Block::from([
             0xEE, 0x0D, 0x0A, 0x0D, 0xD1, 0x2D, 0xE2, 0x48, 0x2B, 0xFF, 0xE9, 0x82, 0x8F, 0x2F,
             0x9A, 0xB9,
         ]);
 
         let mut block = plaintext;
 
-        cipher.encrypt_block_inplace(&mut block, BLOCK_SIZE);
+        cipher
+            .encrypt_block_inplace(&mut block, BLOCK_SIZE)
+            .unwrap();
+        cipher2.decrypt_block_inplace(&mut block).unwrap();
 
-        assert_ne!(block.bytes(), ciphertext.bytes());
+        assert_eq!(block.bytes(), plaintext.bytes());
     }
 
     #[test]
-    fn test_cipher_encryption() {
-        let key: Key = [
-            0x6B, 0x38, 0x46, 0x7A, 0x58, 0x77, 0x32, 0x61, 0x4C, 0x70, 0x51, 0x39, 0x76, 0x4A,
-            0x33, 0x6E, 0x47, 0x6D, 0x34, 0x52, 0x30, 0x79, 0x55, 0x63, 0x48, 0x74, 0x42, 0x73,
-            0x56, 0x37, 0x64, 0x59,
-        ];
+    fn test_cipher_tag_covers_ciphertext_and_aad() {
+        let key: Key = [7; 32];
+        let iv = Block::from([9_u8; IV_SIZE]);
+        let aad = b"authenticated data spanning two blocks";
+
+        let mut block = Block::from([1_u8; BLOCK_SIZE]);
+        let mut cipher = Cipher::new(key, iv, aad);
+        cipher
+            .encrypt_block_inplace(&mut block, BLOCK_SIZE)
+            .unwrap();
+        let tag = *cipher.tag();
+
+        let mut opener = Cipher::new(key, iv, aad);
+        let mut decrypted = block;
+        opener.decrypt_block_inplace(&mut decrypted).unwrap();
+        assert_eq!(opener.tag().bytes(), tag.bytes());
+
+        let mut tampered = block;
+        tampered.bytes_mut()[0] ^= 1;
+        let mut opener = Cipher::new(key, iv, aad);
+        opener.decrypt_block_inplace(&mut tampered).unwrap();
+        assert_ne!(opener.tag().bytes(), tag.bytes());
+
+        let mut opener = Cipher::new(key, iv, b"other data");
+        let mut decrypted = block;
+        opener.decrypt_block_inplace(&mut decrypted).unwrap();
+        assert_ne!(opener.tag().bytes(), tag.bytes());
+    }
 
-        let iv = Block::from([
-            0x3A, 0x9F, 0xB4, 0x7E, 0x2D, 0x1C, 0xF8, 0x05, 0x9C, 0x7B, 0xA2, 0x6D,
-        ]);
+    #[test]
+    fn test_cipher_decrypt_keeps_padding_like_bytes() {
+        let key: Key = [7; 32];
+        let iv = Block::from([9_u8; IV_SIZE]);
 
-        let mut cipher = Cipher::new(key, iv, &None);
-        let mut cipher2 = cipher.clone();
+        // a full block that happens to end like pkcs7 padding
+        let mut plaintext = Block::from([0xAB_u8; BLOCK_SIZE]);
+        plaintext.bytes_mut()[BLOCK_SIZE - 1] = 1;
+        let mut last = Block::default();
+        pkcs7::pad(&mut last, 0);
 
-        let plaintext = Block::from([
-            0xEE, 0x0D, 0x0A, 0x0D, 0xD1, 0x2D, 0xE2, 0x48, 0x2B, 0xFF, 0xE9, 0x82, 0x8F, 0x2F,
-            0x9A, 0xB9,
-        ]);
+        let mut cipher = Cipher::new(key, iv, &[]);
+        let mut opener = cipher.clone();
 
         let mut block = plaintext;
+        cipher
+            .encrypt_block_inplace(&mut block, BLOCK_SIZE)
+            .unwrap();
+        cipher.encrypt_block_inplace(&mut last, 0).unwrap();
 
-        cipher.encrypt_block_inplace(&mut block, BLOCK_SIZE);
-        cipher2.decrypt_block_inplace(&mut block);
-
+        opener.decrypt_block_inplace(&mut block).unwrap();
         assert_eq!(block.bytes(), plaintext.bytes());
+        assert_eq!(opener.decrypt_last_block_inplace(&mut last).unwrap(), 0);
+        assert_eq!(opener.tag().bytes(), cipher.tag().bytes());
+    }
+
+    #[test]
+    fn test_cipher_refuses_counter_wrap() {
+        // the tag takes counter `u32::MAX - 2`, leaving two blocks of payload
+        let mut iv = Block::from([4_u8; IV_SIZE]);
+        iv.bytes_mut()[IV_SIZE..].copy_from_slice(&(u32::MAX - 3).to_be_bytes());
+        let mut cipher = Cipher::new([3; 32], iv, &[]);
+        let mut opener = cipher.clone();
+
+        let mut data = [7_u8; 3 * BLOCK_SIZE];
+        assert!(matches!(
+            cipher.encrypt_blocks_inplace(&mut data),
+            Err(error::Error::Other(_))
+        ));
+        assert_eq!(data, [7_u8; 3 * BLOCK_SIZE], "nothing was encrypted");
+
+        cipher
+            .encrypt_blocks_inplace(&mut data[..2 * BLOCK_SIZE])
+            .unwrap();
+        let mut block = Block::default();
+        assert!(cipher.encrypt_block_inplace(&mut block, 0).is_err());
+        assert!(cipher.encrypt_tail_inplace(&mut [1]).is_err());
+        cipher.encrypt_tail_inplace(&mut []).unwrap();
+
+        opener
+            .decrypt_blocks_inplace(&mut data[..2 * BLOCK_SIZE])
+            .unwrap();
+        assert_eq!(data, [7_u8; 3 * BLOCK_SIZE]);
+        assert!(matches!(
+            opener.decrypt_block_inplace(&mut block),
+            Err(error::Error::TagMismatch(_))
+        ));
+        assert_eq!(opener.tag().bytes(), cipher.tag().bytes());
     }
 }
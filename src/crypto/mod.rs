@@ -1,3 +1,5 @@
+use sha2::{Digest, Sha256};
+
 pub mod block;
 pub mod cipher;
 pub mod pkcs7;
@@ -7,3 +9,17 @@ pub const BLOCK_SIZE: usize = 16;
 pub const KEY_SIZE: usize = 32;
 
 pub type Key = [u8; KEY_SIZE];
+
+pub const FINGERPRINT_SIZE: usize = 8;
+
+/// Names a key without giving anything away about it, to tell keys apart.
+pub fn fingerprint(key: &Key) -> [u8; FINGERPRINT_SIZE] {
+    let digest = Sha256::new()
+        .chain_update(b"file-encryptor key fingerprint")
+        .chain_update(key)
+        .finalize();
+
+    let mut fingerprint = [0_u8; FINGERPRINT_SIZE];
+    fingerprint.copy_from_slice(&digest[..FINGERPRINT_SIZE]);
+    fingerprint
+}
@@ -15,6 +15,9 @@ pub fn unpad(block: &mut Block) -> usize {
 
     let bytes = block.bytes_mut();
     let last_byte = bytes[block_len - 1];
+    if last_byte as usize > block_len {
+        return 0;
+    }
 
     // check if padding exists
     let mut bytes_padded = 0;
@@ -36,6 +39,7 @@ pub fn unpad(block: &mut Block) -> usize {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn pkcs7_pad() {
@@ -69,4 +73,26 @@ mod tests {
             assert_eq!(block.bytes()[i], 0);
         }
     }
+
+    #[test]
+    fn pkcs7_unpad_out_of_range() {
+        let mut block = Block::default();
+        block.bytes_mut()[BLOCK_SIZE - 1] = BLOCK_SIZE as u8 + 1;
+        assert_eq!(unpad(&mut block), 0);
+        assert_eq!(block.bytes()[BLOCK_SIZE - 1], BLOCK_SIZE as u8 + 1);
+
+        block.bytes_mut()[BLOCK_SIZE - 1] = 0xff;
+        assert_eq!(unpad(&mut block), 0);
+    }
+
+    proptest! {
+        #[test]
+        fn pkcs7_pad_unpad_any(bytes in any::<[u8; BLOCK_SIZE]>(), size in 0..BLOCK_SIZE) {
+            let mut block = Block::default();
+            block.bytes_mut()[..size].copy_from_slice(&bytes[..size]);
+            pad(&mut block, size);
+            prop_assert_eq!(unpad(&mut block), BLOCK_SIZE - size);
+            prop_assert_eq!(&block.bytes()[..size], &bytes[..size]);
+        }
+    }
 }
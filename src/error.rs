@@ -1,12 +1,70 @@
-use std::{error, fmt, io, process, result};
+use alloc::{boxed::Box, string::String};
+use core::{error, fmt, result};
+#[cfg(feature = "std")]
+use std::{
+    io,
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::crypto::KEY_SIZE;
 
 pub type Result<T> = result::Result<T, Error>;
 
+/// The error behind a `BadHeader` or `KeyFileInvalid` one, if any, see `Error::caused_by`.
+pub type Cause = Box<dyn error::Error + Send + Sync>;
+
+/// Where an error happened, as far as it is known: the file, and the byte offset in it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Location {
+    #[cfg(feature = "std")]
+    pub path: Option<PathBuf>,
+    pub offset: Option<u64>,
+}
+
+impl Location {
+    #[cfg(feature = "std")]
+    pub fn path(path: &Path) -> Self {
+        Self {
+            path: Some(path.to_path_buf()),
+            offset: None,
+        }
+    }
+
+    pub fn offset(offset: u64) -> Self {
+        Self {
+            #[cfg(feature = "std")]
+            path: None,
+            offset: Some(offset),
+        }
+    }
+}
+
+/// Every variant has an exit code of its own, see `status_code`. These never change, scripts
+/// may rely on them.
 #[derive(Debug)]
 pub enum Error {
-    IO(String),
-    Key,
-    Encryption(String),
+    /// reading or writing a file failed
+    #[cfg(feature = "std")]
+    IO(io::Error, Location),
+    /// the key does not match the key check of the file, nothing was decrypted
+    WrongKey(Location),
+    /// the sealed data is not what was sealed: its tag (or a chunk's) does not match, or it came
+    /// apart before the tag was reached. Files sealed without a key check end up here with a
+    /// wrong key too
+    TagMismatch(Location),
+    /// the file ends before its tag, or in the middle of its header
+    Truncated(Location),
+    /// the header, or the metadata section, is malformed
+    BadHeader(&'static str, Location, Option<Cause>),
+    /// the header is of a format version this build cannot open
+    UnsupportedVersion(u8, Location),
+    /// a key file is not exactly one key, or stdin ended before a whole key was read
+    KeyFileInvalid(Location, Option<Cause>),
+    /// a headerless (format version 0) file, whose tag only covers the lengths: it is only opened
+    /// when explicitly allowed, see `Opener::with_unauthenticated_legacy`
+    Unauthenticated(Location),
+    /// anything else: invalid arguments, failed batches, unsupported operations
     Other(String),
 }
 
@@ -14,42 +72,238 @@ impl Error {
     pub fn status_code(&self) -> u8 {
         match self {
             Self::Other(_) => 1,
-            Self::IO(_) => 2,
-            Self::Key => 3,
-            Self::Encryption(_) => 4,
+            #[cfg(feature = "std")]
+            Self::IO(..) => 2,
+            Self::WrongKey(_) => 3,
+            Self::TagMismatch(_) => 4,
+            Self::Truncated(_) => 5,
+            Self::BadHeader(..) => 6,
+            Self::UnsupportedVersion(..) => 7,
+            Self::KeyFileInvalid(..) => 8,
+            Self::Unauthenticated(_) => 9,
+        }
+    }
+
+    /// A stable, machine readable name for the variant.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Other(_) => "other",
+            #[cfg(feature = "std")]
+            Self::IO(..) => "io",
+            Self::WrongKey(_) => "wrong_key",
+            Self::TagMismatch(_) => "tag_mismatch",
+            Self::Truncated(_) => "truncated",
+            Self::BadHeader(..) => "bad_header",
+            Self::UnsupportedVersion(..) => "unsupported_version",
+            Self::KeyFileInvalid(..) => "key_file_invalid",
+            Self::Unauthenticated(_) => "unauthenticated",
+        }
+    }
+
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            #[cfg(feature = "std")]
+            Self::IO(_, at) => Some(at),
+            Self::WrongKey(at)
+            | Self::TagMismatch(at)
+            | Self::Truncated(at)
+            | Self::BadHeader(_, at, _)
+            | Self::UnsupportedVersion(_, at)
+            | Self::KeyFileInvalid(at, _)
+            | Self::Unauthenticated(at) => Some(at),
+            Self::Other(_) => None,
+        }
+    }
+
+    /// Keeps `cause` as the source of a `BadHeader` or `KeyFileInvalid` error, the other variants
+    /// have none of their own.
+    pub fn caused_by(mut self, cause: impl Into<Cause>) -> Self {
+        if let Self::BadHeader(_, _, source) | Self::KeyFileInvalid(_, source) = &mut self {
+            *source = Some(cause.into());
+        }
+        self
+    }
+
+    /// An I/O error on `path`.
+    #[cfg(feature = "std")]
+    pub fn io(err: io::Error, path: &Path) -> Self {
+        Self::IO(err, Location::path(path))
+    }
+
+    /// Attributes an error about sealed data to the file `path`, unless it already names one.
+    /// I/O and key file errors are left alone, they are about whichever file failed.
+    #[cfg(feature = "std")]
+    pub fn with_path(mut self, path: &Path) -> Self {
+        if let Some(at) = self.sealed_location() {
+            at.path.get_or_insert_with(|| path.to_path_buf());
+        }
+        self
+    }
+
+    /// Places an error about sealed data at `offset`, unless it already has an offset.
+    pub fn with_offset(mut self, offset: u64) -> Self {
+        if let Some(at) = self.sealed_location() {
+            at.offset.get_or_insert(offset);
+        }
+        self
+    }
+
+    fn sealed_location(&mut self) -> Option<&mut Location> {
+        match self {
+            Self::WrongKey(at)
+            | Self::TagMismatch(at)
+            | Self::Truncated(at)
+            | Self::BadHeader(_, at, _)
+            | Self::UnsupportedVersion(_, at)
+            | Self::Unauthenticated(at) => Some(at),
+            #[cfg(feature = "std")]
+            Self::IO(..) => None,
+            Self::KeyFileInvalid(..) | Self::Other(_) => None,
         }
     }
 }
 
-impl error::Error for Error {}
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            #[cfg(feature = "std")]
+            Self::IO(err, _) => Some(err),
+            Self::BadHeader(_, _, Some(cause)) | Self::KeyFileInvalid(_, Some(cause)) => {
+                Some(cause.as_ref())
+            }
+            _ => None,
+        }
+    }
+}
 
 unsafe impl Send for Error {}
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let at = self.location();
+        #[cfg(feature = "std")]
+        if let Some(path) = at.and_then(|at| at.path.as_ref()) {
+            write!(f, "{}: ", path.display())?;
+        }
+
         match self {
-            Self::IO(msg) => write!(f, "{}", msg),
-            Self::Key => write!(f, "Invalid key"),
+            #[cfg(feature = "std")]
+            Self::IO(err, _) => write!(f, "{}", err),
+            Self::WrongKey(_) => write!(f, "wrong key, the file was sealed with another one"),
+            Self::TagMismatch(_) => write!(f, "invalid tag, the file is corrupted"),
+            Self::Truncated(_) => write!(f, "truncated file"),
+            Self::BadHeader(reason, ..) => write!(f, "invalid header: {}", reason),
+            Self::UnsupportedVersion(version, _) => {
+                write!(f, "unsupported file format version {}", version)
+            }
+            Self::KeyFileInvalid(..) => {
+                write!(
+                    f,
+                    "invalid key, a key file holds exactly {} bytes",
+                    KEY_SIZE
+                )
+            }
+            Self::Unauthenticated(_) => write!(
+                f,
+                "headerless file (format version 0), its tag does not authenticate the data"
+            ),
             Self::Other(msg) => write!(f, "{}", msg),
-            Self::Encryption(msg) => write!(f, "{}", msg),
+        }?;
+
+        if let Some(offset) = at.and_then(|at| at.offset) {
+            write!(f, " (at byte {})", offset)?;
         }
+
+        Ok(())
     }
 }
 
+#[cfg(feature = "std")]
 impl process::Termination for Error {
     fn report(self) -> process::ExitCode {
         self.status_code().into()
     }
 }
 
+#[cfg(feature = "std")]
 impl From<io::Error> for Error {
-    fn from(value: std::io::Error) -> Self {
-        Self::IO(value.to_string())
+    fn from(value: io::Error) -> Self {
+        // readers and writers of this crate tunnel their errors through `io::Error`
+        if value.get_ref().is_some_and(|err| err.is::<Error>()) {
+            return *value
+                .into_inner()
+                .and_then(|err| err.downcast::<Error>().ok())
+                .expect("checked above");
+        }
+
+        Self::IO(value, Location::default())
     }
 }
 
+#[cfg(feature = "std")]
+impl From<Error> for io::Error {
+    fn from(value: Error) -> Self {
+        let kind = match &value {
+            Error::IO(err, _) => err.kind(),
+            Error::Other(_) => io::ErrorKind::Other,
+            Error::WrongKey(_) | Error::KeyFileInvalid(..) => io::ErrorKind::InvalidInput,
+            Error::Truncated(_) => io::ErrorKind::UnexpectedEof,
+            Error::TagMismatch(_)
+            | Error::BadHeader(..)
+            | Error::UnsupportedVersion(..)
+            | Error::Unauthenticated(_) => io::ErrorKind::InvalidData,
+        };
+
+        io::Error::new(kind, value)
+    }
+}
+
+#[cfg(feature = "std")]
 impl From<anyhow::Error> for Error {
     fn from(value: anyhow::Error) -> Self {
         Self::Other(value.to_string())
     }
 }
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_location() {
+        let err = Error::TagMismatch(Location::offset(42)).with_path(Path::new("x.enc"));
+        assert_eq!(
+            err.to_string(),
+            "x.enc: invalid tag, the file is corrupted (at byte 42)"
+        );
+        assert_eq!(err.status_code(), 4);
+
+        // the first location given sticks
+        let err = err.with_path(Path::new("y.enc")).with_offset(7);
+        assert_eq!(
+            err.location(),
+            Some(&Location {
+                path: Some(PathBuf::from("x.enc")),
+                offset: Some(42),
+            })
+        );
+
+        // an I/O error is about whichever file failed, not the sealed one
+        let err = Error::io(io::ErrorKind::NotFound.into(), Path::new("key"));
+        let err = err.with_path(Path::new("x.enc"));
+        assert_eq!(err.location(), Some(&Location::path(Path::new("key"))));
+        assert!(error::Error::source(&err).is_some());
+
+        // as is the cause of a malformed header or key file
+        let err = Error::BadHeader("invalid metadata", Location::default(), None)
+            .caused_by(io::Error::other("not json"));
+        assert_eq!(error::Error::source(&err).unwrap().to_string(), "not json");
+        let err = Error::KeyFileInvalid(Location::default(), None);
+        assert!(error::Error::source(&err).is_none());
+
+        // through `io::Error` and back
+        let tunneled = io::Error::from(Error::Truncated(Location::offset(3)));
+        assert_eq!(tunneled.kind(), io::ErrorKind::UnexpectedEof);
+        assert!(matches!(Error::from(tunneled), Error::Truncated(_)));
+    }
+}
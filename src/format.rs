@@ -0,0 +1,570 @@
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::io::{self, Read, Write};
+
+#[cfg(feature = "cli")]
+use clap::ValueEnum;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+#[cfg(feature = "std")]
+use crate::ioutils;
+use crate::{
+    crypto::{block::Block, cipher::Cipher, Key, BLOCK_SIZE, FINGERPRINT_SIZE, IV_SIZE},
+    error::{self, Location},
+};
+
+/// Every sealed file starts with these bytes, files without it are treated as the headerless
+/// (version 0) format.
+pub const MAGIC: [u8; 4] = *b"FENC";
+/// The version sealed files are written with. Version 2 is sealed with standard AES-256-GCM,
+/// versions 0 and 1 with the cipher of their own, see `crypto::cipher`, and still open.
+/// Version 1 headers end at the compression, the flags byte came with version 2.
+pub const VERSION: u8 = 2;
+
+/// magic, version, compression, flags
+pub const HEADER_SIZE: usize = MAGIC.len() + 3;
+/// magic, version, compression
+const VERSION_1_HEADER_SIZE: usize = MAGIC.len() + 2;
+
+/// the plaintext is a tar stream of a whole directory
+const FLAG_ARCHIVE: u8 = 0b0000_0001;
+/// the ciphertext is split in independently authenticated chunks, see `seekable`.
+/// the chunk size follows the flags, as a big endian u32
+const FLAG_SEEKABLE: u8 = 0b0000_0010;
+/// a key check value follows the chunk size (if any), see `Header::key_check`
+const FLAG_KEY_CHECK: u8 = 0b0000_0100;
+
+/// the size of a sealed metadata section follows the key check (if any), as a big endian u32.
+/// the section itself follows the iv, see `metadata`
+const FLAG_METADATA: u8 = 0b0000_1000;
+/// the fingerprint of the key follows the metadata size (if any), see `Header::key_id`
+const FLAG_KEY_ID: u8 = 0b0001_0000;
+/// the size of a key wrap section follows the key id (if any), as a big endian u32. the section
+/// itself follows the iv, before the sealed metadata, see `plugin`
+const FLAG_WRAP: u8 = 0b0010_0000;
+
+/// Larger metadata sections are refused when reading a header, a name is a few hundred bytes at
+/// most.
+pub const MAX_METADATA_SIZE: u32 = 64 * 1024;
+/// Larger key wrap sections are refused too, a wrapped key is a few kilobytes at most.
+pub const MAX_WRAP_SIZE: u32 = 64 * 1024;
+
+pub const KEY_CHECK_SIZE: usize = 8;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+#[cfg_attr(
+    feature = "std",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "lowercase")
+)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+    Gzip,
+}
+
+impl TryFrom<u8> for Compression {
+    type Error = error::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Zstd),
+            2 => Ok(Self::Gzip),
+            _ => Err(error::Error::BadHeader(
+                "unknown compression algorithm",
+                Location::default(),
+                None,
+            )),
+        }
+    }
+}
+
+impl From<Compression> for u8 {
+    fn from(value: Compression) -> Self {
+        match value {
+            Compression::None => 0,
+            Compression::Zstd => 1,
+            Compression::Gzip => 2,
+        }
+    }
+}
+
+/// The plaintext header preceding the ciphertext. Everything but the iv is fed to the cipher as
+/// additional authenticated data, so a flipped bit in the header fails the tag check.
+#[derive(Debug, Clone, Copy)]
+pub struct Header {
+    pub version: u8,
+    pub compression: Compression,
+    pub archive: bool,
+    /// plaintext bytes per chunk of a seekable file
+    pub chunk_size: Option<u32>,
+    /// tells a wrong key apart from a corrupted file, before any decryption. set by `Sealer`
+    pub key_check: Option<[u8; KEY_CHECK_SIZE]>,
+    /// size of the sealed metadata section after the iv. set by `Sealer::with_metadata`
+    pub metadata_size: Option<u32>,
+    /// `crypto::fingerprint` of the key, for files sealed with a key of the keystore. unlike the
+    /// key check, it is the same for every file sealed with the key
+    pub key_id: Option<[u8; FINGERPRINT_SIZE]>,
+    /// size of the key wrap section after the iv, for files sealed under a key wrapped by a
+    /// plugin
+    pub wrap_size: Option<u32>,
+    pub iv: Block,
+}
+
+impl Header {
+    #[cfg(feature = "std")]
+    pub fn new(compression: Compression) -> Self {
+        Self {
+            version: VERSION,
+            compression,
+            archive: false,
+            chunk_size: None,
+            key_check: None,
+            metadata_size: None,
+            key_id: None,
+            wrap_size: None,
+            iv: Block::new_iv(),
+        }
+    }
+
+    /// Binds the header to `key`, a later open with another key fails with
+    /// `Error::WrongKey`.
+    pub fn set_key_check(&mut self, key: &Key) {
+        self.key_check = Some(key_check(key, &self.iv));
+    }
+
+    /// Whether `key` is the one the file was sealed with, as far as the key check tells. Any key
+    /// passes when there is none, the tag check is all there is then.
+    pub fn check_key(&self, key: &Key) -> bool {
+        self.key_check
+            .is_none_or(|key_check_value| key_check_value == key_check(key, &self.iv))
+    }
+
+    /// The cipher of the header's format version, for the payload under `iv` and `aad` (the
+    /// header's own, or ones derived from them).
+    pub fn cipher(&self, key: Key, iv: Block, aad: &[u8]) -> Cipher {
+        if self.version >= 2 {
+            Cipher::new(key, iv, aad)
+        } else {
+            Cipher::legacy(key, iv, aad)
+        }
+    }
+
+    /// the authenticated part of the header, empty for the headerless format
+    pub fn aad(&self) -> Vec<u8> {
+        if self.version == 0 {
+            return Vec::new();
+        }
+        if self.version == 1 {
+            let mut buf = Vec::with_capacity(VERSION_1_HEADER_SIZE);
+            buf.extend_from_slice(&MAGIC);
+            buf.push(self.version);
+            buf.push(self.compression.into());
+            return buf;
+        }
+
+        let mut flags = 0;
+        if self.archive {
+            flags |= FLAG_ARCHIVE;
+        }
+        if self.chunk_size.is_some() {
+            flags |= FLAG_SEEKABLE;
+        }
+        if self.key_check.is_some() {
+            flags |= FLAG_KEY_CHECK;
+        }
+        if self.metadata_size.is_some() {
+            flags |= FLAG_METADATA;
+        }
+        if self.key_id.is_some() {
+            flags |= FLAG_KEY_ID;
+        }
+        if self.wrap_size.is_some() {
+            flags |= FLAG_WRAP;
+        }
+
+        let mut buf =
+            Vec::with_capacity(HEADER_SIZE + 4 + KEY_CHECK_SIZE + 4 + FINGERPRINT_SIZE + 4);
+        buf.extend_from_slice(&MAGIC);
+        buf.push(self.version);
+        buf.push(self.compression.into());
+        buf.push(flags);
+        if let Some(chunk_size) = self.chunk_size {
+            buf.extend_from_slice(&chunk_size.to_be_bytes());
+        }
+        if let Some(key_check) = self.key_check {
+            buf.extend_from_slice(&key_check);
+        }
+        if let Some(metadata_size) = self.metadata_size {
+            buf.extend_from_slice(&metadata_size.to_be_bytes());
+        }
+        if let Some(key_id) = self.key_id {
+            buf.extend_from_slice(&key_id);
+        }
+        if let Some(wrap_size) = self.wrap_size {
+            buf.extend_from_slice(&wrap_size.to_be_bytes());
+        }
+        buf
+    }
+
+    /// size of the whole header, iv, key wrap section and sealed metadata included: where the
+    /// payload starts
+    pub fn size(&self) -> usize {
+        self.aad().len()
+            + IV_SIZE
+            + self.wrap_size.unwrap_or(0) as usize
+            + self.metadata_size.unwrap_or(0) as usize
+    }
+
+    /// Writes the header up to the iv, the key wrap section and sealed metadata are up to the
+    /// caller.
+    #[cfg(feature = "std")]
+    pub fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.aad())?;
+        w.write_all(self.iv.iv_bytes())
+    }
+
+    /// Reads the header up to the iv, the key wrap section and sealed metadata (if any) are left
+    /// to read. A field that does not parse is reported at its offset, a header cut short where
+    /// it ends.
+    #[cfg(feature = "std")]
+    pub fn read<R: Read>(r: &mut R) -> error::Result<Self> {
+        Self::read_fields(|buf| Ok(ioutils::read_full(r, buf)?))
+    }
+
+    /// Parses the header at the start of `bytes`, as `read` does. It needs no `std`, `size()`
+    /// tells where the payload starts.
+    pub fn parse(mut bytes: &[u8]) -> error::Result<Self> {
+        Self::read_fields(|buf| {
+            let n = buf.len().min(bytes.len());
+            buf[..n].copy_from_slice(&bytes[..n]);
+            bytes = &bytes[n..];
+            Ok(n)
+        })
+    }
+
+    /// `fill` fills a buffer, or as much of it as there is left to read.
+    fn read_fields(mut fill: impl FnMut(&mut [u8]) -> error::Result<usize>) -> error::Result<Self> {
+        let mut pos = 0;
+        let mut read = |buf: &mut [u8]| -> error::Result<u64> {
+            let at = pos;
+            let n = fill(buf)?;
+            pos += n as u64;
+            if n < buf.len() {
+                return Err(error::Error::Truncated(Location::offset(pos)));
+            }
+            Ok(at)
+        };
+
+        let mut magic = [0_u8; MAGIC.len()];
+        read(&mut magic)?;
+
+        let mut iv = Block::default();
+        if magic != MAGIC {
+            // headerless file, what was read is the start of the iv
+            iv.bytes_mut()[..MAGIC.len()].copy_from_slice(&magic);
+            read(&mut iv.bytes_mut()[MAGIC.len()..IV_SIZE])?;
+            return Ok(Self {
+                version: 0,
+                compression: Compression::None,
+                archive: false,
+                chunk_size: None,
+                key_check: None,
+                metadata_size: None,
+                key_id: None,
+                wrap_size: None,
+                iv,
+            });
+        }
+
+        let mut fields = [0_u8; VERSION_1_HEADER_SIZE - MAGIC.len()];
+        let at = read(&mut fields)?;
+        let [version, compression] = fields;
+        if !(1..=VERSION).contains(&version) {
+            return Err(error::Error::UnsupportedVersion(
+                version,
+                Location::offset(at),
+            ));
+        }
+        let compression =
+            Compression::try_from(compression).map_err(|err| err.with_offset(at + 1))?;
+        let mut flags = 0;
+        if version >= 2 {
+            read(core::slice::from_mut(&mut flags))?;
+        }
+        let known =
+            FLAG_ARCHIVE | FLAG_SEEKABLE | FLAG_KEY_CHECK | FLAG_METADATA | FLAG_KEY_ID | FLAG_WRAP;
+        if flags & !known != 0 {
+            return Err(invalid_header("unknown header flags", at + 2));
+        }
+
+        let chunk_size = if flags & FLAG_SEEKABLE != 0 {
+            let mut buf = [0_u8; 4];
+            let at = read(&mut buf)?;
+            let chunk_size = u32::from_be_bytes(buf);
+            if !valid_chunk_size(chunk_size) {
+                return Err(invalid_header("invalid chunk size", at));
+            }
+            Some(chunk_size)
+        } else {
+            None
+        };
+
+        let key_check = if flags & FLAG_KEY_CHECK != 0 {
+            let mut key_check = [0_u8; KEY_CHECK_SIZE];
+            read(&mut key_check)?;
+            Some(key_check)
+        } else {
+            None
+        };
+
+        let metadata_size = if flags & FLAG_METADATA != 0 {
+            let mut buf = [0_u8; 4];
+            let at = read(&mut buf)?;
+            let metadata_size = u32::from_be_bytes(buf);
+            if metadata_size > MAX_METADATA_SIZE {
+                return Err(invalid_header("metadata section too large", at));
+            }
+            Some(metadata_size)
+        } else {
+            None
+        };
+
+        let key_id = if flags & FLAG_KEY_ID != 0 {
+            let mut key_id = [0_u8; FINGERPRINT_SIZE];
+            read(&mut key_id)?;
+            Some(key_id)
+        } else {
+            None
+        };
+
+        let wrap_size = if flags & FLAG_WRAP != 0 {
+            let mut buf = [0_u8; 4];
+            let at = read(&mut buf)?;
+            let wrap_size = u32::from_be_bytes(buf);
+            if wrap_size > MAX_WRAP_SIZE {
+                return Err(invalid_header("key wrap section too large", at));
+            }
+            Some(wrap_size)
+        } else {
+            None
+        };
+
+        read(&mut iv.bytes_mut()[..IV_SIZE])?;
+
+        Ok(Self {
+            version,
+            compression,
+            archive: flags & FLAG_ARCHIVE != 0,
+            chunk_size,
+            key_check,
+            metadata_size,
+            key_id,
+            wrap_size,
+            iv,
+        })
+    }
+}
+
+/// Chunks hold whole blocks, and few enough of them that the 32 bit block counter never wraps.
+pub fn valid_chunk_size(chunk_size: u32) -> bool {
+    chunk_size != 0
+        && (chunk_size as usize).is_multiple_of(BLOCK_SIZE)
+        && chunk_size <= MAX_CHUNK_SIZE
+}
+
+pub const MAX_CHUNK_SIZE: u32 = 16 * 1024 * 1024;
+
+/// A keyed hash of the iv, under its own label so that it has nothing in common with the cipher.
+/// It is unique to the file, so files sealed with the same key can't be told apart by it.
+fn key_check(key: &Key, iv: &Block) -> [u8; KEY_CHECK_SIZE] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac takes keys of any size");
+    mac.update(b"file-encryptor key check");
+    mac.update(iv.iv_bytes());
+
+    let mut key_check = [0_u8; KEY_CHECK_SIZE];
+    key_check.copy_from_slice(&mac.finalize().into_bytes()[..KEY_CHECK_SIZE]);
+    key_check
+}
+
+fn invalid_header(reason: &'static str, offset: u64) -> error::Error {
+    error::Error::BadHeader(reason, Location::offset(offset), None)
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_roundtrip() {
+        let mut header = Header::new(Compression::Zstd);
+        header.archive = true;
+        let mut buf = Vec::new();
+        header.write(&mut buf).unwrap();
+        assert_eq!(buf.len(), HEADER_SIZE + IV_SIZE);
+
+        let parsed = Header::read(&mut buf.as_slice()).unwrap();
+        assert_eq!(parsed.version, VERSION);
+        assert_eq!(parsed.compression, Compression::Zstd);
+        assert!(parsed.archive);
+        assert_eq!(parsed.chunk_size, None);
+        assert_eq!(parsed.iv.bytes(), header.iv.bytes());
+        assert_eq!(parsed.aad(), header.aad());
+    }
+
+    #[test]
+    fn header_legacy() {
+        let iv = Block::new_iv();
+        let parsed = Header::read(&mut iv.iv_bytes()).unwrap();
+        assert_eq!(parsed.version, 0);
+        assert_eq!(parsed.compression, Compression::None);
+        assert_eq!(parsed.iv.bytes(), iv.bytes());
+        assert!(parsed.aad().is_empty());
+    }
+
+    #[test]
+    fn header_version_1() {
+        // sealed by the first version with a header, before the flags byte
+        let sealed =
+            crate::hex::decode("46454e430100d17f24fecb73db8517b481dad91a2e5157f9060b47ed4ab70f1f")
+                .unwrap();
+        let parsed = Header::read(&mut sealed.as_slice()).unwrap();
+        assert_eq!(parsed.version, 1);
+        assert_eq!(parsed.compression, Compression::None);
+        assert!(!parsed.archive);
+        assert_eq!(parsed.key_check, None);
+        assert_eq!(parsed.iv.iv_bytes(), &sealed[6..18]);
+        assert_eq!(parsed.aad(), &sealed[..6]);
+        assert_eq!(parsed.size(), 18);
+        assert_eq!(Header::parse(&sealed).unwrap().aad(), parsed.aad());
+    }
+
+    #[test]
+    fn header_unknown_compression() {
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&[VERSION, 0xff, 0]);
+        buf.extend_from_slice(&[0_u8; IV_SIZE]);
+        let err = Header::read(&mut buf.as_slice()).unwrap_err();
+        assert!(matches!(err, error::Error::BadHeader(_, at, _) if at.offset == Some(5)));
+    }
+
+    #[test]
+    fn header_unknown_flags() {
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&[VERSION, 0, 0x80]);
+        buf.extend_from_slice(&[0_u8; IV_SIZE]);
+        let err = Header::read(&mut buf.as_slice()).unwrap_err();
+        assert!(matches!(err, error::Error::BadHeader(_, at, _) if at.offset == Some(6)));
+    }
+
+    #[test]
+    fn header_errors() {
+        let mut buf = MAGIC.to_vec();
+        buf.extend_from_slice(&[VERSION + 1, 0, 0]);
+        buf.extend_from_slice(&[0_u8; IV_SIZE]);
+        let err = Header::read(&mut buf.as_slice()).unwrap_err();
+        assert!(matches!(err, error::Error::UnsupportedVersion(v, _) if v == VERSION + 1));
+
+        let mut header = Header::new(Compression::None);
+        header.chunk_size = Some(4096);
+        let mut buf = Vec::new();
+        header.write(&mut buf).unwrap();
+        for size in [0, 3, HEADER_SIZE + 2, buf.len() - 1] {
+            let err = Header::read(&mut &buf[..size]).unwrap_err();
+            assert!(matches!(err, error::Error::Truncated(_)), "{}", err);
+            let err = Header::parse(&buf[..size]).unwrap_err();
+            assert!(matches!(err, error::Error::Truncated(at) if at.offset == Some(size as u64)));
+        }
+    }
+
+    #[test]
+    fn header_parse() {
+        let mut header = Header::new(Compression::Gzip);
+        header.chunk_size = Some(4096);
+        header.set_key_check(&[1; 32]);
+        let mut buf = Vec::new();
+        header.write(&mut buf).unwrap();
+        buf.extend_from_slice(b"payload");
+
+        let parsed = Header::parse(&buf).unwrap();
+        assert_eq!(parsed.aad(), header.aad());
+        assert_eq!(parsed.iv.bytes(), header.iv.bytes());
+        assert_eq!(&buf[parsed.size()..], b"payload");
+    }
+
+    #[test]
+    fn header_seekable() {
+        let mut header = Header::new(Compression::None);
+        header.chunk_size = Some(4096);
+        let mut buf = Vec::new();
+        header.write(&mut buf).unwrap();
+        assert_eq!(buf.len(), header.size());
+
+        let parsed = Header::read(&mut buf.as_slice()).unwrap();
+        assert_eq!(parsed.chunk_size, Some(4096));
+        assert_eq!(parsed.aad(), header.aad());
+
+        for chunk_size in [0, 17, MAX_CHUNK_SIZE + BLOCK_SIZE as u32] {
+            let mut buf = MAGIC.to_vec();
+            buf.extend_from_slice(&[VERSION, 0, FLAG_SEEKABLE]);
+            buf.extend_from_slice(&chunk_size.to_be_bytes());
+            buf.extend_from_slice(&[0_u8; IV_SIZE]);
+            assert!(Header::read(&mut buf.as_slice()).is_err());
+        }
+    }
+
+    #[test]
+    fn header_key_check() {
+        let mut header = Header::new(Compression::None);
+        header.chunk_size = Some(4096);
+        assert!(header.check_key(&[1; 32]));
+
+        header.set_key_check(&[1; 32]);
+        let mut buf = Vec::new();
+        header.write(&mut buf).unwrap();
+        assert_eq!(buf.len(), header.size());
+
+        let parsed = Header::read(&mut buf.as_slice()).unwrap();
+        assert_eq!(parsed.key_check, header.key_check);
+        assert_eq!(parsed.chunk_size, Some(4096));
+        assert_eq!(parsed.aad(), header.aad());
+        assert!(parsed.check_key(&[1; 32]));
+        assert!(!parsed.check_key(&[2; 32]));
+
+        // unique to the file
+        let mut other = Header::new(Compression::None);
+        other.set_key_check(&[1; 32]);
+        assert_ne!(other.key_check, header.key_check);
+    }
+
+    #[test]
+    fn header_key_id() {
+        let mut header = Header::new(Compression::None);
+        header.metadata_size = Some(100);
+        header.key_id = Some(crate::crypto::fingerprint(&[1; 32]));
+        header.wrap_size = Some(50);
+        let mut buf = Vec::new();
+        header.write(&mut buf).unwrap();
+        assert_eq!(buf.len() + 150, header.size());
+
+        let parsed = Header::read(&mut buf.as_slice()).unwrap();
+        assert_eq!(parsed.key_id, header.key_id);
+        assert_eq!(parsed.wrap_size, Some(50));
+        assert_eq!(parsed.metadata_size, Some(100));
+        assert_eq!(parsed.aad(), header.aad());
+        assert!(Header::read(&mut &buf[..buf.len() - IV_SIZE - 1]).is_err());
+
+        header.wrap_size = Some(MAX_WRAP_SIZE + 1);
+        let mut buf = Vec::new();
+        header.write(&mut buf).unwrap();
+        let err = Header::read(&mut buf.as_slice()).unwrap_err();
+        assert!(matches!(
+            err,
+            error::Error::BadHeader("key wrap section too large", ..)
+        ));
+    }
+}
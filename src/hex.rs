@@ -0,0 +1,49 @@
+//! Lowercase hex, as ids, nonces and sealed keys are written in keystore entries, plugin
+//! messages and `inspect`'s descriptions.
+
+use alloc::{string::String, vec::Vec};
+
+const DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+pub fn encode(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .flat_map(|byte| {
+            [
+                DIGITS[usize::from(byte >> 4)],
+                DIGITS[usize::from(byte & 0xf)],
+            ]
+        })
+        .map(char::from)
+        .collect()
+}
+
+/// `None` unless `hex` is an even number of hex digits, of either case.
+pub fn decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hex_roundtrip() {
+        assert_eq!(encode(&[0x00, 0x0f, 0xa5, 0xff]), "000fa5ff");
+        assert_eq!(decode("000fa5ff").unwrap(), [0x00, 0x0f, 0xa5, 0xff]);
+        assert_eq!(decode("A5").unwrap(), [0xa5]);
+        assert!(decode("").unwrap().is_empty());
+
+        assert_eq!(decode("abc"), None);
+        assert_eq!(decode("zz"), None);
+        // `from_str_radix` alone takes a sign
+        assert_eq!(decode("+f"), None);
+        assert_eq!(decode("é0"), None);
+    }
+}
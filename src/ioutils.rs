@@ -1,87 +1,479 @@
 use std::{
-    fs::{File, OpenOptions},
-    io::{Read, Write},
+    fs::{self, File, FileTimes, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
 };
 
-use clap::Parser;
+use crate::{
+    crypto::{Key, KEY_SIZE},
+    error::{self, Location},
+    stream::BUFFER_SIZE,
+};
 
-use crate::crypto::block::Block;
+pub struct IO {
+    reader: Box<dyn Read + Send>,
+    writer: Box<dyn Write + Send>,
+}
 
-#[derive(Parser, Debug, Clone)]
-pub struct FileArg {
-    /// (optional) input file, read from stdin by default
-    #[arg(short, long)]
-    pub input_file: Option<String>,
+/// Opens the input file, or stdin if there is none.
+pub fn open_input(filein: Option<&Path>) -> error::Result<Box<dyn Read + Send>> {
+    Ok(if let Some(filename) = filein {
+        Box::new(
+            OpenOptions::new()
+                .read(true)
+                .open(filename)
+                .map_err(|err| error::Error::io(err, filename))?,
+        )
+    } else {
+        Box::new(std::io::stdin())
+    })
+}
 
-    /// (optional) output file, write to stdout by default
-    #[arg(short, long)]
-    pub output_file: Option<String>,
+/// Creates (or truncates) the output file, or stdout if there is none.
+pub fn create_output(fileout: Option<&Path>) -> error::Result<Box<dyn Write + Send>> {
+    Ok(if let Some(filename) = fileout {
+        Box::new(
+            OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .open(filename)
+                .map_err(|err| error::Error::io(err, filename))?,
+        )
+    } else {
+        Box::new(std::io::stdout())
+    })
+}
 
-    /*
-    /// (optional) additional authenticated data
-    #[arg(short, long)]
-    pub aad: Option<String>,
-    */
+/// Fills `buf` unless the reader ends first, the returned size is only short at eof.
+pub fn read_full<R: Read + ?Sized>(r: &mut R, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut size = 0;
+    while size < buf.len() {
+        match r.read(&mut buf[size..]) {
+            Ok(0) => break,
+            Ok(n) => size += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
 
-    /// (optional) key file, read (the first) 32 byte from stdin by default
-    #[arg(short, long)]
-    pub key: Option<String>,
+    Ok(size)
 }
 
+/// Copies `r` into `w` through a `BUFFER_SIZE` buffer, `io::copy` moves 8 KiB at a time.
+pub fn copy<R, W>(r: &mut R, w: &mut W) -> std::io::Result<u64>
+where
+    R: Read + ?Sized,
+    W: Write + ?Sized,
+{
+    let mut buf = vec![0_u8; BUFFER_SIZE];
+    let mut copied = 0;
+    loop {
+        let n = read_full(r, &mut buf)?;
+        w.write_all(&buf[..n])?;
+        copied += n as u64;
+
+        if n < buf.len() {
+            return Ok(copied);
+        }
+    }
+}
+
+/// Reads the key from the key file if there is one, otherwise reads (the first) 32 bytes from
+/// stdin.
+pub fn read_key(key: &Option<String>) -> error::Result<Key> {
+    let mut buf = Key::default();
+    match key {
+        None => {
+            std::io::stdin()
+                .read_exact(&mut buf)
+                .map_err(|err| match err.kind() {
+                    io::ErrorKind::UnexpectedEof => {
+                        error::Error::KeyFileInvalid(Location::default(), Some(err.into()))
+                    }
+                    _ => error::Error::from(err),
+                })?;
+        }
+        Some(key) => {
+            let path = Path::new(key);
+            let file = File::open(path).map_err(|err| error::Error::io(err, path))?;
+            buf = read_key_file(file).map_err(|err| match err {
+                error::Error::KeyFileInvalid(_, cause) => {
+                    error::Error::KeyFileInvalid(Location::path(path), cause)
+                }
+                err => err,
+            })?;
+        }
+    }
+
+    Ok(buf)
+}
+
+/// Reads a key file, which holds the key and nothing else.
+pub fn read_key_file<R: Read>(mut r: R) -> error::Result<Key> {
+    // one more byte than a key, to tell a longer file apart
+    let mut buf = [0_u8; KEY_SIZE + 1];
+    if read_full(&mut r, &mut buf)? != KEY_SIZE {
+        return Err(error::Error::KeyFileInvalid(Location::default(), None));
+    }
+
+    let mut key = Key::default();
+    key.copy_from_slice(&buf[..KEY_SIZE]);
+    Ok(key)
+}
+
+/// Fails if the output names the input file, truncating it would destroy the input before it is
+/// read.
+pub fn ensure_distinct(filein: Option<&Path>, fileout: Option<&Path>) -> std::io::Result<()> {
+    let (Some(filein), Some(fileout)) = (filein, fileout) else {
+        return Ok(());
+    };
+
+    let (Ok(input), Ok(output)) = (fs::metadata(filein), fs::metadata(fileout)) else {
+        return Ok(());
+    };
+
+    #[cfg(unix)]
+    let same = {
+        use std::os::unix::fs::MetadataExt;
+        input.dev() == output.dev() && input.ino() == output.ino()
+    };
+    #[cfg(not(unix))]
+    let same = input.is_file()
+        && output.is_file()
+        && fs::canonicalize(filein)? == fs::canonicalize(fileout)?;
+
+    if same {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{}: input and output are the same file, use `--in-place` instead",
+                fileout.display()
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// A temporary file next to `target`, which replaces it on `commit`. Until then the target is
+/// left untouched, and the temporary file is removed if the replacement is dropped. A symlink is
+/// resolved first: the file it points to is replaced, the link is kept.
 #[derive(Debug)]
-pub struct IO {
-    filein: Option<File>,
-    fileout: Option<File>,
+pub struct Replacement {
+    target: PathBuf,
+    temp: PathBuf,
+    committed: bool,
 }
 
-impl IO {
-    pub fn new(filein: &Option<String>, fileout: &Option<String>) -> std::io::Result<Self> {
-        let filein = if let Some(filename) = filein {
-            Some(OpenOptions::new().read(true).open(filename)?)
-        } else {
-            None
-        };
+impl Replacement {
+    pub fn new(target: &Path) -> std::io::Result<Self> {
+        use rand::Rng;
 
-        let fileout = if let Some(filename) = fileout {
-            Some(
-                OpenOptions::new()
-                    .create(true)
-                    .truncate(true)
-                    .write(true)
-                    .open(filename)?,
-            )
-        } else {
-            None
-        };
+        // renaming over the link would replace the link itself, and leave the file as it was
+        let target = &fs::canonicalize(target)?;
+        if !fs::metadata(target)?.is_file() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}: only regular files can be replaced", target.display()),
+            ));
+        }
+
+        let name = target.file_name().unwrap_or_default().to_string_lossy();
+        let dir = target.parent().unwrap_or(Path::new(""));
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            // the plaintext may be written here, keep it private until the original mode is copied
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+
+        loop {
+            let temp = dir.join(format!(
+                ".{}.{:08x}.tmp",
+                name,
+                rand::thread_rng().gen::<u32>()
+            ));
+            match options.open(&temp) {
+                Ok(_) => {
+                    return Ok(Self {
+                        target: target.to_path_buf(),
+                        temp,
+                        committed: false,
+                    })
+                }
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(err) => return Err(err),
+            }
+        }
+    }
 
-        Ok(Self { filein, fileout })
+    /// The temporary file to write the replacement to.
+    pub fn path(&self) -> &Path {
+        &self.temp
     }
 
-    pub fn read_block(&mut self, block: &mut Block) -> std::io::Result<usize> {
-        match &mut self.filein {
-            None => std::io::stdin().read(block.bytes_mut()),
-            Some(fd) => fd.read(block.bytes_mut()),
+    /// Syncs the temporary file to disk, gives it the mode, ownership and mtime of the target,
+    /// then renames it over the target.
+    pub fn commit(mut self) -> std::io::Result<()> {
+        let original = fs::metadata(&self.target)?;
+        let file = OpenOptions::new().write(true).open(&self.temp)?;
+        file.sync_all()?;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            let current = file.metadata()?;
+            if (current.uid(), current.gid()) != (original.uid(), original.gid()) {
+                std::os::unix::fs::fchown(&file, Some(original.uid()), Some(original.gid()))?;
+            }
+        }
+
+        file.set_permissions(original.permissions())?;
+        file.set_times(
+            FileTimes::new()
+                .set_accessed(original.accessed()?)
+                .set_modified(original.modified()?),
+        )?;
+        file.sync_all()?;
+        drop(file);
+
+        fs::rename(&self.temp, &self.target)?;
+        self.committed = true;
+
+        // the rename itself is only durable once the directory is synced
+        #[cfg(unix)]
+        if let Some(dir) = self.target.parent() {
+            let dir = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            File::open(dir)?.sync_all()?;
         }
+
+        Ok(())
     }
+}
 
-    pub fn write_block(&mut self, block: &Block, n: usize) -> std::io::Result<usize> {
-        match &mut self.fileout {
-            None => std::io::stdout().write(&block.bytes()[..n]),
-            Some(fd) => fd.write(&block.bytes()[..n]),
+impl Drop for Replacement {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp);
         }
     }
+}
+
+impl IO {
+    pub fn new(filein: &Option<String>, fileout: &Option<String>) -> error::Result<Self> {
+        let filein = filein.as_deref().map(Path::new);
+        let fileout = fileout.as_deref().map(Path::new);
+        ensure_distinct(filein, fileout)?;
+        Ok(Self::from_parts(
+            open_input(filein)?,
+            create_output(fileout)?,
+        ))
+    }
 
+    pub fn from_parts(reader: Box<dyn Read + Send>, writer: Box<dyn Write + Send>) -> Self {
+        Self { reader, writer }
+    }
+
+    /// The input and the output, to be used at the same time.
+    pub fn split(&mut self) -> (&mut dyn Read, &mut dyn Write) {
+        (&mut self.reader, &mut self.writer)
+    }
+
+    /// Fills `bytes` unless the input ends first, the returned size is only short at eof.
     pub fn read_bytes(&mut self, bytes: &mut [u8]) -> std::io::Result<usize> {
-        match &mut self.filein {
-            None => std::io::stdin().read(bytes),
-            Some(fd) => fd.read(bytes),
+        read_full(&mut self.reader, bytes)
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.writer.write_all(bytes)
+    }
+}
+
+impl Read for IO {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl Write for IO {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use rand::Rng;
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, SystemTime},
+    };
+
+    /// Hands out at most a few bytes per `read`, and is interrupted now and then, like a pipe or
+    /// a socket would.
+    pub(crate) struct ShortReader<R>(pub R);
+
+    impl<R: Read> Read for ShortReader<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let mut rng = rand::thread_rng();
+            if rng.gen_ratio(1, 10) {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+
+            let n = buf.len().min(rng.gen_range(1..=20));
+            self.0.read(&mut buf[..n])
+        }
+    }
+
+    /// Takes at most a few bytes per `write`.
+    #[derive(Clone, Default)]
+    pub(crate) struct ShortWriter(pub Arc<Mutex<Vec<u8>>>);
+
+    impl Write for ShortWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(rand::thread_rng().gen_range(1..=20));
+            self.0.lock().unwrap().extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn short_reads_and_writes() {
+        let mut data = vec![0_u8; 10_000];
+        rand::thread_rng().fill(&mut data[..]);
+
+        let output = ShortWriter::default();
+        let mut io = IO::from_parts(
+            Box::new(ShortReader(io::Cursor::new(data.clone()))),
+            Box::new(output.clone()),
+        );
+
+        let mut buf = vec![0_u8; 4096];
+        let mut read = Vec::new();
+        loop {
+            let n = io.read_bytes(&mut buf).unwrap();
+            read.extend_from_slice(&buf[..n]);
+            io.write_bytes(&buf[..n]).unwrap();
+            if n < buf.len() {
+                break;
+            }
+        }
+
+        assert_eq!(read, data);
+        assert_eq!(*output.0.lock().unwrap(), data);
+    }
+
+    #[test]
+    fn replacement_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("file");
+        fs::write(&target, b"original").unwrap();
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        File::options()
+            .write(true)
+            .open(&target)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&target, fs::Permissions::from_mode(0o640)).unwrap();
+        }
+
+        let replacement = Replacement::new(&target).unwrap();
+        fs::write(replacement.path(), b"replaced").unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"original");
+        replacement.commit().unwrap();
+
+        assert_eq!(fs::read(&target).unwrap(), b"replaced");
+        let metadata = fs::metadata(&target).unwrap();
+        assert_eq!(metadata.modified().unwrap(), mtime);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o640);
         }
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn replacement_through_symlink() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("file");
+        let link = dir.path().join("link");
+        fs::write(&target, b"original").unwrap();
+        std::os::unix::fs::symlink("file", &link).unwrap();
+
+        let replacement = Replacement::new(&link).unwrap();
+        fs::write(replacement.path(), b"replaced").unwrap();
+        replacement.commit().unwrap();
+
+        assert!(fs::symlink_metadata(&link).unwrap().is_symlink());
+        assert_eq!(fs::read(&target).unwrap(), b"replaced");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn replacement_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("file");
+        fs::write(&target, b"original").unwrap();
+
+        let replacement = Replacement::new(&target).unwrap();
+        fs::write(replacement.path(), b"half written").unwrap();
+        drop(replacement);
+
+        assert_eq!(fs::read(&target).unwrap(), b"original");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn same_input_and_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        let other = dir.path().join("other");
+        fs::write(&file, b"data").unwrap();
+
+        assert!(ensure_distinct(Some(&file), Some(&file)).is_err());
+        assert!(ensure_distinct(Some(&file), Some(&dir.path().join("./file"))).is_err());
+        assert!(ensure_distinct(Some(&file), Some(&other)).is_ok());
+        assert!(ensure_distinct(Some(&file), None).is_ok());
+        assert!(IO::new(
+            &Some(file.to_str().unwrap().to_string()),
+            &Some(file.to_str().unwrap().to_string())
+        )
+        .is_err());
+        assert_eq!(fs::read(&file).unwrap(), b"data");
     }
 
-    pub fn write_bytes(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
-        match &mut self.fileout {
-            None => std::io::stdout().write(bytes),
-            Some(fd) => fd.write(bytes),
+    #[test]
+    fn key_file_size() {
+        let key = [5_u8; KEY_SIZE];
+        assert_eq!(read_key_file(ShortReader(&key[..])).unwrap(), key);
+        for size in [0, 1, KEY_SIZE - 1, KEY_SIZE + 1, 1000] {
+            let file = vec![5_u8; size];
+            assert!(matches!(
+                read_key_file(&file[..]),
+                Err(error::Error::KeyFileInvalid(..))
+            ));
         }
     }
 }
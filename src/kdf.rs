@@ -0,0 +1,155 @@
+//! The scrypt derivation `keygen` puts every key through: passwords, random bytes and piped
+//! input alike.
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    crypto::{Key, KEY_SIZE},
+    error,
+};
+
+/// scrypt parameters of every key derived by `keygen`, with no salt, unless the config file
+/// sets others
+pub const SCRYPT_LOG_N: u8 = 16;
+pub const SCRYPT_R: u32 = 8;
+pub const SCRYPT_P: u32 = 2;
+
+/// scrypt cost parameters, the ones left out of a config file keep their default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Params {
+    pub log_n: u8,
+    pub r: u32,
+    pub p: u32,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        // https://tobtu.com/minimum-password-settings/
+        Self {
+            log_n: SCRYPT_LOG_N,
+            r: SCRYPT_R,
+            p: SCRYPT_P,
+        }
+    }
+}
+
+pub struct Hash(scrypt::Params);
+
+impl Default for Hash {
+    fn default() -> Self {
+        Self::new(Params::default()).expect("invalid param for scrypt")
+    }
+}
+
+impl Hash {
+    pub fn new(params: Params) -> error::Result<Self> {
+        scrypt::Params::new(params.log_n, params.r, params.p, KEY_SIZE)
+            .map(Self)
+            .map_err(|err| error::Error::Other(format!("invalid scrypt parameters: {}", err)))
+    }
+
+    pub fn hash(&self, payload: &[u8]) -> Key {
+        let mut key = Key::default();
+        self.derive(payload, &[], &mut key);
+        key
+    }
+
+    /// Like `hash`, with a salt, for keys derived from a passphrase that is not itself a key.
+    pub fn hash_salted(&self, payload: &[u8], salt: &[u8]) -> Key {
+        let mut key = Key::default();
+        self.derive(payload, salt, &mut key);
+        key
+    }
+
+    fn derive(&self, payload: &[u8], salt: &[u8], out: &mut [u8]) {
+        scrypt::scrypt(payload, salt, &self.0, out)
+            .expect("invalid keysize buffer, use constant `KEY_SIZE`");
+    }
+}
+
+/// The key `keygen --password` derives from `password`.
+pub fn password_key(password: &[u8]) -> Key {
+    Hash::default().hash(password)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hex;
+
+    fn derive(password: &str, salt: &str, log_n: u8, r: u32, p: u32) -> Vec<u8> {
+        let hash = Hash(scrypt::Params::new(log_n, r, p, 64).unwrap());
+        let mut out = vec![0_u8; 64];
+        hash.derive(password.as_bytes(), salt.as_bytes(), &mut out);
+        out
+    }
+
+    /// RFC 7914, section 12.
+    #[test]
+    fn scrypt_rfc7914_vectors() {
+        assert_eq!(
+            derive("", "", 4, 1, 1),
+            hex::decode(
+                "77d6576238657b203b19ca42c18a0497f16b4844e3074ae8dfdffa3fede21442\
+                 fcd0069ded0948f8326a753a0fc81f17e8d3e0fb2e0d3628cf35e20c38d18906"
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            derive("password", "NaCl", 10, 8, 16),
+            hex::decode(
+                "fdbabe1c9d3472007856e7190d01e9fe7c6ad7cbc8237830e77376634b373162\
+                 2eaf30d92e22a3886ff109279d9830dac727afb94a83ee6d8360cbdfa2cc0640"
+            )
+            .unwrap()
+        );
+        assert_eq!(
+            derive("pleaseletmein", "SodiumChloride", 14, 8, 1),
+            hex::decode(
+                "7023bdcb3afd7348461c06cd81fd38ebfda8fbba904f8e3ea9b543f6545da1f2\
+                 d5432955613f0fcf62d49705242a9af9e61e85dc0d651e40dfcf017b45575887"
+            )
+            .unwrap()
+        );
+    }
+
+    /// The last RFC 7914 vector, N = 2^20 takes a GiB of memory.
+    #[test]
+    #[ignore]
+    fn scrypt_rfc7914_large_vector() {
+        assert_eq!(
+            derive("pleaseletmein", "SodiumChloride", 20, 8, 1),
+            hex::decode(
+                "2101cb9b6a511aaeaddbbe09cf70f881ec568d574a2ffd4dabe5ee9820adaa47\
+                 8e56fd8f4ba5d09ffa1c6d927c40f4c337304049e8a952fbcbf45c6fa77a41a4"
+            )
+            .unwrap()
+        );
+    }
+
+    /// Keys derived from a password must never change, files sealed with them would not open.
+    #[test]
+    fn keygen_password_key() {
+        assert_eq!(
+            password_key(b"password").to_vec(),
+            hex::decode("4b3f8ba176f4247cb1f5758bbeb266fb4c42903d9114c97c5e6a050a4d151385")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn kdf_params() {
+        let cheap = Params {
+            log_n: 4,
+            r: 1,
+            p: 1,
+        };
+        assert_ne!(
+            Hash::new(cheap).unwrap().hash(b"password"),
+            password_key(b"password")
+        );
+        assert!(Hash::new(Params { r: 0, ..cheap }).is_err());
+        assert!(Hash::new(Params { log_n: 64, ..cheap }).is_err());
+    }
+}
@@ -0,0 +1,369 @@
+//! Named keys, each in a JSON file of its own in the keystore directory. A key is sealed with
+//! `Cipher` under a key derived from the master passphrase by scrypt, with a salt of its own, and
+//! its entry authenticates its name and id along.
+
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    crypto::{self, block::Block, cipher::Cipher, Key, BLOCK_SIZE, IV_SIZE, KEY_SIZE},
+    error::{self, Location},
+    hex,
+    kdf::{self, Hash},
+};
+
+/// The version keystore entries are written with.
+pub const VERSION: u8 = 1;
+
+const SALT_SIZE: usize = 16;
+
+/// A key of the keystore, as stored. Only `open` needs the passphrase.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Entry {
+    pub version: u8,
+    pub name: String,
+    /// `crypto::fingerprint` of the key, recorded in the header of the files sealed with it
+    pub id: String,
+    /// how the passphrase is derived into the key sealing this one
+    pub kdf: kdf::Params,
+    salt: String,
+    nonce: String,
+    /// the key, then the tag
+    sealed: String,
+}
+
+impl Entry {
+    fn seal(name: &str, key: &Key, passphrase: &[u8], params: kdf::Params) -> error::Result<Self> {
+        let mut salt = [0_u8; SALT_SIZE];
+        rand::thread_rng().fill_bytes(&mut salt);
+        let iv = Block::new_iv();
+        let mut entry = Self {
+            version: VERSION,
+            name: name.to_string(),
+            id: hex::encode(&crypto::fingerprint(key)),
+            kdf: params,
+            salt: hex::encode(&salt),
+            nonce: hex::encode(iv.iv_bytes()),
+            sealed: String::new(),
+        };
+
+        let mut sealed = key.to_vec();
+        let mut cipher = entry.cipher(passphrase)?;
+        cipher.encrypt_blocks_inplace(&mut sealed)?;
+        sealed.extend_from_slice(cipher.tag().bytes());
+        entry.sealed = hex::encode(&sealed);
+        Ok(entry)
+    }
+
+    /// The key, `None` with a wrong passphrase or a tampered entry.
+    pub fn open(&self, passphrase: &[u8]) -> error::Result<Option<Key>> {
+        let sealed = hex::decode(&self.sealed)
+            .filter(|sealed| sealed.len() == KEY_SIZE + BLOCK_SIZE)
+            .ok_or_else(|| invalid_entry(&self.name))?;
+        let (sealed, tag) = sealed.split_at(KEY_SIZE);
+
+        let mut key = Key::default();
+        key.copy_from_slice(sealed);
+        let mut cipher = self.cipher(passphrase)?;
+        cipher.decrypt_blocks_inplace(&mut key)?;
+        if cipher.tag().bytes() != tag {
+            return Ok(None);
+        }
+
+        Ok(Some(key))
+    }
+
+    fn cipher(&self, passphrase: &[u8]) -> error::Result<Cipher> {
+        let salt = hex::decode(&self.salt).ok_or_else(|| invalid_entry(&self.name))?;
+        let mut iv = Block::default();
+        match hex::decode(&self.nonce) {
+            Some(nonce) if nonce.len() == IV_SIZE => {
+                iv.bytes_mut()[..IV_SIZE].copy_from_slice(&nonce)
+            }
+            _ => return Err(invalid_entry(&self.name)),
+        }
+
+        let key = Hash::new(self.kdf)?.hash_salted(passphrase, &salt);
+        Ok(Cipher::new(key, iv, &self.aad()))
+    }
+
+    /// a renamed entry, or one given another id, does not open
+    fn aad(&self) -> Vec<u8> {
+        let mut aad = b"file-encryptor keystore".to_vec();
+        aad.push(self.version);
+        aad.extend_from_slice(self.name.as_bytes());
+        aad.push(0);
+        aad.extend_from_slice(self.id.as_bytes());
+        aad
+    }
+}
+
+pub struct Keystore {
+    dir: PathBuf,
+}
+
+/// The entries of a keystore, see `Keystore::list`.
+#[derive(Debug, Default)]
+pub struct Listing {
+    /// by name
+    pub entries: Vec<Entry>,
+    /// why each `*.json` file that is not a valid entry was left out, naming the file
+    pub skipped: Vec<error::Error>,
+}
+
+impl Keystore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// `$XDG_DATA_HOME/file-encryptor/keys` (`~/.local/share` when unset), `None` when there is
+    /// neither `$XDG_DATA_HOME` nor `$HOME`.
+    pub fn default_dir() -> Option<PathBuf> {
+        let data_dir = env::var_os("XDG_DATA_HOME")
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))?;
+        Some(data_dir.join("file-encryptor").join("keys"))
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", name))
+    }
+
+    /// Every entry. A keystore that does not exist yet is empty, a malformed entry is skipped
+    /// rather than hiding the others.
+    pub fn list(&self) -> error::Result<Listing> {
+        let dir = match fs::read_dir(&self.dir) {
+            Ok(dir) => dir,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Listing::default()),
+            Err(err) => return Err(error::Error::io(err, &self.dir)),
+        };
+
+        let mut listing = Listing::default();
+        for file in dir {
+            let path = file.map_err(|err| error::Error::io(err, &self.dir))?.path();
+            if path.extension() != Some("json".as_ref()) {
+                continue;
+            }
+
+            let name = path
+                .file_stem()
+                .and_then(|name| name.to_str())
+                .unwrap_or("");
+            match self.entry(name) {
+                Ok(entry) => listing.entries.push(entry),
+                Err(error::Error::Other(msg)) => listing
+                    .skipped
+                    .push(error::Error::Other(format!("{}: {}", path.display(), msg))),
+                Err(err) => listing.skipped.push(err.with_path(&path)),
+            }
+        }
+        listing.entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(listing)
+    }
+
+    pub fn entry(&self, name: &str) -> error::Result<Entry> {
+        check_name(name)?;
+        let path = self.path(name);
+        let json = match fs::read(&path) {
+            Ok(json) => json,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(error::Error::Other(format!(
+                    "no key `{}` in the keystore at {}",
+                    name,
+                    self.dir.display()
+                )))
+            }
+            Err(err) => return Err(error::Error::io(err, &path)),
+        };
+
+        match serde_json::from_slice::<Entry>(&json) {
+            Ok(entry) if entry.version == VERSION && entry.name == name => Ok(entry),
+            Ok(entry) if entry.version != VERSION => Err(error::Error::UnsupportedVersion(
+                entry.version,
+                Location::path(&path),
+            )),
+            _ => Err(invalid_entry(name)),
+        }
+    }
+
+    /// Stores `key` as `name`, which must be new. The passphrase must be the one of the keys
+    /// already stored, if any.
+    pub fn add(&self, name: &str, key: &Key, passphrase: &[u8]) -> error::Result<Entry> {
+        check_name(name)?;
+        if let Some(stored) = self.list()?.entries.first() {
+            if stored.open(passphrase)?.is_none() {
+                return Err(wrong_passphrase());
+            }
+        }
+
+        let entry = Entry::seal(name, key, passphrase, kdf::Params::default())?;
+        let json = serde_json::to_vec_pretty(&entry).expect("plain data always serializes");
+
+        create_dir(&self.dir).map_err(|err| error::Error::io(err, &self.dir))?;
+        let path = self.path(name);
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&path).map_err(|err| match err.kind() {
+            io::ErrorKind::AlreadyExists => {
+                error::Error::Other(format!("the keystore already has a key `{}`", name))
+            }
+            _ => error::Error::io(err, &path),
+        })?;
+        file.write_all(&json)
+            .and_then(|()| file.sync_all())
+            .map_err(|err| error::Error::io(err, &path))?;
+
+        Ok(entry)
+    }
+
+    /// The key stored as `name`.
+    pub fn key(&self, name: &str, passphrase: &[u8]) -> error::Result<Key> {
+        self.entry(name)?
+            .open(passphrase)?
+            .ok_or_else(wrong_passphrase)
+    }
+
+    pub fn remove(&self, name: &str) -> error::Result<()> {
+        self.entry(name)?;
+        let path = self.path(name);
+        fs::remove_file(&path).map_err(|err| error::Error::io(err, &path))
+    }
+}
+
+/// Key names are file names: letters, digits, `-`, `_` and `.`, not starting with a `.`.
+fn check_name(name: &str) -> error::Result<()> {
+    let valid = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+    if !valid || name.is_empty() || name.len() > 64 || name.starts_with('.') {
+        return Err(error::Error::Other(format!(
+            "invalid key name `{}`, expected up to 64 letters, digits, `-`, `_` and `.`",
+            name
+        )));
+    }
+
+    Ok(())
+}
+
+fn create_dir(dir: &Path) -> io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(dir)
+}
+
+fn wrong_passphrase() -> error::Error {
+    error::Error::Other(String::from("wrong keystore passphrase"))
+}
+
+fn invalid_entry(name: &str) -> error::Error {
+    error::Error::Other(format!("the keystore entry of `{}` is malformed", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keystore_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Keystore::new(dir.path().join("keys"));
+        assert!(store.list().unwrap().entries.is_empty());
+
+        let entry = store.add("prod-backups", &[1; 32], b"secret").unwrap();
+        assert_eq!(entry.id, hex::encode(&crypto::fingerprint(&[1; 32])));
+        store.add("dev", &[2; 32], b"secret").unwrap();
+        assert_eq!(store.key("prod-backups", b"secret").unwrap(), [1; 32]);
+
+        let names: Vec<_> = store
+            .list()
+            .unwrap()
+            .entries
+            .into_iter()
+            .map(|e| e.name)
+            .collect();
+        assert_eq!(names, ["dev", "prod-backups"]);
+
+        assert!(store.key("prod-backups", b"wrong").is_err());
+        assert!(store.add("other", &[3; 32], b"wrong").is_err());
+        assert!(store.add("dev", &[3; 32], b"secret").is_err());
+        assert!(store.add("../escape", &[3; 32], b"secret").is_err());
+        assert!(store.key("missing", b"secret").is_err());
+
+        store.remove("dev").unwrap();
+        assert!(store.remove("dev").is_err());
+        assert_eq!(store.list().unwrap().entries.len(), 1);
+    }
+
+    #[test]
+    fn keystore_list_skips_bad_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Keystore::new(dir.path());
+        store.add("good", &[1; 32], b"secret").unwrap();
+        fs::write(dir.path().join("garbage.json"), b"{ not json").unwrap();
+        fs::write(dir.path().join("..json"), b"{}").unwrap();
+        fs::write(dir.path().join("notes.txt"), b"not an entry").unwrap();
+
+        let listing = store.list().unwrap();
+        let names: Vec<_> = listing.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["good"]);
+        assert_eq!(listing.skipped.len(), 2);
+        assert!(listing
+            .skipped
+            .iter()
+            .any(|err| err.to_string().contains("garbage.json")));
+
+        // nor do they keep another key from being added
+        store.add("other", &[2; 32], b"secret").unwrap();
+    }
+
+    #[test]
+    fn keystore_entry_tamper() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Keystore::new(dir.path());
+        let entry = store.add("a", &[1; 32], b"secret").unwrap();
+        assert_eq!(entry.open(b"secret").unwrap(), Some([1; 32]));
+
+        // an entry renamed, or given another id, fails its tag
+        let renamed = Entry {
+            name: String::from("b"),
+            ..entry.clone()
+        };
+        assert_eq!(renamed.open(b"secret").unwrap(), None);
+        let other_id = Entry {
+            id: hex::encode(&crypto::fingerprint(&[2; 32])),
+            ..entry.clone()
+        };
+        assert_eq!(other_id.open(b"secret").unwrap(), None);
+
+        let mut sealed = hex::decode(&entry.sealed).unwrap();
+        sealed[0] ^= 1;
+        let flipped = Entry {
+            sealed: hex::encode(&sealed),
+            ..entry.clone()
+        };
+        assert_eq!(flipped.open(b"secret").unwrap(), None);
+
+        let truncated = Entry {
+            sealed: hex::encode(&sealed[1..]),
+            ..entry
+        };
+        assert!(truncated.open(b"secret").is_err());
+    }
+}
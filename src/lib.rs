@@ -1,4 +1,40 @@
-pub mod error;
+//! Without the default `std` feature, only the `crypto` core, `error` and header parsing
+//! (`format::Header::parse`) are built, over `core` and `alloc`.
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+#[cfg(feature = "age")]
+pub mod age;
+#[cfg(feature = "std")]
+pub mod archive;
+#[cfg(feature = "async")]
+pub mod async_stream;
+#[cfg(feature = "cli")]
 pub mod command;
 pub mod crypto;
+pub mod error;
+pub mod format;
+pub mod hex;
+#[cfg(feature = "std")]
 pub mod ioutils;
+#[cfg(feature = "kdf")]
+pub mod kdf;
+#[cfg(feature = "kdf")]
+pub mod keystore;
+#[cfg(feature = "std")]
+pub mod metadata;
+#[cfg(feature = "std")]
+pub mod mmap;
+#[cfg(feature = "std")]
+pub mod plugin;
+#[cfg(feature = "std")]
+pub mod seekable;
+#[cfg(feature = "std")]
+pub mod stream;
+
+#[cfg(feature = "async")]
+pub use async_stream::{AsyncOpenReader, AsyncSealWriter};
+#[cfg(feature = "std")]
+pub use stream::{OpenReader, SealWriter};
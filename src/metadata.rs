@@ -0,0 +1,213 @@
+//! The original name, mode, mtime and size of a sealed file, kept in a section of their own
+//! between the header and the payload:
+//!
+//! ```text
+//! header (metadata flag and size) | iv | sealed metadata (padded) | metadata tag | payload
+//! ```
+//!
+//! The section is JSON, encrypted and authenticated on its own so that it can be read before the
+//! payload, under the header as additional data and a nonce of its own.
+
+use std::{
+    fs::{self, File, FileTimes},
+    io,
+    path::{Component, Path},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    crypto::{block::Block, cipher::Cipher, pkcs7, Key, BLOCK_SIZE},
+    error::{self, Location},
+    format::Header,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metadata {
+    /// the file name alone, without its directory
+    pub name: Option<String>,
+    /// unix permission bits
+    pub mode: Option<u32>,
+    /// seconds and nanoseconds since the unix epoch
+    pub mtime: Option<(u64, u32)>,
+    pub size: Option<u64>,
+}
+
+impl Metadata {
+    /// The metadata of a regular file. A name that is not valid UTF-8 is left out.
+    pub fn from_file(path: &Path) -> io::Result<Self> {
+        let metadata = fs::metadata(path)?;
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|mtime| (mtime.as_secs(), mtime.subsec_nanos()))
+            .ok();
+
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(metadata.permissions().mode() & 0o7777)
+        };
+        #[cfg(not(unix))]
+        let mode = None;
+
+        Ok(Self {
+            name: path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .map(String::from),
+            mode,
+            mtime,
+            size: Some(metadata.len()),
+        })
+    }
+
+    /// The recorded name, if it is a plain file name: no directory, no `..`, nothing that could
+    /// land outside of the directory it is joined to.
+    pub fn safe_name(&self) -> Option<&str> {
+        let name = self.name.as_deref()?;
+        let mut components = Path::new(name).components();
+        let single =
+            matches!(components.next(), Some(Component::Normal(_))) && components.next().is_none();
+
+        (single && !name.contains(['/', '\\', '\0'])).then_some(name)
+    }
+
+    /// Gives `path` the recorded mode and mtime. The setuid, setgid and sticky bits are never
+    /// restored.
+    pub fn restore(&self, path: &Path) -> io::Result<()> {
+        #[cfg(unix)]
+        if let Some(mode) = self.mode {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o777))?;
+        }
+
+        if let Some((secs, nanos)) = self.mtime {
+            let mtime = SystemTime::UNIX_EPOCH + Duration::new(secs, nanos);
+            File::options()
+                .write(true)
+                .open(path)?
+                .set_times(FileTimes::new().set_modified(mtime))?;
+        }
+
+        Ok(())
+    }
+
+    /// Seals the metadata, and records the size of the section in `header`, which must be final
+    /// otherwise.
+    pub(crate) fn seal(&self, key: Key, header: &mut Header) -> error::Result<Vec<u8>> {
+        let plaintext = serde_json::to_vec(self).expect("plain data always serializes");
+        header.metadata_size = Some(sealed_size(plaintext.len()) as u32);
+        let mut cipher = metadata_cipher(key, header);
+
+        let tail = plaintext.len() % BLOCK_SIZE;
+        let whole = plaintext.len() - tail;
+        let mut sealed = plaintext[..whole].to_vec();
+        cipher.encrypt_blocks_inplace(&mut sealed)?;
+
+        let mut block = Block::default();
+        block.bytes_mut()[..tail].copy_from_slice(&plaintext[whole..]);
+        pkcs7::pad(&mut block, tail);
+        cipher.encrypt_block_inplace(&mut block, tail)?;
+        sealed.extend_from_slice(block.bytes());
+        sealed.extend_from_slice(cipher.tag().bytes());
+
+        Ok(sealed)
+    }
+
+    /// Opens the section sealed by `seal`, `sealed` is exactly `header.metadata_size` bytes.
+    /// Errors have no offset, the caller knows where the section starts.
+    pub(crate) fn open(key: Key, header: &Header, sealed: &[u8]) -> error::Result<Self> {
+        if sealed.len() < 2 * BLOCK_SIZE || !sealed.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(invalid_metadata("invalid metadata section size"));
+        }
+
+        let mut cipher = metadata_cipher(key, header);
+        let (data, tag) = sealed.split_at(sealed.len() - BLOCK_SIZE);
+        let (whole, padded) = data.split_at(data.len() - BLOCK_SIZE);
+        let mut plaintext = whole.to_vec();
+        cipher.decrypt_blocks_inplace(&mut plaintext)?;
+
+        let mut block = Block::default();
+        block.bytes_mut().copy_from_slice(padded);
+        let size = cipher.decrypt_last_block_inplace(&mut block)?;
+        plaintext.extend_from_slice(&block.bytes()[..size]);
+
+        if cipher.tag().bytes() != tag {
+            return Err(error::Error::TagMismatch(Location::default()));
+        }
+
+        serde_json::from_slice(&plaintext)
+            .map_err(|err| invalid_metadata("invalid metadata").caused_by(err))
+    }
+}
+
+fn sealed_size(size: usize) -> usize {
+    (size / BLOCK_SIZE + 1) * BLOCK_SIZE + BLOCK_SIZE
+}
+
+/// The nonce is the file iv with its top bit flipped, which neither the payload (counting up from
+/// the iv) nor a seekable chunk (the iv xor'ed with its index, past the first four bytes) uses.
+fn metadata_cipher(key: Key, header: &Header) -> Cipher {
+    let mut iv = header.iv;
+    iv.bytes_mut()[0] ^= 0x80;
+
+    let mut aad = header.aad();
+    aad.extend_from_slice(b"metadata");
+    header.cipher(key, iv, &aad)
+}
+
+fn invalid_metadata(reason: &'static str) -> error::Error {
+    error::Error::BadHeader(reason, Location::default(), None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::format::Compression;
+
+    #[test]
+    fn metadata_roundtrip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("report.pdf");
+        fs::write(&path, b"%PDF").unwrap();
+
+        let metadata = Metadata::from_file(&path).unwrap();
+        assert_eq!(metadata.name.as_deref(), Some("report.pdf"));
+        assert_eq!(metadata.size, Some(4));
+
+        let key: Key = [1; 32];
+        let mut header = Header::new(Compression::None);
+        header.set_key_check(&key);
+        let sealed = metadata.seal(key, &mut header).unwrap();
+        assert_eq!(header.metadata_size, Some(sealed.len() as u32));
+        assert_eq!(Metadata::open(key, &header, &sealed).unwrap(), metadata);
+
+        assert!(Metadata::open([2; 32], &header, &sealed).is_err());
+        let mut tampered = sealed.clone();
+        tampered[0] ^= 1;
+        assert!(matches!(
+            Metadata::open(key, &header, &tampered),
+            Err(error::Error::TagMismatch(_))
+        ));
+        let mut other = header;
+        other.archive = true;
+        assert!(Metadata::open(key, &other, &sealed).is_err());
+    }
+
+    #[test]
+    fn metadata_safe_name() {
+        let named = |name: &str| Metadata {
+            name: Some(name.to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(named("report.pdf").safe_name(), Some("report.pdf"));
+        assert_eq!(named(".hidden").safe_name(), Some(".hidden"));
+        for name in ["", ".", "..", "../x", "/etc/passwd", "a/b", "a\\b", "x\0"] {
+            assert_eq!(named(name).safe_name(), None, "{:?}", name);
+        }
+        assert_eq!(Metadata::default().safe_name(), None);
+    }
+}
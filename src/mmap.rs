@@ -0,0 +1,381 @@
+//! Sealing and opening regular files through memory maps, the cipher runs directly over the
+//! mapped output. The size of the output has to be known up front, so only uncompressed files
+//! (seekable or not) without metadata are mapped, everything else goes through the stream path.
+//!
+//! A mapped input must not be truncated while it is read, the process gets a `SIGBUS` otherwise.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io,
+    ops::{Deref, DerefMut},
+    path::Path,
+};
+
+use memmap2::{Mmap, MmapMut};
+
+use crate::{
+    crypto::{block::Block, pkcs7, Key, BLOCK_SIZE},
+    error::{self, Location},
+    format::{Compression, Header},
+    seekable,
+    stream::BUFFER_SIZE,
+};
+
+/// Whether `input` and `output` can be mapped: a non empty regular file, and a regular file or
+/// nothing yet.
+pub fn can_map(input: &Path, output: &Path) -> bool {
+    let input = fs::metadata(input).is_ok_and(|metadata| metadata.is_file() && metadata.len() > 0);
+    let output = match fs::metadata(output) {
+        Ok(metadata) => metadata.is_file(),
+        Err(err) => err.kind() == io::ErrorKind::NotFound,
+    };
+
+    input && output
+}
+
+/// Whether a file with this header can be sealed or opened through memory maps. Headerless
+/// (format version 0) files are not, they are not authenticated.
+pub fn supports(header: &Header) -> bool {
+    header.version != 0
+        && header.compression == Compression::None
+        && !header.archive
+        && header.metadata_size.is_none()
+        && header.wrap_size.is_none()
+}
+
+/// Seals `input` into `output`, see `can_map` and `supports`. As with `Sealer`, a key check is
+/// added to `header`. `progress` is called with the
+/// plaintext bytes sealed so far, as for `SealWriter`.
+pub fn seal(
+    input: &Path,
+    output: &Path,
+    key: Key,
+    header: &Header,
+    mut progress: impl FnMut(u64),
+) -> error::Result<()> {
+    debug_assert!(supports(header));
+    let mut header = *header;
+    header.set_key_check(&key);
+    let header = &header;
+    let input = map_input(input)?;
+    let plaintext = &input[..];
+
+    let mut out = Output::create(output, header.size() + sealed_size(header, plaintext.len()))?;
+    let mut header_bytes = Vec::with_capacity(header.size());
+    header.write(&mut header_bytes)?;
+    out[..header.size()].copy_from_slice(&header_bytes);
+    let sealed = &mut out[header.size()..];
+
+    let Some(chunk_size) = header.chunk_size else {
+        let mut cipher = header.cipher(key, header.iv, &header.aad());
+        let tail = plaintext.len() % BLOCK_SIZE;
+        let whole = plaintext.len() - tail;
+        for start in (0..whole).step_by(BUFFER_SIZE) {
+            let end = whole.min(start + BUFFER_SIZE);
+            sealed[start..end].copy_from_slice(&plaintext[start..end]);
+            cipher.encrypt_blocks_inplace(&mut sealed[start..end])?;
+            progress(end as u64);
+        }
+
+        let mut block = Block::default();
+        block.bytes_mut()[..tail].copy_from_slice(&plaintext[whole..]);
+        pkcs7::pad(&mut block, tail);
+        cipher.encrypt_block_inplace(&mut block, tail)?;
+        sealed[whole..whole + BLOCK_SIZE].copy_from_slice(block.bytes());
+        sealed[whole + BLOCK_SIZE..].copy_from_slice(cipher.tag().bytes());
+        progress(plaintext.len() as u64);
+
+        return Ok(out.finish(None)?);
+    };
+
+    // the last chunk is the first one short of `chunk_size`, empty if need be
+    let chunk_size = chunk_size as usize;
+    let mut at = 0;
+    for index in 0.. {
+        let start = index as usize * chunk_size;
+        let size = chunk_size.min(plaintext.len() - start);
+        let last = size < chunk_size;
+
+        let chunk = &mut sealed[at..at + seekable::sealed_size(size, last)];
+        chunk[..size].copy_from_slice(&plaintext[start..start + size]);
+        seekable::seal_chunk(key, header, index, last, chunk, size)?;
+        at += chunk.len();
+        progress((start + size) as u64);
+
+        if last {
+            break;
+        }
+    }
+
+    Ok(out.finish(None)?)
+}
+
+/// Opens `input` into `output`, see `can_map` and `supports`. Like the stream path, the output
+/// holds whatever was decrypted when a tag turns out to be wrong. `progress` is called with the
+/// sealed bytes opened so far, as for `OpenReader`.
+pub fn open(
+    input: &Path,
+    output: &Path,
+    key: Key,
+    mut progress: impl FnMut(u64),
+) -> error::Result<()> {
+    let path = input;
+    let input = map_input(path)?;
+    let truncated =
+        || error::Error::Truncated(Location::offset(input.len() as u64)).with_path(path);
+    let mut sealed = &input[..];
+    let header = Header::read(&mut sealed).map_err(|err| err.with_path(path))?;
+    if header.version == 0 {
+        return Err(error::Error::Unauthenticated(Location::path(path)));
+    }
+    if !supports(&header) {
+        return Err(error::Error::Other(String::from(
+            "only uncompressed files without metadata can be memory mapped",
+        )));
+    }
+    if !header.check_key(&key) {
+        return Err(error::Error::WrongKey(Location::path(path)));
+    }
+
+    let Some(chunk_size) = header.chunk_size else {
+        if sealed.len() < 2 * BLOCK_SIZE || !sealed.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(truncated());
+        }
+
+        let mut out = Output::create(output, sealed.len() - BLOCK_SIZE)?;
+        let mut cipher = header.cipher(key, header.iv, &header.aad());
+        let whole = sealed.len() - 2 * BLOCK_SIZE;
+        for start in (0..whole).step_by(BUFFER_SIZE) {
+            let end = whole.min(start + BUFFER_SIZE);
+            out[start..end].copy_from_slice(&sealed[start..end]);
+            cipher
+                .decrypt_blocks_inplace(&mut out[start..end])
+                .map_err(|err| {
+                    err.with_offset((header.size() + start) as u64)
+                        .with_path(path)
+                })?;
+            progress((header.size() + end) as u64);
+        }
+
+        let mut block = Block::default();
+        block
+            .bytes_mut()
+            .copy_from_slice(&sealed[whole..whole + BLOCK_SIZE]);
+        let size = cipher
+            .decrypt_last_block_inplace(&mut block)
+            .map_err(|err| {
+                err.with_offset((header.size() + whole) as u64)
+                    .with_path(path)
+            })?;
+        out[whole..whole + size].copy_from_slice(&block.bytes()[..size]);
+
+        let valid = cipher.tag().bytes() == &sealed[whole + BLOCK_SIZE..];
+        out.finish(Some(whole + size))?;
+        progress(input.len() as u64);
+
+        return if valid {
+            Ok(())
+        } else {
+            let at = (header.size() + whole + BLOCK_SIZE) as u64;
+            Err(error::Error::TagMismatch(Location::offset(at)).with_path(path))
+        };
+    };
+
+    let stride = chunk_size as usize + BLOCK_SIZE;
+    let chunks = sealed.len().div_ceil(stride);
+    if chunks == 0 {
+        return Err(truncated());
+    }
+
+    // each chunk is opened in place, right where its plaintext goes. its tag spills over the
+    // start of the next one, which overwrites it. one block of slack covers a short last chunk
+    let mut out = Output::create(
+        output,
+        sealed.len() - (chunks - 1) * BLOCK_SIZE + BLOCK_SIZE,
+    )?;
+    let mut size = 0;
+    for (index, chunk) in sealed.chunks(stride).enumerate() {
+        let last = index == chunks - 1;
+        let dst = &mut out[size..size + chunk.len()];
+        dst.copy_from_slice(chunk);
+        let at = (header.size() + index * stride) as u64;
+        size += seekable::open_chunk(key, &header, index as u64, last, dst)
+            .map_err(|err| err.with_offset(at).with_path(path))?;
+        progress((header.size() + index * stride + chunk.len()) as u64);
+    }
+
+    Ok(out.finish(Some(size))?)
+}
+
+/// Size of the sealed data following the header.
+fn sealed_size(header: &Header, size: usize) -> usize {
+    match header.chunk_size {
+        None => (size / BLOCK_SIZE + 1) * BLOCK_SIZE + BLOCK_SIZE,
+        Some(chunk_size) => {
+            let chunk_size = chunk_size as usize;
+            (size / chunk_size) * seekable::sealed_size(chunk_size, false)
+                + seekable::sealed_size(size % chunk_size, true)
+        }
+    }
+}
+
+fn map_input(path: &Path) -> error::Result<Mmap> {
+    let file = File::open(path).map_err(|err| error::Error::io(err, path))?;
+    // SAFETY: the map is only read, and lives as long as the call. see the module doc about
+    // concurrent truncation
+    unsafe { Mmap::map(&file) }.map_err(|err| error::Error::io(err, path))
+}
+
+/// An output file mapped at its final (or largest) size.
+struct Output {
+    file: File,
+    map: MmapMut,
+}
+
+impl Output {
+    fn create(path: &Path, size: usize) -> error::Result<Self> {
+        let mapped = || -> io::Result<Self> {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(path)?;
+            file.set_len(size as u64)?;
+
+            // SAFETY: the file was just truncated and sized by this process, nothing else is
+            // expected to touch it while it is mapped
+            let map = unsafe { MmapMut::map_mut(&file)? };
+            Ok(Self { file, map })
+        };
+
+        mapped().map_err(|err| error::Error::io(err, path))
+    }
+
+    /// Unmaps the file, and truncates it to `size` when the final size is smaller.
+    fn finish(self, size: Option<usize>) -> io::Result<()> {
+        let Self { file, map } = self;
+        drop(map);
+
+        if let Some(size) = size {
+            file.set_len(size as u64)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Deref for Output {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.map
+    }
+}
+
+impl DerefMut for Output {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.map
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::{OpenReader, SealWriter};
+    use rand::RngCore;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn mmap_matches_stream() {
+        let key: Key = [3; 32];
+        let dir = tempfile::tempdir().unwrap();
+        let (plain, sealed, opened) = (
+            dir.path().join("plain"),
+            dir.path().join("sealed"),
+            dir.path().join("opened"),
+        );
+
+        for chunk_size in [None, Some(64)] {
+            for size in [1, 15, 16, 64, 65, 128, 10_000] {
+                let mut plaintext = vec![0_u8; size];
+                rand::thread_rng().fill_bytes(&mut plaintext);
+                fs::write(&plain, &plaintext).unwrap();
+
+                let mut header = Header::new(Compression::None);
+                header.chunk_size = chunk_size;
+                let mut sealed_progress = 0;
+                seal(&plain, &sealed, key, &header, |n| sealed_progress = n).unwrap();
+                assert_eq!(sealed_progress, size as u64);
+
+                // same bytes as the stream path
+                let mut writer = SealWriter::new(Vec::new(), key, header).unwrap();
+                writer.write_all(&plaintext).unwrap();
+                assert_eq!(fs::read(&sealed).unwrap(), writer.finish().unwrap());
+
+                // a longer file is truncated
+                fs::write(&opened, vec![1_u8; 2 * size + 100]).unwrap();
+                let mut opened_progress = 0;
+                open(&sealed, &opened, key, |n| opened_progress = n).unwrap();
+                assert_eq!(opened_progress, fs::metadata(&sealed).unwrap().len());
+                assert_eq!(
+                    fs::read(&opened).unwrap(),
+                    plaintext,
+                    "{:?} {}",
+                    chunk_size,
+                    size
+                );
+
+                let mut streamed = Vec::new();
+                OpenReader::new(File::open(&sealed).unwrap(), key)
+                    .unwrap()
+                    .read_to_end(&mut streamed)
+                    .unwrap();
+                assert_eq!(streamed, plaintext);
+            }
+        }
+    }
+
+    #[test]
+    fn mmap_tamper() {
+        let key: Key = [4; 32];
+        let dir = tempfile::tempdir().unwrap();
+        let (plain, sealed, opened) = (
+            dir.path().join("plain"),
+            dir.path().join("sealed"),
+            dir.path().join("opened"),
+        );
+        fs::write(&plain, vec![9_u8; 1000]).unwrap();
+
+        for chunk_size in [None, Some(64)] {
+            let mut header = Header::new(Compression::None);
+            header.chunk_size = chunk_size;
+            seal(&plain, &sealed, key, &header, |_| {}).unwrap();
+            assert!(matches!(
+                open(&sealed, &opened, [5; 32], |_| {}),
+                Err(error::Error::WrongKey(_))
+            ));
+
+            let mut bytes = fs::read(&sealed).unwrap();
+            let at = bytes.len() - 40;
+            bytes[at] ^= 1;
+            fs::write(&sealed, &bytes).unwrap();
+            let err = open(&sealed, &opened, key, |_| {}).unwrap_err();
+            assert!(matches!(err, error::Error::TagMismatch(_)), "{}", err);
+            assert_eq!(
+                err.location().unwrap().path.as_deref(),
+                Some(sealed.as_path())
+            );
+
+            fs::write(&sealed, &bytes[..bytes.len() - 3]).unwrap();
+            assert!(matches!(
+                open(&sealed, &opened, key, |_| {}),
+                Err(error::Error::Truncated(_))
+            ));
+        }
+
+        assert!(can_map(&plain, &opened));
+        assert!(!can_map(dir.path(), &opened));
+        assert!(!can_map(&plain, dir.path()));
+    }
+}
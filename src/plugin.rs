@@ -0,0 +1,307 @@
+//! Keys wrapped by external programs, so that a KMS, an HSM or any other secret backend can hold
+//! the keys of sealed files without this crate knowing about it.
+//!
+//! A file sealed through the plugin `NAME` is sealed under a random key of its own, which the
+//! plugin wraps: the host runs `file-encryptor-plugin-NAME wrap`, found in `$PATH`, and stores
+//! what it answers in the key wrap section of the header, see `format::Header::wrap_size`.
+//! Opening runs `file-encryptor-plugin-NAME unwrap` on that section. Both talk one line at a
+//! time over stdin and stdout, the first word of a line telling what it holds, bytes in hex:
+//!
+//! ```text
+//! wrap:    > version 1             unwrap:  > version 1
+//!          > recipient RECIPIENT            > identity IDENTITY    (if any)
+//!          > file-key HEX                   > wrapped HEX
+//!          > done                           > done
+//!          < wrapped HEX                    < file-key HEX
+//! ```
+//!
+//! The recipient and identity mean whatever the plugin wants, a key id, a URL... A plugin that
+//! cannot answers `error MESSAGE` instead. When it unwraps, it answers `wrong-identity MESSAGE`
+//! for a key wrapped for another identity, which the host reports as a wrong key, and
+//! `corrupt MESSAGE` for a wrapped key that is malformed or fails its authentication, which the
+//! host reports as a bad header. It ignores the lines it does not know, so that later versions of
+//! the protocol can add some, and its stderr is left to the terminal. Nothing trusts the plugin
+//! with more than the key: a wrong key fails the key check of the header.
+
+use std::{
+    io::{self, Read, Write},
+    process::{Command, Stdio},
+    thread,
+};
+
+use crate::{
+    crypto::{Key, KEY_SIZE},
+    error::{self, Location},
+    format::Header,
+    hex,
+    stream::truncated,
+};
+
+/// The version of the protocol the host speaks, sent first.
+pub const VERSION: u8 = 1;
+
+/// Plugins are programs named with this prefix, then their name.
+pub const PROGRAM_PREFIX: &str = "file-encryptor-plugin-";
+
+/// A stream whose start was read, and put back, see `Section::peek`.
+pub type Peeked<R> = io::Chain<io::Cursor<Vec<u8>>, R>;
+
+/// The key wrap section: the name of the plugin, a newline, then the key as it wrapped it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    pub plugin: String,
+    pub wrapped: Vec<u8>,
+}
+
+impl Section {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.plugin.len() + 1 + self.wrapped.len());
+        bytes.extend_from_slice(self.plugin.as_bytes());
+        bytes.push(b'\n');
+        bytes.extend_from_slice(&self.wrapped);
+        bytes
+    }
+
+    /// Parses a section written by `to_bytes`. Errors have no offset, the caller knows where the
+    /// section starts.
+    pub fn parse(bytes: &[u8]) -> error::Result<Self> {
+        let malformed =
+            || error::Error::BadHeader("malformed key wrap section", Location::default(), None);
+        let newline = bytes
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or_else(malformed)?;
+        let plugin =
+            std::str::from_utf8(&bytes[..newline]).map_err(|err| malformed().caused_by(err))?;
+        check_name(plugin).map_err(|err| malformed().caused_by(err))?;
+
+        Ok(Self {
+            plugin: plugin.to_string(),
+            wrapped: bytes[newline + 1..].to_vec(),
+        })
+    }
+
+    /// Reads the header and key wrap section (if any) at the start of `r`, and hands back a
+    /// reader of the whole stream again, what was read included.
+    pub fn peek<R: Read>(r: R) -> error::Result<(Header, Option<Self>, Peeked<R>)> {
+        let mut recorder = Recorder {
+            inner: r,
+            read: Vec::new(),
+        };
+        let header = Header::read(&mut recorder)?;
+
+        let section = match header.wrap_size {
+            None => None,
+            Some(size) => {
+                let at = recorder.read.len();
+                let mut bytes = vec![0_u8; size as usize];
+                recorder
+                    .read_exact(&mut bytes)
+                    .map_err(|err| match err.kind() {
+                        io::ErrorKind::UnexpectedEof => truncated(recorder.read.len() as u64),
+                        _ => err.into(),
+                    })?;
+                Some(Self::parse(&bytes).map_err(|err| err.with_offset(at as u64))?)
+            }
+        };
+
+        Ok((
+            header,
+            section,
+            io::Cursor::new(recorder.read).chain(recorder.inner),
+        ))
+    }
+}
+
+/// Keeps what it reads, so that it can be read again.
+struct Recorder<R> {
+    inner: R,
+    read: Vec<u8>,
+}
+
+impl<R: Read> Read for Recorder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Has the plugin `plugin` wrap `key` for `recipient`.
+pub fn wrap(plugin: &str, recipient: &str, key: &Key) -> error::Result<Section> {
+    let wrapped = run(
+        plugin,
+        "wrap",
+        &[("recipient", recipient), ("file-key", &hex::encode(key))],
+        "wrapped",
+    )?;
+    if wrapped.is_empty() {
+        return Err(malformed_answer(plugin, "wrapped"));
+    }
+
+    Ok(Section {
+        plugin: plugin.to_string(),
+        wrapped,
+    })
+}
+
+/// Has the plugin that wrapped the key of `section` unwrap it, with `identity` if given.
+pub fn unwrap(section: &Section, identity: Option<&str>) -> error::Result<Key> {
+    let wrapped = hex::encode(&section.wrapped);
+    let mut lines = Vec::with_capacity(2);
+    if let Some(identity) = identity {
+        lines.push(("identity", identity));
+    }
+    lines.push(("wrapped", wrapped.as_str()));
+
+    let answer = run(&section.plugin, "unwrap", &lines, "file-key")?;
+    let mut key = Key::default();
+    if answer.len() != KEY_SIZE {
+        return Err(malformed_answer(&section.plugin, "file-key"));
+    }
+    key.copy_from_slice(&answer);
+    Ok(key)
+}
+
+/// Runs the plugin `plugin` for `phase`, sending it `lines`, and returns the bytes of its answer
+/// line, `answer`.
+fn run(plugin: &str, phase: &str, lines: &[(&str, &str)], answer: &str) -> error::Result<Vec<u8>> {
+    check_name(plugin)?;
+    if lines.iter().any(|(_, value)| value.contains('\n')) {
+        return Err(error::Error::Other(String::from(
+            "a plugin recipient or identity is a single line",
+        )));
+    }
+
+    let program = format!("{}{}", PROGRAM_PREFIX, plugin);
+    let mut child = Command::new(&program)
+        .arg(phase)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::NotFound => {
+                error::Error::Other(format!("plugin `{}` not found in `$PATH`", program))
+            }
+            _ => error::Error::Other(format!("cannot run plugin `{}`: {}", program, err)),
+        })?;
+
+    let mut input = format!("version {}\n", VERSION);
+    for (name, value) in lines {
+        input.push_str(&format!("{} {}\n", name, value));
+    }
+    input.push_str("done\n");
+
+    // written from a thread of its own: the plugin may answer before it read everything, and
+    // block once stdout is full
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let writer = thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child.wait_with_output()?;
+    // a plugin may give up before reading everything, what it answers tells why
+    match writer.join().expect("writing stdin does not panic") {
+        Err(err) if err.kind() != io::ErrorKind::BrokenPipe => return Err(err.into()),
+        _ => {}
+    }
+
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        match line.split_once(' ') {
+            Some((word, data)) if word == answer => {
+                return hex::decode(data.trim()).ok_or_else(|| malformed_answer(plugin, answer))
+            }
+            Some(("error", message)) => {
+                return Err(error::Error::Other(format!(
+                    "plugin `{}`: {}",
+                    plugin,
+                    message.trim()
+                )))
+            }
+            Some(("wrong-identity", _)) if phase == "unwrap" => {
+                return Err(error::Error::WrongKey(Location::default()))
+            }
+            // the section, not the identity or the plugin, is at fault
+            Some(("corrupt", message)) if phase == "unwrap" => {
+                let reason = "the key wrap plugin found the wrapped key corrupted";
+                return Err(error::Error::BadHeader(reason, Location::default(), None)
+                    .caused_by(format!("plugin `{}`: {}", plugin, message.trim())));
+            }
+            _ => continue,
+        }
+    }
+
+    Err(error::Error::Other(match output.status.success() {
+        true => format!("plugin `{}` did not answer `{}`", plugin, answer),
+        false => format!("plugin `{}` failed, {}", plugin, output.status),
+    }))
+}
+
+/// Plugin names are part of a program name: letters, digits, `-` and `_`, up to 64.
+fn check_name(name: &str) -> error::Result<()> {
+    let valid = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_'));
+    if !valid || name.is_empty() || name.len() > 64 {
+        return Err(error::Error::Other(format!(
+            "invalid plugin name `{}`, expected up to 64 letters, digits, `-` and `_`",
+            name
+        )));
+    }
+
+    Ok(())
+}
+
+fn malformed_answer(plugin: &str, answer: &str) -> error::Error {
+    error::Error::Other(format!(
+        "plugin `{}` answered a malformed `{}`",
+        plugin, answer
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{format::Compression, stream::SealWriter};
+    use std::io::Write;
+
+    #[test]
+    fn plugin_section() {
+        let section = Section {
+            plugin: String::from("kms"),
+            wrapped: b"opaque\nbytes".to_vec(),
+        };
+        assert_eq!(Section::parse(&section.to_bytes()).unwrap(), section);
+        assert!(Section::parse(b"no newline").is_err());
+        assert!(Section::parse(b"../kms\nbytes").is_err());
+
+        let mut writer = SealWriter::with_wrap(
+            Vec::new(),
+            [1; 32],
+            Header::new(Compression::None),
+            None,
+            &section.to_bytes(),
+        )
+        .unwrap();
+        writer.write_all(b"plaintext").unwrap();
+        let sealed = writer.finish().unwrap();
+
+        let (header, peeked, mut stream) = Section::peek(sealed.as_slice()).unwrap();
+        assert_eq!(header.wrap_size, Some(section.to_bytes().len() as u32));
+        assert_eq!(peeked, Some(section));
+        let mut whole = Vec::new();
+        stream.read_to_end(&mut whole).unwrap();
+        assert_eq!(whole, sealed);
+
+        let mut plaintext = Vec::new();
+        crate::OpenReader::new(whole.as_slice(), [1; 32])
+            .unwrap()
+            .read_to_end(&mut plaintext)
+            .unwrap();
+        assert_eq!(plaintext, b"plaintext");
+
+        let at = header.size() - 5;
+        assert!(matches!(
+            Section::peek(&sealed[..at]),
+            Err(error::Error::Truncated(_))
+        ));
+    }
+}
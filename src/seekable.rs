@@ -0,0 +1,417 @@
+//! The seekable format splits the plaintext in chunks of `chunk_size` bytes, each one encrypted
+//! and authenticated on its own:
+//!
+//! ```text
+//! header | chunk 0 | tag 0 | chunk 1 | tag 1 | ... | last chunk (padded) | last tag
+//! ```
+//!
+//! Every chunk but the last holds exactly `chunk_size` bytes, so the chunk size doubles as the
+//! index: chunk `i` starts at `header.size() + i * (chunk_size + BLOCK_SIZE)`. The last chunk
+//! holds less than `chunk_size` bytes (possibly none) and is pkcs7 padded.
+//!
+//! Each chunk has its own iv, the file iv xor'ed with the chunk index, and authenticates the
+//! header, its index and whether it is the last one, so chunks cannot be reordered, dropped or
+//! moved between files.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::{
+    crypto::{block::Block, cipher::Cipher, pkcs7, Key, BLOCK_SIZE, IV_SIZE},
+    error::{self, Location},
+    format::Header,
+    ioutils,
+    metadata::Metadata,
+};
+
+pub const DEFAULT_CHUNK_SIZE: u32 = 64 * 1024;
+
+fn chunk_cipher(key: Key, header: &Header, index: u64, last: bool) -> Cipher {
+    let mut iv = header.iv;
+    iv.bytes_mut()[IV_SIZE - 8..IV_SIZE]
+        .iter_mut()
+        .zip(index.to_be_bytes())
+        .for_each(|(byte, i)| *byte ^= i);
+
+    let mut aad = header.aad();
+    aad.extend_from_slice(&index.to_be_bytes());
+    aad.push(last as u8);
+
+    header.cipher(key, iv, &aad)
+}
+
+fn truncated(offset: u64) -> error::Error {
+    error::Error::Truncated(Location::offset(offset))
+}
+
+/// Size of a sealed chunk holding `size` bytes of plaintext, its padding and tag included.
+pub fn sealed_size(size: usize, last: bool) -> usize {
+    if last {
+        (size / BLOCK_SIZE + 1) * BLOCK_SIZE + BLOCK_SIZE
+    } else {
+        size + BLOCK_SIZE
+    }
+}
+
+/// Encrypts the `size` bytes of plaintext starting `chunk` in place, padding them if it is the
+/// last chunk, followed by the tag. `chunk` holds `sealed_size(size, last)` bytes.
+pub fn seal_chunk(
+    key: Key,
+    header: &Header,
+    index: u64,
+    last: bool,
+    chunk: &mut [u8],
+    size: usize,
+) -> error::Result<()> {
+    debug_assert_eq!(chunk.len(), sealed_size(size, last));
+    let mut cipher = chunk_cipher(key, header, index, last);
+
+    // only the last chunk may end with a partial (or empty) block
+    let tail = size % BLOCK_SIZE;
+    let whole = size - tail;
+    debug_assert!(last || tail == 0, "partial block in a chunk");
+    cipher.encrypt_blocks_inplace(&mut chunk[..whole])?;
+
+    let mut at = whole;
+    if last {
+        let mut block = Block::default();
+        block.bytes_mut()[..tail].copy_from_slice(&chunk[whole..size]);
+        pkcs7::pad(&mut block, tail);
+        cipher.encrypt_block_inplace(&mut block, tail)?;
+        chunk[at..at + BLOCK_SIZE].copy_from_slice(block.bytes());
+        at += BLOCK_SIZE;
+    }
+
+    chunk[at..].copy_from_slice(cipher.tag().bytes());
+    Ok(())
+}
+
+/// Decrypts a chunk in place and checks its tag, returns the size of its plaintext, which starts
+/// the chunk. Errors have no offset, it is up to the caller to place them at the chunk.
+pub fn open_chunk(
+    key: Key,
+    header: &Header,
+    index: u64,
+    last: bool,
+    chunk: &mut [u8],
+) -> error::Result<usize> {
+    let chunk_size = header.chunk_size.expect("not a seekable header") as usize;
+    let valid = if last {
+        chunk.len() >= 2 * BLOCK_SIZE && chunk.len() <= chunk_size + BLOCK_SIZE
+    } else {
+        chunk.len() == chunk_size + BLOCK_SIZE
+    };
+    if !valid || !chunk.len().is_multiple_of(BLOCK_SIZE) {
+        return Err(error::Error::Truncated(Location::default()));
+    }
+
+    let mut cipher = chunk_cipher(key, header, index, last);
+    let (data, tag) = chunk.split_at_mut(chunk.len() - BLOCK_SIZE);
+
+    let size = if last {
+        let (whole, padded) = data.split_at_mut(data.len() - BLOCK_SIZE);
+        cipher.decrypt_blocks_inplace(whole)?;
+
+        let mut block = Block::default();
+        block.bytes_mut().copy_from_slice(padded);
+        let size = cipher.decrypt_last_block_inplace(&mut block)?;
+        padded.copy_from_slice(block.bytes());
+
+        whole.len() + size
+    } else {
+        cipher.decrypt_blocks_inplace(data)?;
+        data.len()
+    };
+
+    if cipher.tag().bytes() != tag {
+        return Err(error::Error::TagMismatch(Location::default()));
+    }
+
+    Ok(size)
+}
+
+/// Reads the plaintext of a seekable sealed file, decrypting only the chunks that are read.
+pub struct SeekableOpenReader<R> {
+    inner: R,
+    key: Key,
+    header: Header,
+    metadata: Option<Metadata>,
+    chunk_size: u64,
+    chunks: u64,
+    len: u64,
+    pos: u64,
+    chunk: Vec<u8>,
+    chunk_index: Option<u64>,
+}
+
+impl<R: Read + Seek> SeekableOpenReader<R> {
+    /// Reads the header, and authenticates the last chunk to learn the plaintext length.
+    pub fn new(mut inner: R, key: Key) -> error::Result<Self> {
+        inner.seek(SeekFrom::Start(0))?;
+        let header = Header::read(&mut inner)?;
+        if !header.check_key(&key) {
+            return Err(error::Error::WrongKey(Location::default()));
+        }
+        let metadata = match header.metadata_size {
+            None => None,
+            Some(size) => {
+                let at = (header.size() - size as usize) as u64;
+                inner.seek(SeekFrom::Start(at))?;
+                let mut sealed = vec![0_u8; size as usize];
+                let n = ioutils::read_full(&mut inner, &mut sealed)?;
+                if n < sealed.len() {
+                    return Err(truncated(at + n as u64));
+                }
+                let metadata =
+                    Metadata::open(key, &header, &sealed).map_err(|err| err.with_offset(at))?;
+                Some(metadata)
+            }
+        };
+        let Some(chunk_size) = header.chunk_size else {
+            return Err(error::Error::Other(String::from(
+                "not a seekable file, seal it with `--seekable`",
+            )));
+        };
+
+        let chunk_size = chunk_size as u64;
+        let file_len = inner.seek(SeekFrom::End(0))?;
+        let data_len = file_len
+            .checked_sub(header.size() as u64)
+            .ok_or_else(|| truncated(file_len))?;
+        let stride = chunk_size + BLOCK_SIZE as u64;
+        let chunks = data_len.div_ceil(stride);
+        if chunks == 0 {
+            return Err(truncated(file_len));
+        }
+
+        let mut reader = Self {
+            inner,
+            key,
+            header,
+            metadata,
+            chunk_size,
+            chunks,
+            len: 0,
+            pos: 0,
+            chunk: Vec::new(),
+            chunk_index: None,
+        };
+
+        reader.load(chunks - 1)?;
+        reader.len = (chunks - 1) * chunk_size + reader.chunk.len() as u64;
+
+        Ok(reader)
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// The metadata sealed along with the file, if any.
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Length of the plaintext.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn load(&mut self, index: u64) -> error::Result<()> {
+        if self.chunk_index == Some(index) {
+            return Ok(());
+        }
+        self.chunk_index = None;
+
+        let stride = self.chunk_size + BLOCK_SIZE as u64;
+        let at = self.header.size() as u64 + index * stride;
+        self.inner.seek(SeekFrom::Start(at))?;
+
+        self.chunk.resize(stride as usize, 0);
+        let size = ioutils::read_full(&mut self.inner, &mut self.chunk)?;
+        self.chunk.truncate(size);
+
+        let last = index == self.chunks - 1;
+        let size = open_chunk(self.key, &self.header, index, last, &mut self.chunk)
+            .map_err(|err| err.with_offset(at))?;
+        self.chunk.truncate(size);
+        self.chunk_index = Some(index);
+
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Read for SeekableOpenReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        self.load(self.pos / self.chunk_size)?;
+
+        let start = (self.pos % self.chunk_size) as usize;
+        let n = buf.len().min(self.chunk.len() - start);
+        buf[..n].copy_from_slice(&self.chunk[start..start + n]);
+        self.pos += n as u64;
+
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for SeekableOpenReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+
+        self.pos = pos.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )
+        })?;
+
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        format::Compression,
+        stream::{OpenReader, SealWriter},
+    };
+    use rand::{Rng, RngCore};
+    use std::io::{Cursor, Write};
+
+    const CHUNK_SIZE: u32 = 64;
+
+    fn seal_bytes(key: Key, plaintext: &[u8]) -> Vec<u8> {
+        let mut header = Header::new(Compression::None);
+        header.chunk_size = Some(CHUNK_SIZE);
+
+        let mut writer = SealWriter::new(Vec::new(), key, header).unwrap();
+        writer.write_all(plaintext).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn open_bytes(key: Key, sealed: &[u8]) -> error::Result<Vec<u8>> {
+        let mut opened = Vec::new();
+        OpenReader::new(sealed, key)?.read_to_end(&mut opened)?;
+        Ok(opened)
+    }
+
+    #[test]
+    fn seekable_roundtrip() {
+        let key: Key = [3; 32];
+        let chunk = CHUNK_SIZE as usize;
+        for size in [
+            0,
+            1,
+            15,
+            16,
+            chunk - 1,
+            chunk,
+            chunk + 1,
+            3 * chunk + 5,
+            4 * chunk,
+        ] {
+            let mut plaintext = vec![0_u8; size];
+            rand::thread_rng().fill_bytes(&mut plaintext);
+
+            let sealed = seal_bytes(key, &plaintext);
+            assert_eq!(
+                open_bytes(key, &sealed).unwrap(),
+                plaintext,
+                "size {}",
+                size
+            );
+
+            let mut reader = SeekableOpenReader::new(Cursor::new(&sealed), key).unwrap();
+            assert_eq!(reader.len(), size as u64);
+            let mut opened = Vec::new();
+            reader.read_to_end(&mut opened).unwrap();
+            assert_eq!(opened, plaintext, "size {}", size);
+        }
+    }
+
+    #[test]
+    fn seekable_random_access() {
+        let key: Key = [5; 32];
+        let mut plaintext = vec![0_u8; 10 * CHUNK_SIZE as usize + 7];
+        rand::thread_rng().fill_bytes(&mut plaintext);
+        let sealed = seal_bytes(key, &plaintext);
+
+        let mut reader = SeekableOpenReader::new(Cursor::new(&sealed), key).unwrap();
+        let mut rng = rand::thread_rng();
+        for _ in 0..100 {
+            let offset = rng.gen_range(0..plaintext.len());
+            let length = rng.gen_range(0..200);
+            reader.seek(SeekFrom::Start(offset as u64)).unwrap();
+
+            let mut buf = Vec::new();
+            (&mut reader).take(length).read_to_end(&mut buf).unwrap();
+            let end = plaintext.len().min(offset + length as usize);
+            assert_eq!(buf, &plaintext[offset..end]);
+        }
+
+        assert_eq!(
+            reader.seek(SeekFrom::End(-3)).unwrap(),
+            plaintext.len() as u64 - 3
+        );
+        assert!(reader
+            .seek(SeekFrom::Current(-(plaintext.len() as i64)))
+            .is_err());
+    }
+
+    #[test]
+    fn seekable_tamper() {
+        let key: Key = [7; 32];
+        let chunk = CHUNK_SIZE as usize;
+        let plaintext = vec![42_u8; 3 * chunk + 5];
+        let sealed = seal_bytes(key, &plaintext);
+        let header_size = sealed.len() - 3 * (chunk + BLOCK_SIZE) - 2 * BLOCK_SIZE;
+
+        // a flipped bit only spoils its own chunk for random access
+        let mut tampered = sealed.clone();
+        tampered[header_size + chunk + BLOCK_SIZE + 3] ^= 1;
+        let at = Some((header_size + chunk + BLOCK_SIZE) as u64);
+        let err = open_bytes(key, &tampered).unwrap_err();
+        assert!(
+            matches!(err, error::Error::TagMismatch(ref l) if l.offset == at),
+            "{}",
+            err
+        );
+        let mut reader = SeekableOpenReader::new(Cursor::new(&tampered), key).unwrap();
+        let mut buf = vec![0_u8; chunk];
+        reader.read_exact(&mut buf).unwrap();
+        let err = error::Error::from(reader.read_exact(&mut buf).unwrap_err());
+        assert!(
+            matches!(err, error::Error::TagMismatch(ref l) if l.offset == at),
+            "{}",
+            err
+        );
+
+        // dropping the last chunk, or swapping two, fails too
+        let truncated = &sealed[..sealed.len() - 2 * BLOCK_SIZE];
+        assert!(open_bytes(key, truncated).is_err());
+        assert!(SeekableOpenReader::new(Cursor::new(truncated), key).is_err());
+
+        let mut swapped = sealed.clone();
+        let (first, second) = swapped[header_size..].split_at_mut(chunk + BLOCK_SIZE);
+        first.swap_with_slice(&mut second[..chunk + BLOCK_SIZE]);
+        assert!(open_bytes(key, &swapped).is_err());
+
+        assert!(open_bytes([8; 32], &sealed).is_err());
+        assert!(matches!(
+            SeekableOpenReader::new(Cursor::new(&sealed), [8; 32]),
+            Err(error::Error::WrongKey(_))
+        ));
+    }
+}
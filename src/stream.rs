@@ -0,0 +1,1108 @@
+//! Streaming seal and open, without any file or terminal attached.
+//!
+//! `Sealer` and `Opener` do the actual work on byte slices, compression included, they never
+//! read or write anything themselves. `SealWriter` and `OpenReader` wrap them as `std::io`
+//! adapters:
+//!
+//! ```no_run
+//! use std::io::{self, Write};
+//! use file_encryptor::{format::{Compression, Header}, OpenReader, SealWriter};
+//!
+//! # fn main() -> file_encryptor::error::Result<()> {
+//! let key = [0_u8; 32];
+//! let mut sealer = SealWriter::new(Vec::new(), key, Header::new(Compression::Zstd))?;
+//! sealer.write_all(b"hello")?;
+//! let sealed = sealer.finish()?;
+//!
+//! let mut opener = OpenReader::new(sealed.as_slice(), key)?;
+//! io::copy(&mut opener, &mut io::stdout())?;
+//! # Ok(())
+//! # }
+//! ```
+
+use std::{
+    io::{self, Read, Write},
+    mem,
+};
+
+use crate::{
+    crypto::{block::Block, cipher::Cipher, pkcs7, Key, BLOCK_SIZE},
+    error::{self, Location},
+    format::{Compression, Header, MAX_WRAP_SIZE},
+    metadata::Metadata,
+    seekable,
+};
+
+/// How much the readers and writers of this crate move at once, large enough that the syscalls
+/// cost next to nothing.
+pub const BUFFER_SIZE: usize = 256 * 1024;
+
+/// A progress callback, called with how many input bytes were processed so far.
+pub type Progress = Box<dyn FnMut(u64) + Send>;
+
+pub(crate) fn truncated(offset: u64) -> error::Error {
+    error::Error::Truncated(Location::offset(offset))
+}
+
+/// Sealed data that fails to decompress was tampered with, its tag could not match.
+fn corrupted(offset: u64) -> error::Error {
+    error::Error::TagMismatch(Location::offset(offset))
+}
+
+/// Turns plaintext into a sealed stream, header included.
+pub struct Sealer {
+    key: Key,
+    header: Header,
+    compressor: Option<Compressor>,
+    /// `None` for a seekable stream, every chunk has a cipher of its own
+    cipher: Option<Cipher>,
+    index: u64,
+    buf: Vec<u8>,
+    /// the header, key wrap section and sealed metadata, until written out
+    preamble: Option<Vec<u8>>,
+}
+
+impl Sealer {
+    /// Seals under `header`, with a key check added to it, see `Header::set_key_check`.
+    pub fn new(key: Key, header: Header) -> error::Result<Self> {
+        Self::build(key, header, None, &[])
+    }
+
+    /// Same as `new`, with a sealed metadata section, see `metadata`.
+    pub fn with_metadata(key: Key, header: Header, metadata: &Metadata) -> error::Result<Self> {
+        Self::build(key, header, Some(metadata), &[])
+    }
+
+    /// Same as `with_metadata`, metadata optional, with a key wrap section after the header, see
+    /// `plugin`. Its size is recorded in `header.wrap_size`.
+    pub fn with_wrap(
+        key: Key,
+        header: Header,
+        metadata: Option<&Metadata>,
+        wrap: &[u8],
+    ) -> error::Result<Self> {
+        if wrap.len() > MAX_WRAP_SIZE as usize {
+            return Err(error::Error::Other(String::from(
+                "key wrap section too large",
+            )));
+        }
+        Self::build(key, header, metadata, wrap)
+    }
+
+    fn build(
+        key: Key,
+        mut header: Header,
+        metadata: Option<&Metadata>,
+        wrap: &[u8],
+    ) -> error::Result<Self> {
+        header.wrap_size = (!wrap.is_empty()).then_some(wrap.len() as u32);
+        header.set_key_check(&key);
+        let sealed_metadata = metadata
+            .map(|metadata| metadata.seal(key, &mut header))
+            .transpose()?;
+
+        let mut preamble = Vec::with_capacity(header.size());
+        header
+            .write(&mut preamble)
+            .expect("writing to a vec does not fail");
+        preamble.extend_from_slice(wrap);
+        preamble.extend(sealed_metadata.unwrap_or_default());
+
+        let cipher = match header.chunk_size {
+            None => Some(header.cipher(key, header.iv, &header.aad())),
+            Some(_) => None,
+        };
+
+        Ok(Self {
+            key,
+            header,
+            compressor: Compressor::new(header.compression)?,
+            cipher,
+            index: 0,
+            buf: Vec::new(),
+            preamble: Some(preamble),
+        })
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// Seals what it can of `input`, appending the ciphertext to `out`. A partial block (or chunk)
+    /// is held back until more input comes in.
+    pub fn update(&mut self, input: &[u8], out: &mut Vec<u8>) -> error::Result<()> {
+        self.write_header(out);
+
+        let compressed;
+        let input = match &mut self.compressor {
+            None => input,
+            Some(compressor) => {
+                compressor.write_all(input)?;
+                compressed = compressor.take();
+                &compressed
+            }
+        };
+
+        self.seal(input, out)
+    }
+
+    /// Seals the rest of the input, padded, and appends the tag.
+    pub fn finish(mut self, out: &mut Vec<u8>) -> error::Result<()> {
+        self.write_header(out);
+
+        if let Some(compressor) = self.compressor.take() {
+            let compressed = compressor.finish()?;
+            self.seal(&compressed, out)?;
+        }
+
+        match self.cipher.as_mut() {
+            None => {
+                seal_chunk(self.key, &self.header, self.index, true, &self.buf, out)?;
+            }
+            Some(cipher) => {
+                let size = self.buf.len();
+                let mut block = Block::default();
+                block.bytes_mut()[..size].copy_from_slice(&self.buf);
+                pkcs7::pad(&mut block, size);
+
+                cipher.encrypt_block_inplace(&mut block, size)?;
+                out.extend_from_slice(block.bytes());
+                out.extend_from_slice(cipher.tag().bytes());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_header(&mut self, out: &mut Vec<u8>) {
+        if let Some(preamble) = self.preamble.take() {
+            out.extend(preamble);
+        }
+    }
+
+    fn seal(&mut self, input: &[u8], out: &mut Vec<u8>) -> error::Result<()> {
+        self.buf.extend_from_slice(input);
+
+        let Some(cipher) = self.cipher.as_mut() else {
+            // a full chunk is never the last one, that one is shorter (possibly empty)
+            let chunk_size = self.header.chunk_size.expect("seekable without a cipher") as usize;
+            let mut chunks = self.buf.chunks_exact(chunk_size);
+            for chunk in chunks.by_ref() {
+                seal_chunk(self.key, &self.header, self.index, false, chunk, out)?;
+                self.index += 1;
+            }
+
+            let sealed = self.buf.len() - chunks.remainder().len();
+            self.buf.drain(..sealed);
+            return Ok(());
+        };
+
+        let end = self.buf.len() / BLOCK_SIZE * BLOCK_SIZE;
+        let start = out.len();
+        out.extend_from_slice(&self.buf[..end]);
+        cipher.encrypt_blocks_inplace(&mut out[start..])?;
+        self.buf.drain(..end);
+        Ok(())
+    }
+}
+
+/// Appends the sealed chunk of `plaintext` to `out`.
+fn seal_chunk(
+    key: Key,
+    header: &Header,
+    index: u64,
+    last: bool,
+    plaintext: &[u8],
+    out: &mut Vec<u8>,
+) -> error::Result<()> {
+    let start = out.len();
+    out.extend_from_slice(plaintext);
+    out.resize(start + seekable::sealed_size(plaintext.len(), last), 0);
+    seekable::seal_chunk(key, header, index, last, &mut out[start..], plaintext.len())
+}
+
+/// Turns a sealed stream, header included, back into plaintext.
+///
+/// The plaintext is handed out as it is decrypted, but only `finish` checks the tag of a stream
+/// that is not seekable: nothing it produced can be trusted until `finish` succeeded.
+pub struct Opener {
+    key: Key,
+    /// whether headerless (format version 0) streams are opened, see `with_unauthenticated_legacy`
+    legacy: bool,
+    header: Option<Header>,
+    metadata: Option<Metadata>,
+    decompressor: Option<Decompressor>,
+    cipher: Option<Cipher>,
+    index: u64,
+    buf: Vec<u8>,
+    /// where `buf` starts in the stream
+    offset: u64,
+    plain: Vec<u8>,
+}
+
+impl Opener {
+    /// Refuses headerless (format version 0) streams, with `error::Error::Unauthenticated`.
+    pub fn new(key: Key) -> Self {
+        Self {
+            key,
+            legacy: false,
+            header: None,
+            metadata: None,
+            decompressor: None,
+            cipher: None,
+            index: 0,
+            buf: Vec::new(),
+            offset: 0,
+            plain: Vec::new(),
+        }
+    }
+
+    /// Same as `new`, but also opens headerless (format version 0) streams. Their tag cancels out
+    /// the hash of the ciphertext and only covers the lengths: anyone can modify or forge them,
+    /// and `finish` still succeeds.
+    pub fn with_unauthenticated_legacy(key: Key) -> Self {
+        Self {
+            legacy: true,
+            ..Self::new(key)
+        }
+    }
+
+    /// The header, once enough of the stream came in to read it.
+    pub fn header(&self) -> Option<&Header> {
+        self.header.as_ref()
+    }
+
+    /// The metadata, once the header and the metadata section came in, if the stream has any.
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Opens what it can of `input`, appending the plaintext to `out`. The last blocks (or
+    /// chunk) are held back, until `finish` tells they are the last.
+    pub fn update(&mut self, input: &[u8], out: &mut Vec<u8>) -> error::Result<()> {
+        self.buf.extend_from_slice(input);
+        if !self.read_header()? {
+            return Ok(());
+        }
+
+        self.open(false)?;
+        self.decompress(out)
+    }
+
+    /// Opens the end of the stream and checks its tag.
+    pub fn finish(mut self, out: &mut Vec<u8>) -> error::Result<()> {
+        if !self.read_header()? {
+            return Err(truncated(self.buf.len() as u64));
+        }
+
+        self.open(true)?;
+        self.decompress(out)?;
+        if let Some(decompressor) = self.decompressor {
+            let offset = self.offset;
+            out.append(&mut decompressor.finish().map_err(|_| corrupted(offset))?);
+        }
+
+        Ok(())
+    }
+
+    fn read_header(&mut self) -> error::Result<bool> {
+        if self.header.is_some() {
+            return Ok(true);
+        }
+
+        let mut rest = self.buf.as_slice();
+        let header = match Header::read(&mut rest) {
+            Ok(header) => header,
+            Err(error::Error::Truncated(_)) => return Ok(false),
+            Err(err) => return Err(err),
+        };
+        if header.version == 0 && !self.legacy {
+            return Err(error::Error::Unauthenticated(Location::default()));
+        }
+        if !header.check_key(&self.key) {
+            return Err(error::Error::WrongKey(Location::default()));
+        }
+
+        // the key wrap section is for whoever found the key, it is skipped here, and the metadata
+        // is opened along with the header, waiting for all of it if need be
+        let wrap_size = header.wrap_size.unwrap_or(0) as usize;
+        let metadata_size = header.metadata_size.unwrap_or(0) as usize;
+        if rest.len() < wrap_size + metadata_size {
+            return Ok(false);
+        }
+        rest = &rest[wrap_size..];
+        if metadata_size > 0 {
+            let sealed = &rest[..metadata_size];
+            let at = (self.buf.len() - rest.len()) as u64;
+            let metadata =
+                Metadata::open(self.key, &header, sealed).map_err(|err| err.with_offset(at))?;
+            self.metadata = Some(metadata);
+        }
+
+        let size = self.buf.len() - rest.len() + metadata_size;
+        self.buf.drain(..size);
+        self.offset += size as u64;
+        if header.chunk_size.is_none() {
+            self.cipher = Some(header.cipher(self.key, header.iv, &header.aad()));
+        }
+        self.decompressor = Decompressor::new(header.compression)?;
+        self.header = Some(header);
+
+        Ok(true)
+    }
+
+    /// Moves the plaintext decrypted so far to `out`.
+    fn decompress(&mut self, out: &mut Vec<u8>) -> error::Result<()> {
+        match &mut self.decompressor {
+            None => out.append(&mut self.plain),
+            Some(decompressor) => {
+                decompressor
+                    .write_all(&self.plain)
+                    .map_err(|_| corrupted(self.offset))?;
+                self.plain.clear();
+                out.append(&mut decompressor.take());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn open(&mut self, eof: bool) -> error::Result<()> {
+        let header = self.header.as_ref().expect("read above");
+        let out = &mut self.plain;
+
+        let Some(cipher) = self.cipher.as_mut() else {
+            let stride =
+                header.chunk_size.expect("seekable without a cipher") as usize + BLOCK_SIZE;
+
+            // only the end of the stream tells whether a full chunk is the last one
+            let mut start = 0;
+            while self.buf.len() - start > stride || (eof && start < self.buf.len()) {
+                let end = self.buf.len().min(start + stride);
+                let last = eof && end == self.buf.len();
+                let at = self.offset + start as u64;
+                let chunk = &mut self.buf[start..end];
+                let size = seekable::open_chunk(self.key, header, self.index, last, chunk)
+                    .map_err(|err| err.with_offset(at))?;
+                out.extend_from_slice(&chunk[..size]);
+                self.index += 1;
+                start = end;
+            }
+            self.buf.drain(..start);
+            self.offset += start as u64;
+
+            return if eof && self.index == 0 {
+                Err(truncated(self.offset))
+            } else {
+                Ok(())
+            };
+        };
+
+        // the padded last block and the tag
+        let held = 2 * BLOCK_SIZE;
+        if eof && (self.buf.len() < held || !self.buf.len().is_multiple_of(BLOCK_SIZE)) {
+            return Err(truncated(self.offset + self.buf.len() as u64));
+        }
+
+        let end = self.buf.len().saturating_sub(held) / BLOCK_SIZE * BLOCK_SIZE;
+        cipher
+            .decrypt_blocks_inplace(&mut self.buf[..end])
+            .map_err(|err| err.with_offset(self.offset))?;
+        out.extend_from_slice(&self.buf[..end]);
+        self.buf.drain(..end);
+        self.offset += end as u64;
+
+        if !eof {
+            return Ok(());
+        }
+
+        let mut block = Block::default();
+        block.bytes_mut().copy_from_slice(&self.buf[..BLOCK_SIZE]);
+        let size = cipher
+            .decrypt_last_block_inplace(&mut block)
+            .map_err(|err| err.with_offset(self.offset))?;
+        out.extend_from_slice(&block.bytes()[..size]);
+
+        let tag = if header.version == 0 {
+            cipher.legacy_tag()
+        } else {
+            cipher.tag()
+        };
+        if tag.bytes() != &self.buf[BLOCK_SIZE..] {
+            let at = self.offset + BLOCK_SIZE as u64;
+            return Err(error::Error::TagMismatch(Location::offset(at)));
+        }
+
+        Ok(())
+    }
+}
+
+/// Compresses into memory, for the sealer to take from.
+enum Compressor {
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Gzip(flate2::write::GzEncoder<Vec<u8>>),
+}
+
+impl Compressor {
+    fn new(compression: Compression) -> io::Result<Option<Self>> {
+        Ok(match compression {
+            Compression::None => None,
+            Compression::Zstd => Some(Self::Zstd(zstd::stream::write::Encoder::new(
+                Vec::new(),
+                0,
+            )?)),
+            Compression::Gzip => Some(Self::Gzip(flate2::write::GzEncoder::new(
+                Vec::new(),
+                flate2::Compression::default(),
+            ))),
+        })
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Zstd(w) => w.write_all(buf),
+            Self::Gzip(w) => w.write_all(buf),
+        }
+    }
+
+    /// The compressed bytes produced so far.
+    fn take(&mut self) -> Vec<u8> {
+        match self {
+            Self::Zstd(w) => mem::take(w.get_mut()),
+            Self::Gzip(w) => mem::take(w.get_mut()),
+        }
+    }
+
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Zstd(w) => w.finish(),
+            Self::Gzip(w) => w.finish(),
+        }
+    }
+}
+
+/// Decompresses into memory, for the opener to take from.
+enum Decompressor {
+    Zstd(zstd::stream::write::Decoder<'static, Vec<u8>>),
+    Gzip(flate2::write::GzDecoder<Vec<u8>>),
+}
+
+impl Decompressor {
+    fn new(compression: Compression) -> io::Result<Option<Self>> {
+        Ok(match compression {
+            Compression::None => None,
+            Compression::Zstd => Some(Self::Zstd(zstd::stream::write::Decoder::new(Vec::new())?)),
+            Compression::Gzip => Some(Self::Gzip(flate2::write::GzDecoder::new(Vec::new()))),
+        })
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Zstd(w) => w.write_all(buf),
+            Self::Gzip(w) => w.write_all(buf),
+        }
+    }
+
+    /// The plaintext produced so far.
+    fn take(&mut self) -> Vec<u8> {
+        match self {
+            Self::Zstd(w) => mem::take(w.get_mut()),
+            Self::Gzip(w) => mem::take(w.get_mut()),
+        }
+    }
+
+    /// The rest of the plaintext, checking that the compressed stream is complete.
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Zstd(mut w) => {
+                w.flush()?;
+                Ok(w.into_inner())
+            }
+            Self::Gzip(w) => w.finish(),
+        }
+    }
+}
+
+/// Seals everything written to it into `inner`. The stream is only complete once `finish` is
+/// called, dropping the writer leaves it truncated.
+pub struct SealWriter<W: Write> {
+    inner: W,
+    sealer: Sealer,
+    out: Vec<u8>,
+    processed: u64,
+    progress: Option<Progress>,
+}
+
+impl<W: Write> SealWriter<W> {
+    pub fn new(inner: W, key: Key, header: Header) -> error::Result<Self> {
+        Ok(Self {
+            inner,
+            sealer: Sealer::new(key, header)?,
+            out: Vec::new(),
+            processed: 0,
+            progress: None,
+        })
+    }
+
+    /// Same as `new`, with a sealed metadata section, see `metadata`.
+    pub fn with_metadata(
+        inner: W,
+        key: Key,
+        header: Header,
+        metadata: &Metadata,
+    ) -> error::Result<Self> {
+        Ok(Self {
+            inner,
+            sealer: Sealer::with_metadata(key, header, metadata)?,
+            out: Vec::new(),
+            processed: 0,
+            progress: None,
+        })
+    }
+
+    /// Same as `new`, with a key wrap section and optional metadata, see `Sealer::with_wrap`.
+    pub fn with_wrap(
+        inner: W,
+        key: Key,
+        header: Header,
+        metadata: Option<&Metadata>,
+        wrap: &[u8],
+    ) -> error::Result<Self> {
+        Ok(Self {
+            inner,
+            sealer: Sealer::with_wrap(key, header, metadata, wrap)?,
+            out: Vec::new(),
+            processed: 0,
+            progress: None,
+        })
+    }
+
+    /// Calls `progress` with the plaintext bytes sealed so far, after every write.
+    pub fn with_progress(mut self, progress: impl FnMut(u64) + Send + 'static) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// Writes the end of the stream and its tag, then hands back the inner writer.
+    pub fn finish(mut self) -> error::Result<W> {
+        self.sealer.finish(&mut self.out)?;
+        self.inner.write_all(&self.out)?;
+        self.inner.flush()?;
+
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for SealWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sealer.update(buf, &mut self.out)?;
+        self.inner.write_all(&self.out)?;
+        self.out.clear();
+
+        self.processed += buf.len() as u64;
+        if let Some(progress) = self.progress.as_mut() {
+            progress(self.processed);
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads the plaintext of a sealed stream, decompressing it if need be.
+///
+/// Unless the stream is seekable, the tag is only checked once the end of the stream is reached:
+/// the plaintext read so far can only be trusted once `read` returned 0. A bad tag is reported as
+/// an `io::ErrorKind::InvalidData` error.
+pub struct OpenReader<R: Read> {
+    inner: R,
+    header: Header,
+    metadata: Option<Metadata>,
+    /// `None` once the end of the stream was opened
+    opener: Option<Opener>,
+    buf: Vec<u8>,
+    out: Vec<u8>,
+    pos: usize,
+    processed: u64,
+    progress: Option<Progress>,
+}
+
+impl<R: Read> OpenReader<R> {
+    /// Reads the header of the stream. Headerless (format version 0) streams are refused, see
+    /// `Opener::new`.
+    pub fn new(inner: R, key: Key) -> error::Result<Self> {
+        Self::with_opener(inner, Opener::new(key))
+    }
+
+    /// Same as `new`, but also opens headerless streams, which are not authenticated, see
+    /// `Opener::with_unauthenticated_legacy`.
+    pub fn with_unauthenticated_legacy(inner: R, key: Key) -> error::Result<Self> {
+        Self::with_opener(inner, Opener::with_unauthenticated_legacy(key))
+    }
+
+    fn with_opener(inner: R, opener: Opener) -> error::Result<Self> {
+        let mut reader = Self {
+            inner,
+            header: Header::new(Compression::None),
+            metadata: None,
+            opener: Some(opener),
+            buf: vec![0; BUFFER_SIZE],
+            out: Vec::new(),
+            pos: 0,
+            processed: 0,
+            progress: None,
+        };
+
+        reader.header = loop {
+            if let Some(header) = reader.opener.as_ref().and_then(Opener::header) {
+                break *header;
+            }
+            if !reader.fill()? {
+                return Err(truncated(reader.processed));
+            }
+        };
+        reader.metadata = reader.opener.as_ref().and_then(Opener::metadata).cloned();
+
+        Ok(reader)
+    }
+
+    pub fn header(&self) -> &Header {
+        &self.header
+    }
+
+    /// The metadata sealed along with the stream, if any.
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Calls `progress` with the sealed bytes read so far, header included, after every read
+    /// from the inner reader.
+    pub fn with_progress(mut self, progress: impl FnMut(u64) + Send + 'static) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// Opens the next piece of the stream, returns false once it is over.
+    fn fill(&mut self) -> io::Result<bool> {
+        let Some(opener) = self.opener.as_mut() else {
+            return Ok(false);
+        };
+
+        let n = loop {
+            match self.inner.read(&mut self.buf) {
+                Ok(n) => break n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        };
+
+        self.processed += n as u64;
+        if let Some(progress) = self.progress.as_mut() {
+            progress(self.processed);
+        }
+
+        self.out.drain(..self.pos);
+        self.pos = 0;
+        if n == 0 {
+            let opener = self.opener.take().expect("checked above");
+            opener.finish(&mut self.out)?;
+        } else {
+            opener.update(&self.buf[..n], &mut self.out)?;
+        }
+
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for OpenReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.out.len() {
+            if !self.fill()? {
+                return Ok(0);
+            }
+        }
+
+        let n = buf.len().min(self.out.len() - self.pos);
+        buf[..n].copy_from_slice(&self.out[self.pos..self.pos + n]);
+        self.pos += n;
+
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        crypto::IV_SIZE,
+        hex,
+        ioutils::tests::{ShortReader, ShortWriter},
+    };
+    use proptest::prelude::*;
+    use rand::{rngs::StdRng, RngCore, SeedableRng};
+    use std::sync::{Arc, Mutex};
+
+    fn seal_bytes(key: Key, header: Header, plaintext: &[u8]) -> Vec<u8> {
+        let mut writer = SealWriter::new(Vec::new(), key, header).unwrap();
+        // uneven writes, so that blocks and chunks straddle them
+        for piece in plaintext.chunks(7) {
+            writer.write_all(piece).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    fn seal_bytes_with(key: Key, header: Header, plaintext: &[u8]) -> Vec<u8> {
+        let mut sealed = Vec::new();
+        let mut sealer = Sealer::new(key, header).unwrap();
+        sealer.update(plaintext, &mut sealed).unwrap();
+        sealer.finish(&mut sealed).unwrap();
+        sealed
+    }
+
+    fn open_bytes(key: Key, sealed: &[u8]) -> error::Result<Vec<u8>> {
+        let mut opened = Vec::new();
+        OpenReader::new(sealed, key)?.read_to_end(&mut opened)?;
+        Ok(opened)
+    }
+
+    /// Files sealed by 1.x (format version 1, no header flags, the legacy cipher), key
+    /// `[0x11; 32]`.
+    const VERSION_1_KEY: Key = [0x11; 32];
+    const VERSION_1_PLAINTEXT: &[u8] = b"sealed by file-encryptor 1.x\n";
+    const VERSION_1_SEALED: &str = "46454e430100d17f24fecb73db8517b481dad91a2e5157f9060b47ed4ab70f1f\
+                                    1a8690442edd16d44d78545fdf4f5b689c4e5b6ecf5ca575654089ca486e4df0\
+                                    7633";
+    /// the same, zstd compressed
+    const VERSION_1_ZSTD: &str = "46454e4301013067a043402ec6c093443f2a106c6946a047583cebe2ebabef4c\
+                                  1a59cecf9531c3c645535c5570dc62817a1b6b35ed56ef3e16777a64ba8740cb\
+                                  fd0c3dbc893352904cf2601b058970baf6f9";
+
+    #[test]
+    fn stream_opens_version_1() {
+        for sealed in [VERSION_1_SEALED, VERSION_1_ZSTD] {
+            let sealed = hex::decode(sealed).unwrap();
+            assert_eq!(Header::read(&mut sealed.as_slice()).unwrap().version, 1);
+            assert_eq!(
+                open_bytes(VERSION_1_KEY, &sealed).unwrap(),
+                VERSION_1_PLAINTEXT
+            );
+
+            let mut tampered = sealed.clone();
+            *tampered.last_mut().unwrap() ^= 1;
+            assert!(matches!(
+                open_bytes(VERSION_1_KEY, &tampered),
+                Err(error::Error::TagMismatch(_))
+            ));
+        }
+    }
+
+    /// A headerless (version 0) stream, as sealed before the format had a header.
+    fn seal_version_0(key: Key, plaintext: &[u8]) -> Vec<u8> {
+        let iv = Block::from([3_u8; IV_SIZE]);
+        let mut cipher = Cipher::legacy(key, iv, &[]);
+        let whole = plaintext.len() / BLOCK_SIZE * BLOCK_SIZE;
+        let mut sealed = iv.iv_bytes().to_vec();
+        sealed.extend_from_slice(&plaintext[..whole]);
+        cipher
+            .encrypt_blocks_inplace(&mut sealed[IV_SIZE..])
+            .unwrap();
+
+        let size = plaintext.len() - whole;
+        let mut block = Block::default();
+        block.bytes_mut()[..size].copy_from_slice(&plaintext[whole..]);
+        pkcs7::pad(&mut block, size);
+        cipher.encrypt_block_inplace(&mut block, size).unwrap();
+        sealed.extend_from_slice(block.bytes());
+        sealed.extend_from_slice(cipher.legacy_tag().bytes());
+        sealed
+    }
+
+    #[test]
+    fn stream_refuses_version_0() {
+        let key = [5; 32];
+        let sealed = seal_version_0(key, b"sealed before the header was added");
+        assert!(matches!(
+            open_bytes(key, &sealed),
+            Err(error::Error::Unauthenticated(_))
+        ));
+
+        // the tag does not cover the ciphertext, a flipped bit goes unnoticed
+        let mut tampered = sealed.clone();
+        tampered[IV_SIZE] ^= 1;
+        let mut opened = Vec::new();
+        OpenReader::with_unauthenticated_legacy(tampered.as_slice(), key)
+            .unwrap()
+            .read_to_end(&mut opened)
+            .unwrap();
+        assert_eq!(opened, b"realed before the header was added");
+    }
+
+    #[test]
+    fn stream_roundtrip() {
+        let key: Key = [9; 32];
+        for compression in [Compression::None, Compression::Zstd, Compression::Gzip] {
+            for chunk_size in [None, Some(64)] {
+                for size in [0, 1, 15, 16, 17, 64, 200, 100_000] {
+                    let mut plaintext = vec![0_u8; size];
+                    rand::thread_rng().fill_bytes(&mut plaintext);
+
+                    let mut header = Header::new(compression);
+                    header.chunk_size = chunk_size;
+                    let sealed = seal_bytes(key, header, &plaintext);
+
+                    let opened = open_bytes(key, &sealed).unwrap();
+                    assert_eq!(
+                        opened, plaintext,
+                        "{:?} {:?} {}",
+                        compression, chunk_size, size
+                    );
+                }
+            }
+        }
+    }
+
+    /// Lengths around the block size, whole blocks, anything up to a few chunks, and a few MB.
+    fn plaintext_len() -> impl Strategy<Value = usize> {
+        prop_oneof![
+            4 => prop::sample::select(vec![0, 15, 16, 17]),
+            4 => (1..512_usize).prop_map(|blocks| blocks * BLOCK_SIZE),
+            4 => 0..10_000_usize,
+            1 => (1_usize << 20)..(2 << 20) + BLOCK_SIZE + 1,
+        ]
+    }
+
+    fn header() -> impl Strategy<Value = Header> {
+        let compression = prop::sample::select(vec![
+            Compression::None,
+            Compression::Zstd,
+            Compression::Gzip,
+        ]);
+        // small chunks are left to the seekable tests, a cipher per 16 bytes of a few MB is slow
+        let chunk_size = prop::option::of(prop::sample::select(vec![1024_u32, 4096, 65536]));
+        (compression, chunk_size).prop_map(|(compression, chunk_size)| {
+            let mut header = Header::new(compression);
+            header.chunk_size = chunk_size;
+            header
+        })
+    }
+
+    /// A sealed file of random plaintext, sealed under random parameters.
+    fn sealed() -> impl Strategy<Value = (Key, Vec<u8>, Vec<u8>)> {
+        (any::<Key>(), header(), plaintext_len(), any::<u64>()).prop_map(
+            |(key, header, len, seed)| {
+                let mut plaintext = vec![0_u8; len];
+                StdRng::seed_from_u64(seed).fill_bytes(&mut plaintext);
+                let sealed = seal_bytes(key, header, &plaintext);
+                (key, plaintext, sealed)
+            },
+        )
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn stream_roundtrip_any((key, plaintext, sealed) in sealed()) {
+            prop_assert_eq!(open_bytes(key, &sealed).unwrap(), plaintext);
+        }
+
+        #[test]
+        fn stream_bit_flip_rejected(
+            (key, _, sealed) in sealed(),
+            at in any::<prop::sample::Index>(),
+            bit in 0..8_u8,
+        ) {
+            let mut tampered = sealed;
+            let at = at.index(tampered.len());
+            tampered[at] ^= 1 << bit;
+            prop_assert!(open_bytes(key, &tampered).is_err());
+        }
+
+        #[test]
+        fn stream_truncation_rejected(
+            (key, _, sealed) in sealed(),
+            len in any::<prop::sample::Index>(),
+        ) {
+            let len = len.index(sealed.len());
+            prop_assert!(open_bytes(key, &sealed[..len]).is_err());
+        }
+
+        #[test]
+        fn stream_extension_rejected(
+            (key, _, sealed) in sealed(),
+            extra in prop::collection::vec(any::<u8>(), 1..100),
+        ) {
+            let mut extended = sealed;
+            extended.extend_from_slice(&extra);
+            prop_assert!(open_bytes(key, &extended).is_err());
+        }
+    }
+
+    #[test]
+    fn stream_matches_sealer() {
+        // the adapters add nothing to the stream, a `Sealer` fed in one go gives the same bytes
+        let key: Key = [1; 32];
+        let header = Header::new(Compression::None);
+        let plaintext = b"the quick brown fox jumps over the lazy dog";
+
+        let mut sealed = Vec::new();
+        let mut sealer = Sealer::new(key, header).unwrap();
+        sealer.update(plaintext, &mut sealed).unwrap();
+        sealer.finish(&mut sealed).unwrap();
+        assert_eq!(seal_bytes(key, header, plaintext), sealed);
+
+        let mut opened = Vec::new();
+        let mut opener = Opener::new(key);
+        for byte in &sealed {
+            opener
+                .update(std::slice::from_ref(byte), &mut opened)
+                .unwrap();
+        }
+        opener.finish(&mut opened).unwrap();
+        assert_eq!(opened, plaintext);
+    }
+
+    #[test]
+    fn stream_tamper() {
+        let key: Key = [2; 32];
+        let plaintext = vec![7_u8; 1000];
+        for compression in [Compression::None, Compression::Zstd] {
+            let sealed = seal_bytes(key, Header::new(compression), &plaintext);
+
+            let mut tampered = sealed.clone();
+            *tampered.last_mut().unwrap() ^= 1;
+            let err = open_bytes(key, &tampered).unwrap_err();
+            let tag_at = Some((sealed.len() - BLOCK_SIZE) as u64);
+            assert!(matches!(err, error::Error::TagMismatch(ref at) if at.offset == tag_at));
+
+            // a whole block less looks like another tag, a partial one can only be truncated
+            assert!(open_bytes(key, &sealed[..sealed.len() - BLOCK_SIZE]).is_err());
+            let err = open_bytes(key, &sealed[..sealed.len() - 3]).unwrap_err();
+            let end = Some((sealed.len() - 3) as u64);
+            assert!(matches!(err, error::Error::Truncated(ref at) if at.offset == end));
+
+            // told apart from a corrupted file by the key check
+            let err = open_bytes([3; 32], &sealed).unwrap_err();
+            assert!(matches!(err, error::Error::WrongKey(_)), "{}", err);
+        }
+
+        assert!(matches!(
+            open_bytes(key, b"FENC"),
+            Err(error::Error::Truncated(_))
+        ));
+        assert!(matches!(
+            open_bytes(key, b""),
+            Err(error::Error::Truncated(_))
+        ));
+    }
+
+    #[test]
+    fn stream_short_reads_and_writes() {
+        let key: Key = [5; 32];
+        let mut plaintext = vec![0_u8; 20_000];
+        rand::thread_rng().fill_bytes(&mut plaintext);
+
+        for compression in [Compression::None, Compression::Zstd, Compression::Gzip] {
+            for chunk_size in [None, Some(64)] {
+                let mut header = Header::new(compression);
+                header.chunk_size = chunk_size;
+
+                let sealed = ShortWriter::default();
+                let mut writer = SealWriter::new(sealed.clone(), key, header).unwrap();
+                io::copy(&mut ShortReader(plaintext.as_slice()), &mut writer).unwrap();
+                writer.finish().unwrap();
+                let sealed = sealed.0.lock().unwrap().clone();
+                // compressors may cut their output differently, the cipher must not
+                if compression == Compression::None {
+                    assert_eq!(sealed, seal_bytes_with(key, header, &plaintext));
+                }
+
+                let opened = ShortWriter::default();
+                let mut reader = OpenReader::new(ShortReader(sealed.as_slice()), key).unwrap();
+                io::copy(&mut reader, &mut opened.clone()).unwrap();
+                assert_eq!(*opened.0.lock().unwrap(), plaintext);
+            }
+        }
+    }
+
+    #[test]
+    fn stream_progress() {
+        let key: Key = [6; 32];
+        let plaintext = vec![7_u8; 3 * BUFFER_SIZE + 5];
+        let header = Header::new(Compression::Zstd);
+
+        let sealed_progress = Arc::new(Mutex::new(Vec::new()));
+        let calls = sealed_progress.clone();
+        let mut writer = SealWriter::new(Vec::new(), key, header)
+            .unwrap()
+            .with_progress(move |n| calls.lock().unwrap().push(n));
+        for piece in plaintext.chunks(BUFFER_SIZE) {
+            writer.write_all(piece).unwrap();
+        }
+        let sealed = writer.finish().unwrap();
+        let sealed_progress = sealed_progress.lock().unwrap().clone();
+        assert_eq!(sealed_progress.len(), 4);
+        assert!(sealed_progress.is_sorted());
+        assert_eq!(sealed_progress.last(), Some(&(plaintext.len() as u64)));
+
+        let opened_progress = Arc::new(Mutex::new(0));
+        let last = opened_progress.clone();
+        let mut reader = OpenReader::new(ShortReader(sealed.as_slice()), key)
+            .unwrap()
+            .with_progress(move |n| *last.lock().unwrap() = n);
+        let mut opened = Vec::new();
+        reader.read_to_end(&mut opened).unwrap();
+        assert_eq!(opened, plaintext);
+        assert_eq!(*opened_progress.lock().unwrap(), sealed.len() as u64);
+    }
+
+    #[test]
+    fn stream_metadata() {
+        let key: Key = [3; 32];
+        let plaintext = vec![5_u8; 1000];
+        let metadata = Metadata {
+            name: Some(String::from("report.pdf")),
+            mode: Some(0o640),
+            mtime: Some((1_700_000_000, 5)),
+            size: Some(plaintext.len() as u64),
+        };
+
+        for chunk_size in [None, Some(64)] {
+            let mut header = Header::new(Compression::None);
+            header.chunk_size = chunk_size;
+            let mut writer = SealWriter::with_metadata(Vec::new(), key, header, &metadata).unwrap();
+            writer.write_all(&plaintext).unwrap();
+            let sealed = writer.finish().unwrap();
+
+            let mut reader = OpenReader::new(ShortReader(sealed.as_slice()), key).unwrap();
+            assert_eq!(reader.metadata(), Some(&metadata));
+            let mut opened = Vec::new();
+            reader.read_to_end(&mut opened).unwrap();
+            assert_eq!(opened, plaintext);
+
+            if chunk_size.is_some() {
+                let mut reader =
+                    seekable::SeekableOpenReader::new(io::Cursor::new(&sealed), key).unwrap();
+                assert_eq!(reader.metadata(), Some(&metadata));
+                assert_eq!(reader.len(), plaintext.len() as u64);
+                let mut opened = Vec::new();
+                reader.read_to_end(&mut opened).unwrap();
+                assert_eq!(opened, plaintext);
+            }
+
+            // the section is authenticated, and bound to the header
+            let header = Header::read(&mut sealed.as_slice()).unwrap();
+            let at = header.size() - 20;
+            let mut tampered = sealed.clone();
+            tampered[at] ^= 1;
+            let section = Some((header.size() - header.metadata_size.unwrap() as usize) as u64);
+            assert!(matches!(
+                OpenReader::new(tampered.as_slice(), key),
+                Err(error::Error::TagMismatch(at)) if at.offset == section
+            ));
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! The age test vectors of the C2SP test kit (https://c2sp.org/CCTV/age), as shipped in
+//! `tests/testdata/testkit` of the `age` crate 0.11.2 (MIT OR Apache-2.0). The armored ones are
+//! left out, the ASCII armor is not supported.
+//!
+//! Each file is a few `name: value` lines, an empty line, then the age file.
+
+use std::{fs, io::Read, path::Path, process::Command};
+
+use file_encryptor::{
+    age::{Identity, OpenReader},
+    error::Error,
+};
+use sha2::{Digest, Sha256};
+
+struct Vector {
+    expect: String,
+    payload: Option<String>,
+    identities: Vec<Identity>,
+    age_file: Vec<u8>,
+}
+
+fn parse(bytes: &[u8]) -> Vector {
+    let end = bytes.windows(2).position(|w| w == b"\n\n").unwrap();
+    let mut vector = Vector {
+        expect: String::new(),
+        payload: None,
+        identities: Vec::new(),
+        age_file: bytes[end + 2..].to_vec(),
+    };
+
+    for line in String::from_utf8(bytes[..end].to_vec()).unwrap().lines() {
+        let (name, value) = line.split_once(": ").unwrap();
+        match name {
+            "expect" => vector.expect = value.to_string(),
+            "payload" => vector.payload = Some(value.to_string()),
+            "identity" => vector.identities.push(Identity::parse(value).unwrap()),
+            "passphrase" => vector.identities.push(Identity::Scrypt(value.to_string())),
+            _ => {}
+        }
+    }
+    vector
+}
+
+/// The outcome of opening `vector`, in the words of the test kit.
+fn outcome(vector: &Vector) -> (&'static str, Vec<u8>) {
+    let mut reader = match OpenReader::new(vector.age_file.as_slice(), &vector.identities) {
+        Ok(reader) => reader,
+        Err(Error::WrongKey(_)) => return ("no match", Vec::new()),
+        Err(Error::TagMismatch(_)) => return ("HMAC failure", Vec::new()),
+        Err(Error::BadHeader(..) | Error::Truncated(_)) => return ("header failure", Vec::new()),
+        Err(err) => panic!("unexpected error {}", err),
+    };
+
+    let mut plaintext = Vec::new();
+    match reader.read_to_end(&mut plaintext) {
+        Ok(_) => ("success", plaintext),
+        Err(_) => ("payload failure", plaintext),
+    }
+}
+
+#[test]
+fn age_test_vectors() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/testdata/age");
+    let mut count = 0;
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        let vector = parse(&fs::read(&path).unwrap());
+        let name = path.file_name().unwrap().to_string_lossy();
+
+        let (outcome, plaintext) = outcome(&vector);
+        assert_eq!(outcome, vector.expect, "{}", name);
+        if let Some(payload) = vector.payload.filter(|_| outcome == "success") {
+            let digest: String = Sha256::digest(&plaintext)
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect();
+            assert_eq!(digest, payload, "{}", name);
+        }
+        count += 1;
+    }
+    assert_eq!(count, 85);
+}
+
+/// An identity of the test kit.
+const IDENTITY: &str = "AGE-SECRET-KEY-1XMWWC06LY3EE5RYTXM9MFLAZ2U56JJJ36S0MYPDRWSVLUL66MV4QX3S7F6";
+
+fn file_encryptor(dir: &Path, args: &[&str]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_file-encryptor"))
+        .args(args)
+        .current_dir(dir)
+        // no config file of the user
+        .env("XDG_CONFIG_HOME", dir)
+        .env("FILE_ENCRYPTOR_AGE_PASSPHRASE", "hunter2")
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn age_cli_roundtrip() {
+    let dir = tempfile::tempdir().unwrap();
+    let recipient = Identity::parse(IDENTITY).unwrap().recipient().to_string();
+    fs::write(
+        dir.path().join("identity"),
+        format!("# test\n{}\n", IDENTITY),
+    )
+    .unwrap();
+    fs::write(dir.path().join("plain"), b"sealed for age").unwrap();
+
+    let sealed = file_encryptor(
+        dir.path(),
+        &[
+            "seal",
+            "--format",
+            "age",
+            "-r",
+            &recipient,
+            "-i",
+            "plain",
+            "-o",
+            "x25519.age",
+        ],
+    );
+    assert!(sealed.status.success(), "{:?}", sealed);
+    let sealed = file_encryptor(
+        dir.path(),
+        &[
+            "seal",
+            "--format",
+            "age",
+            "--passphrase",
+            "-i",
+            "plain",
+            "-o",
+            "scrypt.age",
+        ],
+    );
+    assert!(sealed.status.success(), "{:?}", sealed);
+
+    let opened = file_encryptor(
+        dir.path(),
+        &[
+            "open",
+            "--identity",
+            "identity",
+            "-i",
+            "x25519.age",
+            "-o",
+            "x25519",
+        ],
+    );
+    assert!(opened.status.success(), "{:?}", opened);
+    let opened = file_encryptor(dir.path(), &["open", "-i", "scrypt.age", "-o", "scrypt"]);
+    assert!(opened.status.success(), "{:?}", opened);
+    for name in ["x25519", "scrypt"] {
+        assert_eq!(fs::read(dir.path().join(name)).unwrap(), b"sealed for age");
+    }
+
+    let wrong = file_encryptor(dir.path(), &["open", "-i", "x25519.age", "-o", "none"]);
+    assert_eq!(wrong.status.code(), Some(3), "{:?}", wrong);
+}
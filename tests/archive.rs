@@ -0,0 +1,74 @@
+//! `open` of a sealed directory: nothing lands in the target directory, or is listed, unless the
+//! tag checks.
+
+use std::{fs, path::Path, process::Command};
+
+fn file_encryptor(dir: &Path, args: &[&str]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_file-encryptor"))
+        .args(args)
+        .current_dir(dir)
+        // no config file of the user
+        .env("XDG_CONFIG_HOME", dir)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn tampered_archive_leaves_nothing() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("key"), [4; 32]).unwrap();
+    fs::create_dir_all(dir.path().join("project/src")).unwrap();
+    // larger than what is opened at once, so that the first entries are unpacked before the tag
+    // is reached
+    let content = vec![b'x'; 1 << 20];
+    fs::write(dir.path().join("project/src/main.rs"), &content).unwrap();
+    fs::write(dir.path().join("project/run.sh"), b"#!/bin/sh\n").unwrap();
+
+    let sealed = file_encryptor(
+        dir.path(),
+        &["seal", "-k", "key", "-i", "project", "-o", "sealed"],
+    );
+    assert!(sealed.status.success(), "{:?}", sealed);
+
+    // near the end of `src/main.rs`, a flipped bit gets past tar but not the tag
+    let mut tampered = fs::read(dir.path().join("sealed")).unwrap();
+    let inspected = file_encryptor(dir.path(), &["inspect", "--json", "sealed"]);
+    let inspected: serde_json::Value = serde_json::from_slice(&inspected.stdout).unwrap();
+    let header_length = inspected["header_length"].as_u64().unwrap() as usize;
+    tampered[header_length + 4 * 512 + 1_000_000] ^= 1;
+    fs::write(dir.path().join("tampered"), &tampered).unwrap();
+
+    fs::create_dir(dir.path().join("restored")).unwrap();
+    fs::write(dir.path().join("restored/kept"), b"already there").unwrap();
+    let opened = file_encryptor(
+        dir.path(),
+        &["open", "-k", "key", "-i", "tampered", "-o", "restored"],
+    );
+    assert_eq!(opened.status.code(), Some(4), "{:?}", opened);
+    let names: Vec<_> = fs::read_dir(dir.path().join("restored"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(names, ["kept"]);
+
+    let listed = file_encryptor(
+        dir.path(),
+        &["open", "-k", "key", "-i", "tampered", "--list"],
+    );
+    assert_eq!(listed.status.code(), Some(4), "{:?}", listed);
+    assert!(listed.stdout.is_empty());
+    let listed = file_encryptor(dir.path(), &["open", "-k", "key", "-i", "sealed", "--list"]);
+    assert!(listed.status.success(), "{:?}", listed);
+    assert!(String::from_utf8_lossy(&listed.stdout).contains("src/main.rs"));
+
+    let opened = file_encryptor(
+        dir.path(),
+        &["open", "-k", "key", "-i", "sealed", "-o", "restored"],
+    );
+    assert!(opened.status.success(), "{:?}", opened);
+    assert_eq!(
+        fs::read(dir.path().join("restored/src/main.rs")).unwrap(),
+        content
+    );
+    assert!(dir.path().join("restored/kept").exists());
+}
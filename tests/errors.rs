@@ -0,0 +1,27 @@
+//! Exit codes of the command line, see `error::Error::status_code`.
+
+use std::process::Command;
+
+fn file_encryptor(args: &[&str]) -> std::process::Output {
+    let dir = tempfile::tempdir().unwrap();
+    Command::new(env!("CARGO_BIN_EXE_file-encryptor"))
+        .args(args)
+        .current_dir(dir.path())
+        // no config file of the user
+        .env("XDG_CONFIG_HOME", dir.path())
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn usage_and_io_errors_differ() {
+    // clap would exit with 2, the code of `io` errors
+    let usage = file_encryptor(&["seal", "--no-such-flag"]);
+    assert_eq!(usage.status.code(), Some(1), "{:?}", usage);
+
+    let io = file_encryptor(&["seal", "-k", "missing", "-i", "missing", "-o", "out"]);
+    assert_eq!(io.status.code(), Some(2), "{:?}", io);
+
+    let help = file_encryptor(&["--help"]);
+    assert_eq!(help.status.code(), Some(0), "{:?}", help);
+}
@@ -0,0 +1,101 @@
+//! `seal --plugin` and `open --plugin` through the reference plugin, `file-encryptor-plugin-test`.
+
+use std::{env, fs, path::Path, process::Command};
+
+fn file_encryptor(dir: &Path, args: &[&str]) -> std::process::Output {
+    let plugin = Path::new(env!("CARGO_BIN_EXE_file-encryptor-plugin-test"));
+    let inherited = env::var_os("PATH").unwrap_or_default();
+    let path = env::join_paths(
+        [plugin.parent().unwrap().to_path_buf()]
+            .into_iter()
+            .chain(env::split_paths(&inherited)),
+    )
+    .unwrap();
+
+    Command::new(env!("CARGO_BIN_EXE_file-encryptor"))
+        .args(args)
+        .current_dir(dir)
+        .env("PATH", path)
+        // no config file of the user
+        .env("XDG_CONFIG_HOME", dir)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn plugin_roundtrip() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("kek"), [7; 32]).unwrap();
+    fs::write(dir.path().join("other"), [8; 32]).unwrap();
+    fs::write(dir.path().join("plain"), b"wrapped by a plugin").unwrap();
+
+    let sealed = file_encryptor(
+        dir.path(),
+        &[
+            "seal", "--plugin", "test:kek", "-i", "plain", "-o", "sealed",
+        ],
+    );
+    assert!(sealed.status.success(), "{:?}", sealed);
+
+    let opened = file_encryptor(
+        dir.path(),
+        &[
+            "open", "--plugin", "test:kek", "-i", "sealed", "-o", "opened",
+        ],
+    );
+    assert!(opened.status.success(), "{:?}", opened);
+    assert_eq!(
+        fs::read(dir.path().join("opened")).unwrap(),
+        b"wrapped by a plugin"
+    );
+
+    let verified = file_encryptor(
+        dir.path(),
+        &["verify", "--plugin", "test:kek", "-i", "sealed"],
+    );
+    assert!(verified.status.success(), "{:?}", verified);
+
+    let inspected = file_encryptor(dir.path(), &["inspect", "--json", "sealed"]);
+    assert!(String::from_utf8_lossy(&inspected.stdout).contains(r#""plugin":"test""#));
+
+    // the plugin tells another identity from a tampered key, a wrong key either way, and so is
+    // the file key of a key file
+    let refused = file_encryptor(
+        dir.path(),
+        &[
+            "verify",
+            "--plugin",
+            "test:other",
+            "-i",
+            "sealed",
+            "--error-format",
+            "json",
+        ],
+    );
+    assert_eq!(refused.status.code(), Some(3), "{:?}", refused);
+    assert!(String::from_utf8_lossy(&refused.stderr).contains("wrong_key"));
+    let wrong = file_encryptor(dir.path(), &["verify", "-k", "kek", "-i", "sealed"]);
+    assert_eq!(wrong.status.code(), Some(3));
+
+    // a tampered key wrap section is a bad header, not a plugin or identity problem
+    let mut tampered = fs::read(dir.path().join("sealed")).unwrap();
+    let at = tampered.windows(5).position(|w| w == b"test\n").unwrap() + 5;
+    // past the key fingerprint the test plugin starts with, in the sealed key
+    tampered[at + 8 + 12] ^= 1;
+    fs::write(dir.path().join("tampered"), &tampered).unwrap();
+    let opened = file_encryptor(
+        dir.path(),
+        &[
+            "open", "--plugin", "test:kek", "-i", "tampered", "-o", "out",
+        ],
+    );
+    assert_eq!(opened.status.code(), Some(6), "{:?}", opened);
+    assert!(String::from_utf8_lossy(&opened.stderr).contains("wrapped key corrupted"));
+    assert!(!dir.path().join("out").exists());
+
+    let missing = file_encryptor(
+        dir.path(),
+        &["seal", "--plugin", "nope:kek", "-i", "plain", "-o", "x"],
+    );
+    assert!(String::from_utf8_lossy(&missing.stderr).contains("file-encryptor-plugin-nope"));
+}